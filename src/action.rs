@@ -35,3 +35,15 @@ pub(crate) enum Action {
         rpc: Rpc,
     },
 }
+
+impl Action {
+    pub fn rpc(&self) -> &Rpc {
+        match self {
+            Action::ForwardClientRequest(rpc)
+            | Action::ProxyClientRequest(rpc)
+            | Action::RespondToOurDataHandlers { rpc, .. }
+            | Action::RespondToClientHandlers { rpc, .. }
+            | Action::SendToPeers { rpc, .. } => rpc,
+        }
+    }
+}
@@ -0,0 +1,234 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+//! Inspection and repair of a stopped vault's root directory, backing `safe_vault admin`.
+//!
+//! Each operation takes the root directory lock, so fails with `Error::RootDirInUse` while a vault
+//! is running on it.
+
+use crate::{
+    chunk_store, client_handler,
+    control::{Role, ScrubReport},
+    root_dir_lock::RootDirLock,
+    vault::Vault,
+    Result,
+};
+use safe_nd::{AppPermissions, Coins, NodePublicId, PublicKey, XorName};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{Path, PathBuf},
+};
+use structopt::StructOpt;
+
+/// Inspects or repairs the root directory of a stopped vault: the args of `safe_vault admin`.
+#[derive(Debug, StructOpt)]
+#[structopt(name = "admin", rename_all = "kebab-case")]
+pub struct AdminArgs {
+    /// Root directory of the stopped vault.
+    #[structopt(short, long, parse(from_os_str))]
+    pub root_dir: PathBuf,
+    #[allow(missing_docs)]
+    #[structopt(subcommand)]
+    pub command: AdminCommand,
+}
+
+/// Operations on a stopped vault's root directory.
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub enum AdminCommand {
+    /// Show the vault's identity and role.
+    Identity,
+    /// List the number and total size of the chunks in each chunk store.
+    Chunks,
+    /// Show a client's balance and authorised keys.
+    Client {
+        /// Name of the client, as 64 hex characters.
+        name: String,
+    },
+    /// Check that every chunk is valid and stored under its own name.
+    Verify,
+    /// Recompute the space used by each chunk store from its chunks.
+    RecomputeUsedSpace,
+}
+
+/// Identity of a vault, from its `state` file.
+#[derive(Clone, Debug, Serialize)]
+pub struct NodeIdentity {
+    /// Name of the vault, as 64 hex characters.
+    pub name: String,
+    /// Public identity of the vault.
+    pub public_id: NodePublicId,
+    /// Role of the vault when it was last run.
+    pub role: Role,
+}
+
+/// Space used by a chunk store.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ChunkStoreUsage {
+    /// Number of chunks.
+    pub chunks: u64,
+    /// Total size of the chunks in bytes.
+    pub size: u64,
+    /// Size recorded by the vault, which should match `size`.
+    pub recorded_size: Option<u64>,
+}
+
+/// A client's account, as held by the vault.
+#[derive(Clone, Debug, Serialize)]
+pub struct ClientAccount {
+    /// Balance, if the client has one at this vault.
+    pub balance: Option<Coins>,
+    /// Keys of the apps the client has authorised, with their permissions.
+    pub auth_keys: Vec<(PublicKey, AppPermissions)>,
+    /// Version of the authorised keys, if the client has ever had any.
+    pub auth_keys_version: Option<u64>,
+}
+
+/// Returns the identity and role of the vault in `root_dir`.
+pub fn node_identity(root_dir: &Path) -> Result<NodeIdentity> {
+    let _lock = lock(root_dir)?;
+    let (is_elder, id) = Vault::read_state(root_dir)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no vault state in {}", root_dir.display()),
+        )
+    })?;
+    let public_id = id.public_id().clone();
+    Ok(NodeIdentity {
+        name: hex::encode(public_id.name().0),
+        public_id,
+        role: if is_elder { Role::Elder } else { Role::Adult },
+    })
+}
+
+/// Returns the number and size of the chunks in each chunk store in `root_dir`, keyed by the
+/// store's subdirectory name, along with the size recorded by the vault.
+pub fn chunk_store_usage(root_dir: &Path) -> Result<BTreeMap<String, ChunkStoreUsage>> {
+    let _lock = lock(root_dir)?;
+    let recorded_sizes = chunk_store::used_space_per_store(root_dir)?;
+    Ok(chunk_store::chunk_counts_and_sizes(root_dir)?
+        .into_iter()
+        .map(|(store, (chunks, size))| {
+            let recorded_size = recorded_sizes.get(&store).cloned();
+            let usage = ChunkStoreUsage {
+                chunks,
+                size,
+                recorded_size,
+            };
+            (store, usage)
+        })
+        .collect())
+}
+
+/// Returns the account of the client called `name`, given as 64 hex characters.
+pub fn client_account(root_dir: &Path, name: &str) -> Result<ClientAccount> {
+    let name = parse_name(name)?;
+    let _lock = lock(root_dir)?;
+    let (balance, auth_keys, auth_keys_version) =
+        client_handler::read_client_account(root_dir, &name)?;
+    Ok(ClientAccount {
+        balance,
+        auth_keys: auth_keys.into_iter().collect(),
+        auth_keys_version,
+    })
+}
+
+/// Checks every chunk in `root_dir`, as `Command::Scrub` does for a running vault.
+pub fn verify_chunks(root_dir: &Path) -> Result<ScrubReport> {
+    let _lock = lock(root_dir)?;
+    let (checked, invalid) = chunk_store::scrub(root_dir)?;
+    Ok(ScrubReport { checked, invalid })
+}
+
+/// Replaces the space used recorded for each chunk store in `root_dir` with the total size of its
+/// chunks, returning the new values.
+pub fn recompute_used_space(root_dir: &Path) -> Result<BTreeMap<String, u64>> {
+    let _lock = lock(root_dir)?;
+    Ok(chunk_store::recompute_used_space(root_dir)?)
+}
+
+// Locks `root_dir`, without creating it if it doesn't exist.
+fn lock(root_dir: &Path) -> Result<RootDirLock> {
+    if !root_dir.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not a directory", root_dir.display()),
+        )
+        .into());
+    }
+    RootDirLock::acquire(root_dir)
+}
+
+fn parse_name(name: &str) -> Result<XorName> {
+    let invalid_name = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("client name {:?} isn't 64 hex characters", name),
+        )
+    };
+    let bytes = hex::decode(name).map_err(|_| invalid_name())?;
+    if bytes.len() != 32 {
+        return Err(invalid_name().into());
+    }
+    let mut array = [0; 32];
+    array.copy_from_slice(&bytes);
+    Ok(XorName(array))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{chunk_store::ImmutableChunkStore, utils, vault::Init};
+    use safe_nd::{IData, NodeFullId, PubImmutableData};
+    use std::{cell::Cell, fs, rc::Rc};
+    use tempdir::TempDir;
+    use unwrap::unwrap;
+
+    #[test]
+    fn inspect_and_repair_stopped_vault() {
+        let temp_dir = unwrap!(TempDir::new("admin"));
+        let root_dir = temp_dir.path();
+        let id = NodeFullId::new(&mut rand::thread_rng());
+        unwrap!(fs::write(
+            root_dir.join("state"),
+            utils::serialise(&(true, &id))
+        ));
+        let mut chunk_store = unwrap!(ImmutableChunkStore::new(
+            root_dir,
+            u64::max_value(),
+            Rc::new(Cell::new(0)),
+            Init::New,
+        ));
+        unwrap!(chunk_store.put(&IData::Pub(PubImmutableData::new(vec![1, 2, 3]))));
+        drop(chunk_store);
+
+        let identity = unwrap!(node_identity(root_dir));
+        assert_eq!(identity.public_id, *id.public_id());
+        assert_eq!(identity.role, Role::Elder);
+
+        let usage = unwrap!(chunk_store_usage(root_dir));
+        let immutable = unwrap!(usage.get("immutable"));
+        assert_eq!(immutable.chunks, 1);
+        assert_eq!(immutable.recorded_size, Some(immutable.size));
+
+        // Lose the record of the used space, then recompute it.
+        let used_space_path = root_dir.join("chunks/immutable/used_space");
+        unwrap!(fs::write(&used_space_path, utils::serialise(&0_u64)));
+        let size = immutable.size;
+        assert_eq!(
+            unwrap!(recompute_used_space(root_dir)).get("immutable"),
+            Some(&size)
+        );
+        let usage = unwrap!(chunk_store_usage(root_dir));
+        assert_eq!(unwrap!(usage.get("immutable")).recorded_size, Some(size));
+
+        assert!(client_account(root_dir, "not hex").is_err());
+    }
+}
@@ -0,0 +1,288 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+//! Portable archives of a vault's root directory.
+//!
+//! An archive is a bincode-serialised `Header` followed by one `Entry` per file in the root
+//! directory: the identity, the manifest, all PickleDbs and all chunk stores.  Each entry carries a
+//! SHA3-256 checksum of its contents.
+
+use crate::{chunk_store, control, manifest::CURRENT_FORMAT_VERSION, root_dir_lock, Error, Result};
+use log::{info, trace};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Write},
+    path::{Component, Path, PathBuf},
+};
+
+const ARCHIVE_MAGIC: [u8; 8] = *b"SAFEVLTA";
+const PARTIAL_EXTENSION: &str = "partial";
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    magic: [u8; 8],
+    // Format version of the archived root directory, see `manifest`.
+    format_version: u32,
+    entry_count: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    // Path relative to the root directory, with `/` as the separator.
+    path: String,
+    contents: Vec<u8>,
+    checksum: [u8; 32],
+}
+
+/// Writes an archive of `root_dir` to `archive_path`, returning the number of files archived.
+///
+/// The caller must ensure nothing modifies `root_dir` meanwhile.  The archive is written under a
+/// temporary name first, so `archive_path` never holds a partial archive.
+pub(crate) fn export(root_dir: &Path, archive_path: &Path) -> Result<u64> {
+    let partial_path = archive_path.with_extension(PARTIAL_EXTENSION);
+    let mut relative_paths = list_data_files(root_dir)?;
+    relative_paths.retain(|path| {
+        let absolute_path = root_dir.join(path);
+        absolute_path != archive_path && absolute_path != partial_path
+    });
+
+    let mut writer = BufWriter::new(File::create(&partial_path)?);
+    let header = Header {
+        magic: ARCHIVE_MAGIC,
+        format_version: CURRENT_FORMAT_VERSION,
+        entry_count: relative_paths.len() as u64,
+    };
+    bincode::serialize_into(&mut writer, &header)?;
+    for relative_path in &relative_paths {
+        let contents = fs::read(root_dir.join(relative_path))?;
+        let entry = Entry {
+            path: to_archive_path(relative_path)?,
+            checksum: tiny_keccak::sha3_256(&contents),
+            contents,
+        };
+        bincode::serialize_into(&mut writer, &entry)?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    drop(writer);
+
+    fs::rename(&partial_path, archive_path)?;
+    info!(
+        "Exported {} files from {} to {}",
+        header.entry_count,
+        root_dir.display(),
+        archive_path.display()
+    );
+    Ok(header.entry_count)
+}
+
+/// Restores the archive at `archive_path` into `root_dir`, which must not exist yet.
+///
+/// Every entry is checked against its checksum, and every chunk against its file name and, where
+/// it's derived from the contents, its address.  Nothing is written to `root_dir` unless the whole
+/// archive is valid.
+pub fn import_archive<P: AsRef<Path>, Q: AsRef<Path>>(archive_path: P, root_dir: Q) -> Result<()> {
+    let root_dir = root_dir.as_ref();
+    if root_dir.exists() {
+        return Err(Error::InvalidArchive(format!(
+            "{} already exists",
+            root_dir.display()
+        )));
+    }
+
+    let mut reader = BufReader::new(File::open(archive_path.as_ref())?);
+    let header: Header = bincode::deserialize_from(&mut reader)?;
+    if header.magic != ARCHIVE_MAGIC {
+        return Err(Error::InvalidArchive("not a vault archive".to_string()));
+    }
+    if header.format_version > CURRENT_FORMAT_VERSION {
+        return Err(Error::UnsupportedFormatVersion(
+            header.format_version,
+            CURRENT_FORMAT_VERSION,
+        ));
+    }
+
+    let staging_dir = root_dir.with_extension(PARTIAL_EXTENSION);
+    if staging_dir.exists() {
+        fs::remove_dir_all(&staging_dir)?;
+    }
+    let result = (0..header.entry_count).try_for_each(|_| {
+        let entry: Entry = bincode::deserialize_from(&mut reader)?;
+        import_entry(&staging_dir, entry)
+    });
+    if let Err(error) = result {
+        let _ = fs::remove_dir_all(&staging_dir);
+        return Err(error);
+    }
+
+    fs::rename(&staging_dir, root_dir)?;
+    info!(
+        "Imported {} files from {} to {}",
+        header.entry_count,
+        archive_path.as_ref().display(),
+        root_dir.display()
+    );
+    Ok(())
+}
+
+fn import_entry(staging_dir: &Path, entry: Entry) -> Result<()> {
+    let relative_path = from_archive_path(&entry.path)?;
+    if tiny_keccak::sha3_256(&entry.contents) != entry.checksum {
+        return Err(Error::InvalidArchive(format!(
+            "checksum mismatch for {}",
+            entry.path
+        )));
+    }
+    chunk_store::verify_file(&relative_path, &entry.contents)
+        .map_err(|error| Error::InvalidArchive(format!("{}: {}", entry.path, error)))?;
+
+    trace!("Importing {}", entry.path);
+    let path = staging_dir.join(relative_path);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, &entry.contents)?;
+    Ok(())
+}
+
+/// Returns the paths, relative to `root_dir`, of the files holding the vault's data: all of them
+/// except those belonging to the running vault process.
+pub(crate) fn list_data_files(root_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut relative_paths = Vec::new();
+    list_files(root_dir, Path::new(""), &mut relative_paths)?;
+    // The lock and pid files belong to the running vault, and can't be read while locked on
+    // Windows.  The control token is secret, and is replaced whenever a vault starts anyway.
+    relative_paths.retain(|path| {
+        path != Path::new(root_dir_lock::LOCK_FILENAME)
+            && path != Path::new(root_dir_lock::PID_FILENAME)
+            && path != Path::new(control::TOKEN_FILENAME)
+    });
+    Ok(relative_paths)
+}
+
+// Appends the paths, relative to `root_dir`, of all files in `root_dir.join(relative_dir)`.
+fn list_files(root_dir: &Path, relative_dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(root_dir.join(relative_dir))? {
+        let entry = entry?;
+        let relative_path = relative_dir.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            list_files(root_dir, &relative_path, files)?;
+        } else if file_type.is_file() {
+            files.push(relative_path);
+        }
+    }
+    Ok(())
+}
+
+fn to_archive_path(relative_path: &Path) -> Result<String> {
+    let components = relative_path
+        .components()
+        .map(|component| match component {
+            Component::Normal(name) => name.to_str(),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| {
+            Error::InvalidArchive(format!("can't archive {}", relative_path.display()))
+        })?;
+    Ok(components.join("/"))
+}
+
+// Rejects anything which could escape the root directory, e.g. `..` or absolute paths.
+fn from_archive_path(archive_path: &str) -> Result<PathBuf> {
+    let relative_path: PathBuf = archive_path.split('/').collect();
+    let is_valid = relative_path.components().count() > 0
+        && relative_path.components().all(|component| match component {
+            Component::Normal(_) => true,
+            _ => false,
+        });
+    if is_valid {
+        Ok(relative_path)
+    } else {
+        Err(Error::InvalidArchive(format!(
+            "invalid path {}",
+            archive_path
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{chunk_store::ImmutableChunkStore, utils, vault::Init};
+    use safe_nd::{IData, PubImmutableData};
+    use std::{cell::Cell, rc::Rc};
+    use tempdir::TempDir;
+    use unwrap::unwrap;
+
+    fn new_root_dir(temp_dir: &TempDir) -> PathBuf {
+        let root_dir = temp_dir.path().join("root");
+        let mut chunks = unwrap!(ImmutableChunkStore::new(
+            &root_dir,
+            u64::max_value(),
+            Rc::new(Cell::new(0)),
+            Init::New
+        ));
+        unwrap!(chunks.put(&IData::Pub(PubImmutableData::new(vec![1, 2, 3]))));
+        unwrap!(fs::write(root_dir.join("state"), b"identity"));
+        root_dir
+    }
+
+    #[test]
+    fn export_and_import() {
+        let temp_dir = unwrap!(TempDir::new("archive"));
+        let root_dir = new_root_dir(&temp_dir);
+        let archive_path = temp_dir.path().join("vault.archive");
+        unwrap!(fs::write(root_dir.join(root_dir_lock::PID_FILENAME), b"1"));
+        unwrap!(fs::write(root_dir.join(control::TOKEN_FILENAME), b"secret"));
+
+        // Chunk file, its `used_space` record and the state file, but not the pid file or the
+        // control token.
+        assert_eq!(unwrap!(export(&root_dir, &archive_path)), 3);
+
+        let imported_dir = temp_dir.path().join("imported");
+        unwrap!(import_archive(&archive_path, &imported_dir));
+        assert_eq!(
+            unwrap!(fs::read(imported_dir.join("state"))),
+            b"identity".to_vec()
+        );
+
+        // Importing over an existing directory is refused.
+        match import_archive(&archive_path, &imported_dir) {
+            Err(Error::InvalidArchive(_)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn import_rejects_tampered_chunk() {
+        let temp_dir = unwrap!(TempDir::new("archive"));
+        let root_dir = new_root_dir(&temp_dir);
+
+        // Replace the chunk's contents with a different chunk, keeping the original file name.
+        let chunk_dir = root_dir.join("chunks").join("immutable");
+        let mut chunk_paths = unwrap!(fs::read_dir(&chunk_dir))
+            .map(|entry| unwrap!(entry).path())
+            .filter(|path| !path.ends_with("used_space"));
+        let chunk_path = unwrap!(chunk_paths.next());
+        let other_chunk = IData::Pub(PubImmutableData::new(vec![4, 5, 6]));
+        unwrap!(fs::write(&chunk_path, utils::serialise(&other_chunk)));
+
+        let archive_path = temp_dir.path().join("vault.archive");
+        let _ = unwrap!(export(&root_dir, &archive_path));
+
+        let imported_dir = temp_dir.path().join("imported");
+        match import_archive(&archive_path, &imported_dir) {
+            Err(Error::InvalidArchive(_)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        assert!(!imported_dir.exists());
+    }
+}
@@ -64,21 +64,132 @@ fn main() {
 
 #[cfg(not(feature = "mock"))]
 mod detail {
+    use crossbeam_channel::Sender;
     use env_logger::{fmt::Formatter, Builder as LoggerBuilder};
-    use log::{self, Level, Record};
-    use safe_vault::{self, Command, Config, Vault};
+    use log::{self, Level, Log, Metadata, Record};
+    use safe_nd::PublicKey;
+    use safe_vault::{
+        self, AdminArgs, AdminCommand, Command, Config, Error, GithubReleases, LogContext,
+        ReloadReport, RotatingLogFile, ShutdownStatus, UpdateStatus, Vault,
+    };
     use self_update::cargo_crate_version;
-    use self_update::Status;
-    use std::{io::Write, process};
+    use serde::Serialize;
+    use std::{
+        env, fs,
+        io::{self, BufRead, BufReader, Read, Write},
+        net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+        path::PathBuf,
+        process,
+        sync::Mutex,
+        thread,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
     use structopt::StructOpt;
 
-    /// Runs a SAFE Network vault.
+    const CONTROL_USAGE: &str = "expected the control token followed by one of: status, \
+                                 disconnect <peer address>, reload-config, scrub, \
+                                 export <archive file name>, diagnostics, shutdown";
+    // Name of the subcommand parsed into `AdminArgs`.
+    const ADMIN_SUBCOMMAND: &str = "admin";
+    const CONTROL_TIMEOUT: Duration = Duration::from_secs(10);
+    const CONTROL_MAX_LINE_LEN: u64 = 4096;
+    const VAULT_STOPPED: &str = "vault is not running";
+    const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+    const LOG_FILE_NAME: &str = "safe_vault.log";
+    // Target of the log records of this binary and of the library, and prefix of their modules'.
+    const CRATE_NAME: &str = "safe_vault";
+    const LOG_FILE_MAX_SIZE: u64 = 10 * 1024 * 1024;
+    const LOG_FILE_MAX_OLD_FILES: usize = 5;
+
+    /// Runs a SAFE Network vault, or with the `admin` subcommand, operates on a stopped one.
     pub fn main() {
-        let mut config = Config::new();
+        let matches = Config::clap().subcommand(AdminArgs::clap()).get_matches();
+        if let Some(admin_matches) = matches.subcommand_matches(ADMIN_SUBCOMMAND) {
+            process::exit(run_admin(AdminArgs::from_clap(admin_matches)));
+        }
+
+        let mut config = match Config::new() {
+            Ok(config) => config,
+            Err(error) => {
+                println!("{}", error);
+                process::exit(1);
+            }
+        };
         if config.quic_p2p_config().ip.is_none() {
             config.listen_on_loopback();
         }
+        if config.check_config() {
+            match serde_json::to_string_pretty(&config) {
+                Ok(json) => println!("{}", json),
+                Err(error) => {
+                    println!("Cannot print config: {}", error);
+                    process::exit(1);
+                }
+            }
+            return;
+        }
+
+        // Must happen before starting any threads, as only the calling thread survives the fork.
+        if config.daemon() {
+            if let Err(error) = daemonize(&config) {
+                println!("Cannot run as a daemon: {}", error);
+                process::exit(1);
+            }
+        }
+        if let Err(error) = init_logging(&config) {
+            println!("Cannot set up logging: {}", error);
+            process::exit(1);
+        }
+
+        if config.disable_auto_update() {
+            log::info!("Not checking for updates: auto-update is disabled.");
+        } else {
+            start_update();
+        }
+
+        let message = format!(
+            "Running {} v{}",
+            Config::clap().get_name(),
+            env!("CARGO_PKG_VERSION")
+        );
+        log::info!("\n\n{}\n{}", message, "=".repeat(message.len()));
+
+        let (command_tx, command_rx) = crossbeam_channel::bounded(1);
+        let control_port = config.control_port();
+        let root_dir = config.root_dir();
+
+        let mut vault = match Vault::new(config, command_rx) {
+            Ok(vault) => vault,
+            Err(e) => {
+                println!("Cannot start vault due to error: {}", e);
+                process::exit(1);
+            }
+        };
+
+        // Only once the vault holds the root directory, so as not to replace the token of another
+        // vault running on it.
+        if let Some(port) = control_port {
+            match safe_vault::create_control_token(&root_dir) {
+                Ok(token) => spawn_control_listener(port, token, command_tx.clone()),
+                Err(error) => log::error!("Failed to create control token: {}", error),
+            }
+        }
+        spawn_config_watcher(command_tx.clone());
+
+        handle_signals(command_tx);
+
+        match vault.run() {
+            ShutdownStatus::Drained => (),
+            status => {
+                println!("Vault stopped without completing the drain: {:?}", status);
+                process::exit(2);
+            }
+        }
+    }
 
+    // Logs to stderr, or to rotating files if a log directory is configured.
+    fn init_logging(config: &Config) -> Result<(), String> {
         let do_format = move |formatter: &mut Formatter, record: &Record<'_>| {
             let now = formatter.timestamp();
             writeln!(
@@ -92,85 +203,448 @@ mod detail {
             )
         };
         let mut logger = LoggerBuilder::from_default_env();
-        let _ = logger.format(do_format).is_test(false);
+        if config.json_logs() {
+            let _ = logger.format(format_json).is_test(false);
+        } else {
+            let _ = logger.format(do_format).is_test(false);
+        }
+        // With `--verbose`, `RUST_LOG` lets all of our records through and `VerbosityFilter` applies
+        // the verbosity instead, so that it can be changed by reloading the config.  The other
+        // crates' records are still filtered by `RUST_LOG`.
         if config.verbose() != Level::Error {
-            let _ = logger.filter(
-                Config::clap().get_bin_name(),
-                config.verbose().to_level_filter(),
+            let _ = logger.filter(Some(CRATE_NAME), log::LevelFilter::Trace);
+            safe_vault::set_verbosity(config.verbose());
+        }
+        let filter = logger.build();
+        let max_level = filter.filter();
+        let result = match config.log_dir() {
+            Some(log_dir) => {
+                let file = RotatingLogFile::open(
+                    log_dir,
+                    LOG_FILE_NAME,
+                    LOG_FILE_MAX_SIZE,
+                    LOG_FILE_MAX_OLD_FILES,
+                )
+                .map_err(|error| error.to_string())?;
+                log::set_boxed_logger(Box::new(VerbosityFilter(FileLogger {
+                    filter,
+                    file: Mutex::new(file),
+                    json: config.json_logs(),
+                })))
+            }
+            None => log::set_boxed_logger(Box::new(VerbosityFilter(filter))),
+        };
+        result.map_err(|error| error.to_string())?;
+        log::set_max_level(max_level);
+        Ok(())
+    }
+
+    // Passes this crate's records on to the wrapped logger if they're within the verbosity, and the
+    // other crates' records if the wrapped logger's own filter allows them.
+    struct VerbosityFilter<L>(L);
+
+    impl<L: Log> Log for VerbosityFilter<L> {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            safe_vault::verbosity_allows(metadata).unwrap_or_else(|| self.0.enabled(metadata))
+        }
+
+        fn log(&self, record: &Record) {
+            if self.enabled(record.metadata()) {
+                self.0.log(record)
+            }
+        }
+
+        fn flush(&self) {
+            self.0.flush()
+        }
+    }
+
+    // Writes the log records which pass `filter` to rotating log files, in the same formats as
+    // `init_logging` uses for stderr.
+    struct FileLogger {
+        filter: env_logger::Logger,
+        file: Mutex<RotatingLogFile>,
+        json: bool,
+    }
+
+    impl Log for FileLogger {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            self.filter.enabled(metadata)
+        }
+
+        fn log(&self, record: &Record) {
+            if !self.filter.matches(record) {
+                return;
+            }
+            let since_epoch = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            let timestamp = format!(
+                "{}.{:03}",
+                since_epoch.as_secs(),
+                since_epoch.subsec_millis()
             );
+            let line = if self.json {
+                match serde_json::to_string(&JsonRecord::new(record, timestamp)) {
+                    Ok(line) => line + "\n",
+                    Err(_) => return,
+                }
+            } else {
+                format!(
+                    "{} {} [{}:{}] {}\n",
+                    record.level(),
+                    timestamp,
+                    record.file().unwrap_or_default(),
+                    record.line().unwrap_or_default(),
+                    record.args()
+                )
+            };
+            // A panic while holding the lock can't leave the file inconsistent enough to matter.
+            let mut file = self
+                .file
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            // In a single write, so that a rotation can't split the line across files.
+            let _ = file.write_all(line.as_bytes());
         }
-        let _ = logger.try_init();
 
-        match update() {
-            Ok(status) => {
-                if let Status::Updated { .. } = status {
-                    println!("Vault has been updated. Please restart.");
-                    process::exit(0);
+        fn flush(&self) {
+            let mut file = self
+                .file
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            let _ = file.flush();
+        }
+    }
+
+    #[derive(Serialize)]
+    struct JsonRecord<'a> {
+        timestamp: String,
+        level: String,
+        target: &'a str,
+        file: &'a str,
+        line: u32,
+        message: String,
+        #[serde(flatten)]
+        context: LogContext,
+    }
+
+    impl<'a> JsonRecord<'a> {
+        fn new(record: &Record<'a>, timestamp: String) -> Self {
+            Self {
+                timestamp,
+                level: record.level().to_string(),
+                target: record.target(),
+                file: record.file().unwrap_or_default(),
+                line: record.line().unwrap_or_default(),
+                message: record.args().to_string(),
+                context: LogContext::current(),
+            }
+        }
+    }
+
+    // Writes the record as a single line of JSON, including the fields of the request being handled
+    // by the vault on this thread.
+    fn format_json(formatter: &mut Formatter, record: &Record<'_>) -> io::Result<()> {
+        let json_record = JsonRecord::new(record, formatter.timestamp().to_string());
+        let line = serde_json::to_string(&json_record)
+            .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?;
+        writeln!(formatter, "{}", line)
+    }
+
+    // Reloads the config whenever the config file is modified.
+    fn spawn_config_watcher(command_tx: Sender<Command>) {
+        let path = match Config::file_path() {
+            Ok(path) => path,
+            Err(error) => {
+                log::warn!("Not watching the config file: {}", error);
+                return;
+            }
+        };
+        let modified =
+            |path: &PathBuf| -> Option<SystemTime> { fs::metadata(path).ok()?.modified().ok() };
+        let _ = thread::spawn(move || {
+            let mut last_modified = modified(&path);
+            loop {
+                thread::sleep(CONFIG_POLL_INTERVAL);
+                let current = modified(&path);
+                if current == last_modified {
+                    continue;
+                }
+                last_modified = current;
+                log::info!("Config file {} was modified", path.display());
+                reload_config(&command_tx);
+            }
+        });
+    }
+
+    // Reloads the config, logging any changes which need a restart.
+    fn reload_config(command_tx: &Sender<Command>) {
+        match send_reload_config(command_tx) {
+            Ok(report) => {
+                if !report.needs_restart.is_empty() {
+                    log::warn!(
+                        "Config changes to {:?} take effect after a restart",
+                        report.needs_restart
+                    );
                 }
             }
-            Err(e) => log::error!("Updating vault failed: {:?}", e),
+            Err(error) => log::warn!("Not reloading config: {}", error),
         }
+    }
 
-        let message = format!(
-            "Running {} v{}",
-            Config::clap().get_name(),
-            env!("CARGO_PKG_VERSION")
-        );
-        log::info!("\n\n{}\n{}", message, "=".repeat(message.len()));
+    fn send_reload_config(command_tx: &Sender<Command>) -> Result<ReloadReport, String> {
+        let config = Config::reload().map_err(|error| error.to_string())?;
+        send_command(command_tx, |reply_tx| {
+            Command::ReloadConfig(Box::new(config), reply_tx)
+        })
+    }
 
-        let (command_tx, command_rx) = crossbeam_channel::bounded(1);
+    // Shuts the vault down gracefully on SIGINT and SIGTERM, and reloads the config on SIGHUP.  A
+    // second SIGINT or SIGTERM stops the vault without waiting for operations in progress.
+    #[cfg(unix)]
+    fn handle_signals(command_tx: Sender<Command>) {
+        use signal_hook::{iterator::Signals, SIGHUP, SIGINT, SIGTERM};
 
-        // Shutdown the vault gracefully on SIGINT (Ctrl+C).
+        let signals = match Signals::new(&[SIGINT, SIGTERM, SIGHUP]) {
+            Ok(signals) => signals,
+            Err(error) => {
+                log::error!("Failed to set signal handlers: {}", error);
+                return;
+            }
+        };
+        let _ = thread::spawn(move || {
+            for signal in signals.forever() {
+                if signal == SIGHUP {
+                    reload_config(&command_tx);
+                } else if command_tx.send(Command::Shutdown).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+
+    // Shuts the vault down gracefully on Ctrl+C.
+    #[cfg(not(unix))]
+    fn handle_signals(command_tx: Sender<Command>) {
         let result = ctrlc::set_handler(move || {
             let _ = command_tx.send(Command::Shutdown);
         });
         if let Err(error) = result {
             log::error!("Failed to set interrupt handler: {:?}", error)
         }
+    }
 
-        match Vault::new(config, command_rx) {
-            Ok(mut vault) => vault.run(),
-            Err(e) => {
-                println!("Cannot start vault due to error: {:?}", e);
+    // Detaches from the terminal, leaving a locked pid file in the root directory so that a second
+    // daemon on the same root directory fails to start.  Relative paths in the config keep working,
+    // as the working directory is kept.
+    #[cfg(unix)]
+    fn daemonize(config: &Config) -> Result<(), String> {
+        let root_dir = config.root_dir();
+        fs::create_dir_all(&root_dir).map_err(|error| error.to_string())?;
+        let working_dir = env::current_dir().map_err(|error| error.to_string())?;
+        daemonize::Daemonize::new()
+            .pid_file(root_dir.join(safe_vault::PID_FILENAME))
+            .working_directory(working_dir)
+            .start()
+            .map_err(|error| {
+                format!(
+                    "{} (is another vault running on {}?)",
+                    error,
+                    root_dir.display()
+                )
+            })
+    }
+
+    #[cfg(not(unix))]
+    fn daemonize(_config: &Config) -> Result<(), String> {
+        Err("daemon mode is only supported on Unix".to_string())
+    }
+
+    // Accepts one command per connection on 127.0.0.1:`port`, as a line of text starting with
+    // `token`, and writes back the vault's reply.  Replies are JSON, except for `diagnostics` which
+    // is plain text.  Connections are handled one at a time, so each is given `CONTROL_TIMEOUT` to
+    // send its command and read the reply.
+    fn spawn_control_listener(port: u16, token: String, command_tx: Sender<Command>) {
+        let listener = match TcpListener::bind((Ipv4Addr::LOCALHOST, port)) {
+            Ok(listener) => listener,
+            Err(error) => {
+                log::error!(
+                    "Failed to start control listener on port {}: {}",
+                    port,
+                    error
+                );
+                return;
+            }
+        };
+        log::info!("Accepting control commands on 127.0.0.1:{}", port);
+        let _ = thread::spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream
+                    .and_then(|stream| handle_control_connection(stream, &token, &command_tx));
+                if let Err(error) = result {
+                    log::warn!("Control connection failed: {}", error);
+                }
             }
+        });
+    }
+
+    fn handle_control_connection(
+        mut stream: TcpStream,
+        token: &str,
+        command_tx: &Sender<Command>,
+    ) -> io::Result<()> {
+        stream.set_read_timeout(Some(CONTROL_TIMEOUT))?;
+        stream.set_write_timeout(Some(CONTROL_TIMEOUT))?;
+        let mut line = String::new();
+        let _ = BufReader::new((&stream).take(CONTROL_MAX_LINE_LEN)).read_line(&mut line)?;
+        let mut words = line.trim().splitn(2, char::is_whitespace);
+        let reply = if !words
+            .next()
+            .map_or(false, |given| tokens_match(given, token))
+        {
+            log::warn!("Rejected control command with a missing or wrong token");
+            "error: invalid control token".to_string()
+        } else {
+            match run_control_command(words.next().unwrap_or_default(), command_tx) {
+                Ok(reply) => reply,
+                Err(error) => format!("error: {}", error),
+            }
+        };
+        writeln!(stream, "{}", reply)
+    }
+
+    // Compares in constant time, so the token can't be guessed byte by byte from response times.
+    fn tokens_match(given: &str, expected: &str) -> bool {
+        given.len() == expected.len()
+            && given
+                .bytes()
+                .zip(expected.bytes())
+                .fold(0, |difference, (a, b)| difference | (a ^ b))
+                == 0
+    }
+
+    fn run_control_command(line: &str, command_tx: &Sender<Command>) -> Result<String, String> {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("status"), None) => send_command(command_tx, Command::Status).and_then(to_json),
+            (Some("disconnect"), Some(peer_addr)) => {
+                let peer_addr: SocketAddr = peer_addr.parse().map_err(|error| error.to_string())?;
+                send_command(command_tx, |reply_tx| {
+                    Command::DisconnectClient(peer_addr, reply_tx)
+                })
+                .and_then(to_json)
+            }
+            (Some("reload-config"), None) => send_reload_config(command_tx).and_then(to_json),
+            (Some("scrub"), None) => send_command(command_tx, Command::Scrub)?
+                .map_err(|error| error.to_string())
+                .and_then(to_json),
+            (Some("export"), Some(file_name)) => send_command(command_tx, |reply_tx| {
+                Command::Export(PathBuf::from(file_name), reply_tx)
+            })?
+            .map_err(|error| error.to_string())
+            .and_then(to_json),
+            (Some("diagnostics"), None) => send_command(command_tx, Command::DumpDiagnostics),
+            (Some("shutdown"), None) => command_tx
+                .send(Command::Shutdown)
+                .map(|()| "null".to_string())
+                .map_err(|_| VAULT_STOPPED.to_string()),
+            _ => Err(CONTROL_USAGE.to_string()),
         }
     }
 
-    fn update() -> Result<Status, Box<dyn (::std::error::Error)>> {
+    // Sends the command built by `command` and waits for the vault's reply.
+    fn send_command<T>(
+        command_tx: &Sender<Command>,
+        command: impl FnOnce(Sender<T>) -> Command,
+    ) -> Result<T, String> {
+        let (reply_tx, reply_rx) = crossbeam_channel::bounded(1);
+        command_tx
+            .send(command(reply_tx))
+            .map_err(|_| VAULT_STOPPED.to_string())?;
+        reply_rx.recv().map_err(|_| VAULT_STOPPED.to_string())
+    }
+
+    // Prints the outcome of the admin command as JSON, returning the exit code.
+    fn run_admin(args: AdminArgs) -> i32 {
+        let root_dir = args.root_dir.as_path();
+        let result = match args.command {
+            AdminCommand::Identity => print_json(safe_vault::node_identity(root_dir)),
+            AdminCommand::Chunks => print_json(safe_vault::chunk_store_usage(root_dir)),
+            AdminCommand::Client { name } => {
+                print_json(safe_vault::client_account(root_dir, &name))
+            }
+            AdminCommand::Verify => match safe_vault::verify_chunks(root_dir) {
+                Ok(ref report) if !report.invalid.is_empty() => {
+                    print_json(Ok(report)).and(Err("invalid chunks found".to_string()))
+                }
+                result => print_json(result),
+            },
+            AdminCommand::RecomputeUsedSpace => {
+                print_json(safe_vault::recompute_used_space(root_dir))
+            }
+        };
+        match result {
+            Ok(()) => 0,
+            Err(error) => {
+                eprintln!("Error: {}", error);
+                1
+            }
+        }
+    }
+
+    fn print_json<T: Serialize>(result: Result<T, Error>) -> Result<(), String> {
+        let json = result
+            .map_err(|error| error.to_string())
+            .and_then(|value| {
+                serde_json::to_string_pretty(&value).map_err(|error| error.to_string())
+            })?;
+        println!("{}", json);
+        Ok(())
+    }
+
+    fn to_json<T: Serialize>(value: T) -> Result<String, String> {
+        serde_json::to_string(&value).map_err(|error| error.to_string())
+    }
+
+    // Checks for an update in the background, as that can take a while, or hang without network
+    // access.  Releases can't be verified without a pinned signing key, so auto-update is off in
+    // builds without one.
+    fn start_update() {
+        let signing_key = match safe_vault::pinned_signing_key() {
+            Ok(Some(signing_key)) => signing_key,
+            Ok(None) => {
+                log::info!(
+                    "Not checking for updates: no release signing key is pinned in this build."
+                );
+                return;
+            }
+            Err(error) => {
+                log::error!("Not checking for updates: {}", error);
+                return;
+            }
+        };
+        let _ = thread::spawn(move || match update(&signing_key) {
+            Ok(UpdateStatus::Updated(version)) => {
+                log::warn!("Vault has been updated to {}. Please restart.", version)
+            }
+            Ok(UpdateStatus::UpToDate) => log::info!("Vault is up to date."),
+            Err(error) => log::error!("Updating vault failed: {}", error),
+        });
+    }
+
+    fn update(signing_key: &PublicKey) -> Result<UpdateStatus, Error> {
         log::info!("Checking for updates...");
-        let target = self_update::get_target()?;
-        let releases = self_update::backends::github::ReleaseList::configure()
-            .repo_owner("maidsafe")
-            .repo_name("safe_vault")
-            .with_target(&target)
-            .build()?
-            .fetch()?;
-        if !releases.is_empty() {
-            log::debug!("Target for update is {}", target);
-            log::debug!("Found releases: {:#?}\n", releases);
-            let bin_name = if target.contains("pc-windows") {
-                "safe_vault.exe"
-            } else {
-                "safe_vault"
-            };
-            let status = self_update::backends::github::Update::configure()?
-                .repo_owner("maidsafe")
-                .repo_name("safe_vault")
-                .target(&target)
-                .bin_name(&bin_name)
-                .show_download_progress(true)
-                .no_confirm(true)
-                .current_version(cargo_crate_version!())
-                .build()?
-                .update()?;
-            println!("Update status: `{}`!", status.version());
-            return Ok(status);
-        }
-        log::info!("Current version is {}", cargo_crate_version!());
-        log::info!("No releases are available for updates");
-        Ok(Status::UpToDate(
-            "No releases are available for updates".to_string(),
-        ))
+        let target =
+            self_update::get_target().map_err(|error| Error::InvalidUpdate(error.to_string()))?;
+        log::debug!("Target for update is {}", target);
+        safe_vault::install_latest_release(
+            &GithubReleases::new("maidsafe", "safe_vault"),
+            signing_key,
+            cargo_crate_version!(),
+            &target,
+            &env::current_exe()?,
+        )
     }
 }
 
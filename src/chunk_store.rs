@@ -26,13 +26,17 @@ use log::trace;
 use safe_nd::{AData, IData, LoginPacket, MData};
 use std::{
     cell::Cell,
+    collections::BTreeMap,
+    ffi::OsStr,
     fs::{self, DirEntry, File, Metadata},
     io::{Read, Write},
     marker::PhantomData,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
     rc::Rc,
 };
-use used_space::UsedSpace;
+use used_space::{UsedSpace, USED_SPACE_FILENAME};
+
+pub(crate) use login_packet::{LoginPacketVersion, LoginPacketVersionId};
 
 const CHUNK_STORE_DIR: &str = "chunks";
 
@@ -43,6 +47,7 @@ pub(crate) type ImmutableChunkStore = ChunkStore<IData>;
 pub(crate) type MutableChunkStore = ChunkStore<MData>;
 pub(crate) type AppendOnlyChunkStore = ChunkStore<AData>;
 pub(crate) type LoginPacketChunkStore = ChunkStore<LoginPacket>;
+pub(crate) type LoginPacketHistoryChunkStore = ChunkStore<LoginPacketVersion>;
 
 /// `ChunkStore` is a store of data held as serialised files on disk, implementing a maximum disk
 /// usage to restrict storage.
@@ -161,8 +166,15 @@ impl<T: Chunk> ChunkStore<T> {
         }
     }
 
+    /// Changes the maximum storage space checked by this `ChunkStore` against the space used by all
+    /// `ChunkStore`s.  The other stores keep their own limit, so each needs updating.  Chunks
+    /// already stored beyond a lowered limit are kept, but no more can be put until enough are
+    /// deleted.
+    pub fn set_max_capacity(&mut self, max_capacity: u64) {
+        self.max_capacity = max_capacity;
+    }
+
     /// Lists all keys of currently stored data.
-    #[cfg_attr(not(test), allow(unused))]
     pub fn keys(&self) -> Vec<T::Id> {
         fs::read_dir(&self.dir)
             .map(|entries| {
@@ -215,6 +227,176 @@ impl Subdir for LoginPacketChunkStore {
     }
 }
 
+impl Subdir for LoginPacketHistoryChunkStore {
+    fn subdir() -> &'static Path {
+        Path::new("login_packet_history")
+    }
+}
+
+/// Returns true if the file, given its path relative to a vault's root directory, holds a chunk.
+///
+/// Chunk files are never modified in place: `put` replaces the file, so a hard link to one keeps
+/// its contents.  The other files in a chunk store's directory are rewritten in place.
+pub(crate) fn is_chunk_file(relative_path: &Path) -> bool {
+    let mut components = relative_path.components();
+    components.next() == Some(Component::Normal(CHUNK_STORE_DIR.as_ref()))
+        && components.next().is_some()
+        && match (components.next(), components.next()) {
+            (Some(Component::Normal(file_name)), None) => {
+                Path::new(file_name) != Path::new(USED_SPACE_FILENAME)
+            }
+            _ => false,
+        }
+}
+
+/// Checks a file from a vault's root directory, given its path relative to the root.
+///
+/// Chunk files must deserialise to a valid chunk of their store's type, and be named after the
+/// chunk's id.  Files outside the chunk stores aren't checked.
+pub(crate) fn verify_file(relative_path: &Path, contents: &[u8]) -> Result<()> {
+    let mut components = relative_path.components();
+    if components.next() != Some(Component::Normal(CHUNK_STORE_DIR.as_ref())) {
+        return Ok(());
+    }
+    let subdir = match components.next() {
+        Some(Component::Normal(subdir)) => Path::new(subdir),
+        _ => return Err(Error::InvalidChunk),
+    };
+    let file_name = match (components.next(), components.next()) {
+        (Some(Component::Normal(file_name)), None) => {
+            file_name.to_str().ok_or(Error::InvalidChunk)?
+        }
+        _ => return Err(Error::InvalidChunk),
+    };
+    if file_name == USED_SPACE_FILENAME {
+        return Ok(());
+    }
+
+    if subdir == ImmutableChunkStore::subdir() {
+        verify_chunk::<IData>(file_name, contents)
+    } else if subdir == MutableChunkStore::subdir() {
+        verify_chunk::<MData>(file_name, contents)
+    } else if subdir == AppendOnlyChunkStore::subdir() {
+        verify_chunk::<AData>(file_name, contents)
+    } else if subdir == LoginPacketChunkStore::subdir() {
+        verify_chunk::<LoginPacket>(file_name, contents)
+    } else if subdir == LoginPacketHistoryChunkStore::subdir() {
+        verify_chunk::<LoginPacketVersion>(file_name, contents)
+    } else {
+        Err(Error::InvalidChunk)
+    }
+}
+
+/// Returns the space used by each chunk store under `root`, keyed by the store's subdirectory name.
+pub(crate) fn used_space_per_store(root: &Path) -> Result<BTreeMap<String, u64>> {
+    let mut used_space = BTreeMap::new();
+    for entry in fs::read_dir(root.join(CHUNK_STORE_DIR))? {
+        let dir = entry?.path();
+        let record = match fs::read(dir.join(USED_SPACE_FILENAME)) {
+            Ok(record) => record,
+            Err(_) => continue,
+        };
+        if let Some(name) = dir.file_name().and_then(OsStr::to_str) {
+            let _ = used_space.insert(name.to_string(), bincode::deserialize(&record)?);
+        }
+    }
+    Ok(used_space)
+}
+
+/// Flushes the `UsedSpace` record of every chunk store under `root` to disk.  The chunks themselves
+/// are left to the OS, as flushing each of them would make shutting down take as long as a scrub.
+pub(crate) fn sync_used_space(root: &Path) -> Result<()> {
+    for entry in fs::read_dir(root.join(CHUNK_STORE_DIR))? {
+        let record_path = entry?.path().join(USED_SPACE_FILENAME);
+        if record_path.is_file() {
+            utils::sync_file(&record_path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Checks every chunk file under `root` with `verify_file`, returning the number of files checked
+/// along with the paths of the ones which failed.  Nothing is deleted.
+pub(crate) fn scrub(root: &Path) -> Result<(u64, Vec<PathBuf>)> {
+    let mut checked = 0;
+    let mut invalid = Vec::new();
+    for store_entry in fs::read_dir(root.join(CHUNK_STORE_DIR))? {
+        let store_dir = store_entry?.path();
+        if !store_dir.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(&store_dir)? {
+            let path = entry?.path();
+            if path.file_name() == Some(USED_SPACE_FILENAME.as_ref()) {
+                continue;
+            }
+            let relative_path = match path.strip_prefix(root) {
+                Ok(relative_path) => relative_path,
+                Err(_) => continue,
+            };
+            checked += 1;
+            let result = fs::read(&path)
+                .map_err(Error::from)
+                .and_then(|contents| verify_file(relative_path, &contents));
+            if let Err(error) = result {
+                trace!("Scrub found invalid chunk {}: {}", path.display(), error);
+                invalid.push(path);
+            }
+        }
+    }
+    Ok((checked, invalid))
+}
+
+/// Returns the number of chunk files and their total size for each chunk store under `root`, keyed
+/// by the store's subdirectory name.  Sizes are as recorded by `UsedSpace`, i.e. file lengths.
+pub(crate) fn chunk_counts_and_sizes(root: &Path) -> Result<BTreeMap<String, (u64, u64)>> {
+    let mut usage = BTreeMap::new();
+    for store_entry in fs::read_dir(root.join(CHUNK_STORE_DIR))? {
+        let store_dir = store_entry?.path();
+        let name = match store_dir.file_name().and_then(OsStr::to_str) {
+            Some(name) if store_dir.is_dir() => name.to_string(),
+            _ => continue,
+        };
+        let (mut count, mut size) = (0, 0);
+        for entry in fs::read_dir(&store_dir)? {
+            let entry = entry?;
+            if entry.file_name() == USED_SPACE_FILENAME {
+                continue;
+            }
+            count += 1;
+            size += entry.metadata()?.len();
+        }
+        let _ = usage.insert(name, (count, size));
+    }
+    Ok(usage)
+}
+
+/// Overwrites each chunk store's `UsedSpace` record under `root` with the total size of its chunk
+/// files, returning the new values.  The vault mustn't be running.
+pub(crate) fn recompute_used_space(root: &Path) -> Result<BTreeMap<String, u64>> {
+    let mut used_space = BTreeMap::new();
+    for (name, (_, size)) in chunk_counts_and_sizes(root)? {
+        let path = root
+            .join(CHUNK_STORE_DIR)
+            .join(&name)
+            .join(USED_SPACE_FILENAME);
+        let mut file = File::create(path)?;
+        bincode::serialize_into(&mut file, &size)?;
+        file.sync_all()?;
+        let _ = used_space.insert(name, size);
+    }
+    Ok(used_space)
+}
+
+fn verify_chunk<T: Chunk>(file_name: &str, contents: &[u8]) -> Result<()> {
+    let chunk = bincode::deserialize::<T>(contents)?;
+    if chunk.is_valid() && hex::encode(utils::serialise(chunk.id())) == file_name {
+        Ok(())
+    } else {
+        Err(Error::InvalidChunk)
+    }
+}
+
 fn to_chunk_id<T: ChunkId>(entry: DirEntry) -> Option<T> {
     let file_name = entry.file_name();
     let file_name = file_name.into_string().ok()?;
@@ -13,6 +13,11 @@ use serde::{de::DeserializeOwned, Serialize};
 pub(crate) trait Chunk: Serialize + DeserializeOwned {
     type Id: ChunkId;
     fn id(&self) -> &Self::Id;
+
+    /// Returns false if the chunk's contents don't match its id, e.g. because it was tampered with.
+    fn is_valid(&self) -> bool {
+        true
+    }
 }
 
 pub(crate) trait ChunkId: ToDbKey + PartialEq + Eq + DeserializeOwned {}
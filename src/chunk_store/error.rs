@@ -35,6 +35,10 @@ quick_error! {
         NoSuchChunk {
             display("Chunk not found")
         }
+        /// Chunk file doesn't hold a valid chunk stored under the expected name.
+        InvalidChunk {
+            display("Invalid chunk")
+        }
     }
 }
 
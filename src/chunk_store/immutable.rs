@@ -7,7 +7,7 @@
 // permissions and limitations relating to use of the SAFE Network Software.
 
 use super::chunk::{Chunk, ChunkId};
-use safe_nd::{IData, IDataAddress};
+use safe_nd::{IData, IDataAddress, PubImmutableData, UnpubImmutableData};
 
 impl Chunk for IData {
     type Id = IDataAddress;
@@ -17,6 +17,19 @@ impl Chunk for IData {
             IData::Unpub(ref chunk) => chunk.address(),
         }
     }
+
+    // The address of immutable data is derived from its contents, so can be recomputed.
+    fn is_valid(&self) -> bool {
+        match self {
+            IData::Pub(ref chunk) => {
+                PubImmutableData::new(chunk.value().clone()).address() == chunk.address()
+            }
+            IData::Unpub(ref chunk) => {
+                UnpubImmutableData::new(chunk.value().clone(), *chunk.owner()).address()
+                    == chunk.address()
+            }
+        }
+    }
 }
 
 impl ChunkId for IDataAddress {}
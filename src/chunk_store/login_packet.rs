@@ -6,8 +6,9 @@
 // KIND, either express or implied. Please review the Licences for the specific language governing
 // permissions and limitations relating to use of the SAFE Network Software.
 
-use super::chunk::Chunk;
+use super::chunk::{Chunk, ChunkId};
 use safe_nd::{LoginPacket, XorName};
+use serde::{Deserialize, Serialize};
 
 impl Chunk for LoginPacket {
     type Id = XorName;
@@ -15,3 +16,43 @@ impl Chunk for LoginPacket {
         self.destination()
     }
 }
+
+/// Identifies a superseded version of a login packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct LoginPacketVersionId {
+    pub name: XorName,
+    pub version: u64,
+}
+
+impl ChunkId for LoginPacketVersionId {}
+
+/// A version of a login packet: either a superseded one, kept to allow rolling back to it, or an
+/// update staged until it's committed.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct LoginPacketVersion {
+    id: LoginPacketVersionId,
+    packet: LoginPacket,
+}
+
+impl LoginPacketVersion {
+    pub fn new(packet: LoginPacket, version: u64) -> Self {
+        Self {
+            id: LoginPacketVersionId {
+                name: *packet.destination(),
+                version,
+            },
+            packet,
+        }
+    }
+
+    pub fn into_packet(self) -> LoginPacket {
+        self.packet
+    }
+}
+
+impl Chunk for LoginPacketVersion {
+    type Id = LoginPacketVersionId;
+    fn id(&self) -> &Self::Id {
+        &self.id
+    }
+}
@@ -9,12 +9,13 @@
 use super::{
     chunk::{Chunk, ChunkId},
     error::Error,
-    ChunkStore, Subdir,
+    ChunkStore, ImmutableChunkStore, Subdir,
 };
 use crate::{vault::Init, ToDbKey};
 use rand::{distributions::Standard, rngs::ThreadRng, Rng};
+use safe_nd::{IData, PubImmutableData};
 use serde::{Deserialize, Serialize};
-use std::{cell::Cell, path::Path, rc::Rc, u64};
+use std::{cell::Cell, fs, path::Path, rc::Rc, u64};
 use tempdir::TempDir;
 use unwrap::unwrap;
 
@@ -296,3 +297,31 @@ fn keys() {
         assert_eq!(keys.len(), chunks.data_and_sizes.len() - index - 1);
     }
 }
+
+#[test]
+fn scrub_reports_corrupt_chunks() {
+    let root = temp_dir();
+    let used_space = Rc::new(Cell::new(0));
+    let mut chunk_store = unwrap!(ImmutableChunkStore::new(
+        root.path(),
+        u64::MAX,
+        used_space,
+        Init::New
+    ));
+    let good = IData::Pub(PubImmutableData::new(vec![1, 2, 3]));
+    let bad = IData::Pub(PubImmutableData::new(vec![4, 5, 6]));
+    unwrap!(chunk_store.put(&good));
+    unwrap!(chunk_store.put(&bad));
+    let bad_path = unwrap!(chunk_store.file_path(bad.id()));
+    unwrap!(fs::write(&bad_path, b"garbage"));
+
+    assert_eq!(
+        unwrap!(super::scrub(root.path())),
+        (2, vec![bad_path.clone()])
+    );
+    let used_space = unwrap!(super::used_space_per_store(root.path()));
+    assert_eq!(
+        used_space.get("immutable"),
+        Some(&unwrap!(bincode::serialized_size(&good)).saturating_mul(2))
+    );
+}
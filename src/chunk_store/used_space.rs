@@ -17,7 +17,7 @@ use std::{
     rc::Rc,
 };
 
-const USED_SPACE_FILENAME: &str = "used_space";
+pub(super) const USED_SPACE_FILENAME: &str = "used_space";
 
 /// This holds a record (in-memory and on-disk) of the space used by a single `ChunkStore`, and also
 /// an in-memory record of the total space used by all `ChunkStore`s.
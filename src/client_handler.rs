@@ -6,17 +6,45 @@
 // KIND, either express or implied. Please review the Licences for the specific language governing
 // permissions and limitations relating to use of the SAFE Network Software.
 
+mod audit_log;
 mod auth_keys;
 mod balance;
+mod login_packets;
+mod message_ids;
+mod multisig;
+mod rate_limiter;
+mod recovery;
+mod sessions;
+
+pub use self::{
+    audit_log::{AuditEntry, AuditEvent},
+    auth_keys::AppRestrictions,
+    multisig::{MultisigPolicy, TransferProposal},
+    recovery::RecoveryConfig,
+    sessions::{MAX_BUFFERED_RESPONSES, SESSION_GRACE_PERIOD},
+};
 
 use self::{
+    audit_log::AuditLogDb,
     auth_keys::AuthKeysDb,
     balance::{Balance, BalancesDb},
+    login_packets::LoginPacketStore,
+    message_ids::{is_within_replay_window, MessageIdsDb},
+    multisig::MultisigDb,
+    rate_limiter::RateLimiter,
+    recovery::RecoveryDb,
+    sessions::Sessions,
 };
 use crate::{
     action::Action,
-    chunk_store::{error::Error as ChunkStoreError, LoginPacketChunkStore},
     config_handler::write_connection_info,
+    control::ClientStatus,
+    log_context,
+    messages::{
+        SessionToken, VaultMessage, VaultNotification, VaultRequest, VaultResponse,
+        RATE_LIMIT_EXCEEDED,
+    },
+    metrics::Metrics,
     quic_p2p::{self, Config as QuicP2pConfig, Event, NodeInfo, Peer, QuicP2p},
     rpc::Rpc,
     utils::{self, AuthorisationKind},
@@ -26,7 +54,7 @@ use crate::{
 use bytes::Bytes;
 use crossbeam_channel::{self, Receiver};
 use lazy_static::lazy_static;
-use log::{error, info, trace, warn};
+use log::{debug, error, info, trace, warn};
 use safe_nd::{
     AData, ADataAddress, AppPermissions, AppPublicId, Challenge, Coins, Error as NdError, IData,
     IDataAddress, IDataKind, LoginPacket, MData, Message, MessageId, NodePublicId, Notification,
@@ -36,10 +64,12 @@ use safe_nd::{
 use serde::Serialize;
 use std::{
     cell::Cell,
-    collections::HashMap,
-    fmt::{self, Display, Formatter},
-    net::SocketAddr,
+    collections::{hash_map::Entry, BTreeMap, HashMap},
+    fmt::{self, Debug, Display, Formatter},
+    net::{IpAddr, SocketAddr},
+    path::Path,
     rc::Rc,
+    time::{Duration, Instant},
 };
 use unwrap::unwrap;
 
@@ -48,20 +78,60 @@ lazy_static! {
     pub static ref COST_OF_PUT: Coins = unwrap!(Coins::from_nano(1));
 }
 
+const SHUTTING_DOWN: &str = "Vault is shutting down";
+const STALE_REQUEST: &str = "Request timestamp is outside the replay window";
+/// Time after which a client request still awaiting a response is forgotten.
+const PENDING_REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+
 #[derive(Clone, Debug)]
 struct ClientInfo {
     public_id: PublicId,
+    // Session started for this connection once it passed the challenge.
+    session: SessionToken,
+}
+
+struct ClientCandidate {
+    // The challenge value we sent.
+    challenge: Vec<u8>,
+    created_at: Instant,
+}
+
+// A client request which was accepted for handling and is awaiting a response.
+struct PendingRequest {
+    // The connection the request was received on, and its session.
+    peer_addr: SocketAddr,
+    session: SessionToken,
+    received_at: Instant,
 }
 
 pub(crate) struct ClientHandler {
     id: NodePublicId,
     auth_keys: AuthKeysDb,
+    audit_log: AuditLogDb,
     balances: BalancesDb,
+    message_ids: MessageIdsDb,
+    multisig: MultisigDb,
     clients: HashMap<SocketAddr, ClientInfo>,
     // Map of new client connections to the challenge value we sent them.
-    client_candidates: HashMap<SocketAddr, Vec<u8>>,
+    client_candidates: HashMap<SocketAddr, ClientCandidate>,
+    // Number of client candidates per IP address.
+    client_candidates_per_ip: HashMap<IpAddr, usize>,
+    challenge_timeout: Duration,
+    max_client_candidates: usize,
+    max_client_candidates_per_ip: usize,
+    // Client requests accepted for handling and awaiting a response.
+    client_requests: HashMap<MessageId, PendingRequest>,
+    sessions: Sessions,
+    // Rate limiters for requests per client account and per IP address.
+    client_rate_limiter: RateLimiter<XorName>,
+    ip_rate_limiter: RateLimiter<IpAddr>,
+    max_connections_per_client: usize,
     quic_p2p: QuicP2p,
-    login_packets: LoginPacketChunkStore,
+    login_packets: LoginPacketStore,
+    recovery: RecoveryDb,
+    metrics: Metrics,
+    // Set once the vault starts shutting down, after which new clients and requests are refused.
+    draining: bool,
 }
 
 impl ClientHandler {
@@ -69,25 +139,45 @@ impl ClientHandler {
         id: NodePublicId,
         config: &Config,
         total_used_space: &Rc<Cell<u64>>,
+        metrics: Metrics,
         init_mode: Init,
     ) -> Result<(Self, Receiver<Event>)> {
         let auth_keys = AuthKeysDb::new(config.root_dir(), init_mode)?;
+        let audit_log = AuditLogDb::new(config.root_dir(), init_mode)?;
         let balances = BalancesDb::new(config.root_dir(), init_mode)?;
+        let message_ids = MessageIdsDb::new(config.root_dir(), init_mode)?;
+        let multisig = MultisigDb::new(config.root_dir(), init_mode)?;
         let (quic_p2p, event_receiver) = Self::setup_quic_p2p(config.quic_p2p_config())?;
-        let login_packets = LoginPacketChunkStore::new(
+        let login_packets = LoginPacketStore::new(
             config.root_dir(),
             config.max_capacity(),
-            Rc::clone(&total_used_space),
+            total_used_space,
             init_mode,
         )?;
+        let recovery = RecoveryDb::new(config.root_dir(), init_mode)?;
         let client_handler = Self {
             id,
             auth_keys,
+            audit_log,
             balances,
+            message_ids,
+            multisig,
             clients: Default::default(),
             client_candidates: Default::default(),
+            client_candidates_per_ip: Default::default(),
+            challenge_timeout: config.challenge_timeout(),
+            max_client_candidates: config.max_client_candidates(),
+            max_client_candidates_per_ip: config.max_client_candidates_per_ip(),
+            client_requests: Default::default(),
+            sessions: Default::default(),
+            client_rate_limiter: RateLimiter::new(config.client_rate_limit()),
+            ip_rate_limiter: RateLimiter::new(config.ip_rate_limit()),
+            max_connections_per_client: config.max_connections_per_client(),
             quic_p2p,
             login_packets,
+            recovery,
+            metrics,
+            draining: false,
         };
 
         Ok((client_handler, event_receiver))
@@ -132,6 +222,16 @@ impl ClientHandler {
             return;
         }
 
+        if self.draining {
+            info!(
+                "{}: Rejecting connection from {}: shutting down",
+                self,
+                peer.peer_addr()
+            );
+            self.quic_p2p.disconnect_from(peer.peer_addr());
+            return;
+        }
+
         let peer_addr = match peer {
             Peer::Node { node_info } => {
                 info!(
@@ -144,40 +244,312 @@ impl ClientHandler {
             Peer::Client { peer_addr } => peer_addr,
         };
 
+        let candidates_on_ip = self
+            .client_candidates_per_ip
+            .get(&peer_addr.ip())
+            .cloned()
+            .unwrap_or(0);
+        if candidates_on_ip >= self.max_client_candidates_per_ip {
+            info!(
+                "{}: Rejecting connection from {}: too many pending challenges from this IP",
+                self, peer_addr
+            );
+            self.quic_p2p.disconnect_from(peer_addr);
+            return;
+        }
+
+        if self.client_candidates.len() >= self.max_client_candidates {
+            self.evict_oldest_client_candidate();
+        }
+
         let challenge = utils::random_vec(8);
         self.send(
             peer.clone(),
             &Challenge::Request(PublicId::Node(self.id.clone()), challenge.clone()),
         );
-        let _ = self.client_candidates.insert(peer.peer_addr(), challenge);
+        self.insert_client_candidate(
+            peer_addr,
+            ClientCandidate {
+                challenge,
+                created_at: Instant::now(),
+            },
+        );
         info!("{}: Connected to new client on {}", self, peer_addr);
     }
 
+    /// Handles periodic housekeeping: drops client candidates which haven't answered our challenge
+    /// in time and discards expired per-client state.
+    pub fn handle_timeout(&mut self, now: Instant) {
+        let expired_candidates: Vec<_> = self
+            .client_candidates
+            .iter()
+            .filter(|(_, candidate)| {
+                now.duration_since(candidate.created_at) >= self.challenge_timeout
+            })
+            .map(|(peer_addr, _)| *peer_addr)
+            .collect();
+        for peer_addr in expired_candidates {
+            info!(
+                "{}: Client candidate on {} failed to answer the challenge in time",
+                self, peer_addr
+            );
+            let _ = self.remove_client_candidate(peer_addr);
+            self.quic_p2p.disconnect_from(peer_addr);
+        }
+
+        // While draining, the drain timeout applies instead, so that requests which never got a
+        // response are still counted when the vault stops rather than silently forgotten.
+        let expired_requests: Vec<_> = self
+            .client_requests
+            .iter()
+            .filter(|(_, request)| {
+                !self.draining && now.duration_since(request.received_at) >= PENDING_REQUEST_TIMEOUT
+            })
+            .map(|(message_id, _)| *message_id)
+            .collect();
+        if !expired_requests.is_empty() {
+            info!(
+                "{}: Forgot {} client requests which got no response in time",
+                self,
+                expired_requests.len()
+            );
+        }
+        for message_id in expired_requests {
+            let _ = self.client_requests.remove(&message_id);
+            self.settle_request(&message_id, false);
+        }
+
+        self.sessions.prune(now);
+        let now_secs = utils::unix_time_secs();
+        self.message_ids.prune(now_secs);
+        self.multisig.prune(now_secs);
+        self.client_rate_limiter.prune(now);
+        self.ip_rate_limiter.prune(now);
+        self.finalise_due_recoveries();
+    }
+
+    fn insert_client_candidate(&mut self, peer_addr: SocketAddr, candidate: ClientCandidate) {
+        if self
+            .client_candidates
+            .insert(peer_addr, candidate)
+            .is_none()
+        {
+            *self
+                .client_candidates_per_ip
+                .entry(peer_addr.ip())
+                .or_insert(0) += 1;
+        }
+    }
+
+    fn remove_client_candidate(&mut self, peer_addr: SocketAddr) -> Option<ClientCandidate> {
+        let candidate = self.client_candidates.remove(&peer_addr)?;
+        if let Entry::Occupied(mut entry) = self.client_candidates_per_ip.entry(peer_addr.ip()) {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                let _ = entry.remove();
+            }
+        }
+        Some(candidate)
+    }
+
+    fn evict_oldest_client_candidate(&mut self) {
+        let oldest = self
+            .client_candidates
+            .iter()
+            .min_by_key(|(_, candidate)| candidate.created_at)
+            .map(|(peer_addr, _)| *peer_addr);
+        if let Some(peer_addr) = oldest {
+            info!(
+                "{}: Too many client candidates, dropping the oldest on {}",
+                self, peer_addr
+            );
+            let _ = self.remove_client_candidate(peer_addr);
+            self.quic_p2p.disconnect_from(peer_addr);
+        }
+    }
+
     pub fn handle_connection_failure(&mut self, peer_addr: SocketAddr, error: Error) {
         info!("{}: {}", self, error);
-        if let Some(client) = self.clients.remove(&peer_addr) {
+        if !self.remove_client(peer_addr) {
+            let _ = self.remove_client_candidate(peer_addr);
             info!(
-                "{}: Disconnected from {:?} on {}",
-                self, client.public_id, peer_addr
+                "{}: Disconnected from client candidate on {}",
+                self, peer_addr
             );
+        }
+    }
+
+    /// Handles a message to `peer_addr` which wasn't delivered because the connection dropped.  If
+    /// it's a response and the connection's session can be resumed, it's buffered in the session.
+    pub fn handle_unsent_message(&mut self, peer_addr: SocketAddr, bytes: Bytes) {
+        let message_id = if VaultMessage::is_vault_message(&bytes) {
+            match VaultMessage::deserialise(&bytes) {
+                Ok(VaultMessage::Response { message_id, .. }) => message_id,
+                _ => return,
+            }
         } else {
-            let _ = self.client_candidates.remove(&peer_addr);
+            match bincode::deserialize(&bytes) {
+                Ok(Message::Response { message_id, .. }) => message_id,
+                _ => return,
+            }
+        };
+        let now = Instant::now();
+        let buffered = self
+            .sessions
+            .suspended_on(peer_addr)
+            .map_or(false, |session| {
+                self.sessions
+                    .buffer_response(session, message_id, bytes, now)
+            });
+        if buffered {
             info!(
-                "{}: Disconnected from client candidate on {}",
-                self, peer_addr
+                "{}: Connection on {} dropped, buffering response to {:?}",
+                self, peer_addr, message_id
             );
         }
     }
 
+    /// Drops the connection to the client on `peer_addr`.  Returns `false` if there's no such
+    /// client.
+    pub fn disconnect_client(&mut self, peer_addr: SocketAddr) -> bool {
+        if !self.remove_client(peer_addr) {
+            return false;
+        }
+        self.quic_p2p.disconnect_from(peer_addr);
+        true
+    }
+
+    // Forgets the client on `peer_addr` and suspends its session, so that the responses to its
+    // pending requests are kept for it.  Returns `false` if there's no such client.
+    fn remove_client(&mut self, peer_addr: SocketAddr) -> bool {
+        let client = match self.clients.remove(&peer_addr) {
+            Some(client) => client,
+            None => return false,
+        };
+        info!(
+            "{}: Disconnected from {:?} on {}",
+            self, client.public_id, peer_addr
+        );
+        self.sessions.suspend(client.session, Instant::now());
+        true
+    }
+
+    /// Stops accepting new clients and new requests, and drops the connections which haven't
+    /// answered our challenge yet.  Requests already in progress are still handled.
+    pub fn start_draining(&mut self) {
+        self.draining = true;
+        self.client_candidates_per_ip.clear();
+        let candidates: Vec<_> = self
+            .client_candidates
+            .drain()
+            .map(|(addr, _)| addr)
+            .collect();
+        for peer_addr in candidates {
+            self.quic_p2p.disconnect_from(peer_addr);
+        }
+    }
+
+    /// Writes the state which is only persisted in batches, e.g. the IDs of recent mutations, to
+    /// disk.
+    pub fn flush(&mut self) {
+        self.message_ids.flush();
+    }
+
+    /// Sends `VaultNotification::ShuttingDown` to all clients and drops their connections.
+    pub fn disconnect_all_clients(&mut self) {
+        let peer_addrs: Vec<_> = self.clients.keys().cloned().collect();
+        let notification = VaultMessage::Notification(VaultNotification::ShuttingDown);
+        for peer_addr in peer_addrs {
+            self.send_vault_message(peer_addr, &notification);
+            let _ = self.disconnect_client(peer_addr);
+        }
+    }
+
+    pub fn connected_clients(&self) -> Vec<ClientStatus> {
+        self.clients
+            .iter()
+            .map(|(peer_addr, client)| ClientStatus {
+                peer_addr: *peer_addr,
+                public_id: client.public_id.clone(),
+            })
+            .collect()
+    }
+
+    pub fn client_candidate_count(&self) -> usize {
+        self.client_candidates.len()
+    }
+
+    pub fn pending_request_count(&self) -> usize {
+        self.client_requests.len()
+    }
+
+    /// Applies the settings from `config` which can change while running.
+    pub fn apply_config(&mut self, config: &Config) {
+        self.client_rate_limiter
+            .set_rate(config.client_rate_limit());
+        self.ip_rate_limiter.set_rate(config.ip_rate_limit());
+        self.max_connections_per_client = config.max_connections_per_client();
+        self.challenge_timeout = config.challenge_timeout();
+        self.max_client_candidates = config.max_client_candidates();
+        self.max_client_candidates_per_ip = config.max_client_candidates_per_ip();
+        self.login_packets.set_max_capacity(config.max_capacity());
+    }
+
+    /// Describes the connections which haven't completed the challenge yet and the client requests
+    /// awaiting a response, for diagnostics.
+    pub fn diagnostics(&self, now: Instant) -> String {
+        let mut diagnostics = format!("Client candidates: {}\n", self.client_candidates.len());
+        for (peer_addr, candidate) in &self.client_candidates {
+            diagnostics.push_str(&format!(
+                "  {} for {:?}\n",
+                peer_addr,
+                now.duration_since(candidate.created_at)
+            ));
+        }
+        diagnostics.push_str(&format!(
+            "Pending client requests: {}\n",
+            self.client_requests.len()
+        ));
+        for (message_id, request) in &self.client_requests {
+            diagnostics.push_str(&format!(
+                "  {:?} from {} for {:?}\n",
+                message_id,
+                request.peer_addr,
+                now.duration_since(request.received_at)
+            ));
+        }
+        diagnostics
+    }
+
     pub fn handle_client_message(&mut self, peer_addr: SocketAddr, bytes: Bytes) -> Option<Action> {
         if let Some(client) = self.clients.get(&peer_addr).cloned() {
+            if VaultMessage::is_vault_message(&bytes) {
+                return self.handle_client_vault_message(peer_addr, &client, &bytes);
+            }
             match bincode::deserialize(&bytes) {
                 Ok(Message::Request {
                     request,
                     message_id,
                     signature,
                 }) => {
-                    return self.handle_client_request(&client, request, message_id, signature);
+                    self.metrics.request_received(
+                        utils::request_kind(&request),
+                        message_id,
+                        Instant::now(),
+                    );
+                    if self.draining {
+                        let response = request.error_response(NdError::from(SHUTTING_DOWN));
+                        self.send_response_to_peer(peer_addr, message_id, response);
+                        return None;
+                    }
+                    if let Some(retry_after_ms) =
+                        self.check_rate_limits(peer_addr, &client.public_id, &request, message_id)
+                    {
+                        self.send_rate_limit_error(peer_addr, &request, message_id, retry_after_ms);
+                        return None;
+                    }
+                    return self
+                        .handle_client_request(peer_addr, &client, request, message_id, signature);
                 }
                 Ok(Message::Response { response, .. }) => {
                     info!(
@@ -221,15 +593,15 @@ impl ClientHandler {
         None
     }
 
-    #[allow(clippy::cognitive_complexity)]
     fn handle_client_request(
         &mut self,
+        peer_addr: SocketAddr,
         client: &ClientInfo,
         request: Request,
         message_id: MessageId,
         signature: Option<Signature>,
     ) -> Option<Action> {
-        use Request::*;
+        let _log_context = log_context::enter_request(message_id, &client.public_id, &request);
         trace!(
             "{}: Received ({:?} {:?}) from {}",
             self,
@@ -238,10 +610,377 @@ impl ClientHandler {
             client.public_id
         );
 
-        self.verify_signature(&client.public_id, &request, message_id, signature)?;
-        self.authorise_app(&client.public_id, &request, message_id)?;
-        self.verify_consistent_address(&client.public_id, &request, message_id)?;
+        let now = Instant::now();
+        // Requests failing these checks are answered on the connection they came in on, without
+        // being tracked as pending.
+        self.verify_signature(
+            peer_addr,
+            &client.public_id,
+            &request,
+            message_id,
+            signature,
+        )?;
+        self.verify_unique_message_id(peer_addr, &client.public_id, &request, message_id)?;
+        self.authorise_app(peer_addr, &client.public_id, &request, message_id)?;
+        self.verify_consistent_address(peer_addr, &request, message_id)?;
+
+        if let PublicId::App(ref app_id) = client.public_id {
+            // An app's transfer is charged to its spending allowance once it succeeds.  Until then
+            // the amount is held back, so concurrent transfers can't overspend the allowance.
+            if let Some(amount) = auth_keys::transfer_amount(&request) {
+                self.auth_keys
+                    .reserve_spending_allowance(app_id, message_id, amount);
+            }
+            if let AuthorisationKind::Mut = utils::authorisation_kind(&request) {
+                let event = AuditEvent::AppMutation {
+                    app: *app_id.public_key(),
+                    request_kind: utils::request_kind(&request).to_string(),
+                    destination: utils::destination_address(&request).map(|address| *address),
+                    message_id,
+                };
+                self.audit_log
+                    .record_on_success(message_id, app_id.owner(), event);
+            }
+        }
+
+        let _ = self.client_requests.insert(
+            message_id,
+            PendingRequest {
+                peer_addr,
+                session: client.session,
+                received_at: now,
+            },
+        );
+        let action = self.dispatch_client_request(client, request, message_id);
+        // A request which was neither answered nor passed on won't get a response.
+        if action.is_none() && self.client_requests.remove(&message_id).is_some() {
+            debug!(
+                "{}: Dropped ({:?}) from {} without a response",
+                self, message_id, client.public_id
+            );
+            self.settle_request(&message_id, false);
+        }
+        action
+    }
+
+    fn handle_client_vault_message(
+        &mut self,
+        peer_addr: SocketAddr,
+        client: &ClientInfo,
+        bytes: &[u8],
+    ) -> Option<Action> {
+        match VaultMessage::deserialise(bytes) {
+            Ok(VaultMessage::Request {
+                request,
+                message_id,
+                timestamp,
+                signature,
+            }) => self.handle_client_vault_request(
+                peer_addr, client, request, message_id, timestamp, signature,
+            ),
+            Ok(message) => {
+                info!(
+                    "{}: {} invalidly sent {:?}",
+                    self, client.public_id, message
+                );
+                None
+            }
+            Err(err) => {
+                info!(
+                    "{}: Unable to deserialise vault message from {}: {}",
+                    self, client.public_id, err
+                );
+                None
+            }
+        }
+    }
+
+    // Handles a request in the vault's own protocol.  These all need a valid signature, a recent
+    // timestamp and a unique `MessageId`, and pass the same shutdown and rate limit checks as
+    // `safe_nd` requests.
+    fn handle_client_vault_request(
+        &mut self,
+        peer_addr: SocketAddr,
+        client: &ClientInfo,
+        request: VaultRequest,
+        message_id: MessageId,
+        timestamp: u64,
+        signature: Signature,
+    ) -> Option<Action> {
+        trace!(
+            "{}: Received ({:?} {:?}) from {}",
+            self,
+            request,
+            message_id,
+            client.public_id
+        );
+        self.metrics
+            .request_received(request.kind(), message_id, Instant::now());
+        if self.draining {
+            let response = request.error_response(NdError::from(SHUTTING_DOWN));
+            self.send_vault_response_to_peer(peer_addr, message_id, response);
+            return None;
+        }
+        if let Some(retry_after_ms) =
+            self.check_rate_limits(peer_addr, &client.public_id, &request, message_id)
+        {
+            self.send_vault_response_to_peer(
+                peer_addr,
+                message_id,
+                VaultResponse::RateLimitExceeded { retry_after_ms },
+            );
+            return None;
+        }
+
+        let now_secs = utils::unix_time_secs();
+        let rejection = if request.requires_owner() && utils::client(&client.public_id).is_none() {
+            Some(NdError::AccessDenied)
+        } else if !self.is_valid_vault_request_signature(
+            &client.public_id,
+            &request,
+            message_id,
+            timestamp,
+            &signature,
+        ) {
+            Some(NdError::InvalidSignature)
+        } else if !is_within_replay_window(timestamp, request.is_mutation(), now_secs) {
+            warn!(
+                "{}: ({:?}/{:?}) from {} is stale, made at {}",
+                self, request, message_id, client.public_id, timestamp
+            );
+            Some(NdError::from(STALE_REQUEST))
+        } else if !self.is_unique_vault_request(
+            &client.public_id,
+            &request,
+            message_id,
+            timestamp,
+            now_secs,
+        ) {
+            Some(NdError::DuplicateMessageId)
+        } else {
+            None
+        };
+        if let Some(error) = rejection {
+            self.send_vault_response_to_peer(peer_addr, message_id, request.error_response(error));
+            return None;
+        }
+
+        let _ = self.client_requests.insert(
+            message_id,
+            PendingRequest {
+                peer_addr,
+                session: client.session,
+                received_at: Instant::now(),
+            },
+        );
+        let action = match request {
+            VaultRequest::StartSession => self.handle_start_session(client, message_id),
+            VaultRequest::ResumeSession(token) => {
+                self.handle_resume_session(peer_addr, client, token, message_id)
+            }
+            VaultRequest::SetAppRestrictions { key, restrictions } => {
+                self.handle_set_app_restrictions(client, key, restrictions, message_id)
+            }
+            VaultRequest::GetAuditLog => self.handle_get_audit_log(client, message_id),
+            VaultRequest::SetMultisigPolicy(policy) => {
+                self.handle_set_multisig_policy(client, policy, message_id)
+            }
+            VaultRequest::ApproveMultisigTransfer(proposal) => {
+                self.handle_multisig_transfer_approval(client, proposal, message_id)
+            }
+            VaultRequest::UpdateLoginPacket {
+                login_packet,
+                expected_version,
+            } => self.handle_update_login_packet_vault_req(
+                client,
+                &login_packet,
+                expected_version,
+                message_id,
+            ),
+            VaultRequest::ListLoginPacketVersions(name) => {
+                self.handle_list_login_packet_versions(client, &name, message_id)
+            }
+            VaultRequest::RollbackLoginPacket { name, version } => {
+                self.handle_rollback_login_packet(client, &name, version, message_id)
+            }
+            VaultRequest::SetRecoveryConfig { name, config } => {
+                self.handle_set_recovery_config_req(client, &name, &config, message_id)
+            }
+            VaultRequest::ApproveRecovery(new_login_packet) => {
+                self.handle_recovery_approval_req(client, new_login_packet, message_id)
+            }
+            VaultRequest::VetoRecovery(name) => {
+                self.handle_recovery_veto_req(client, &name, message_id)
+            }
+        };
+        if action.is_none() && self.client_requests.remove(&message_id).is_some() {
+            debug!(
+                "{}: Dropped ({:?}) from {} without a response",
+                self, message_id, client.public_id
+            );
+        }
+        action
+    }
+
+    fn is_valid_vault_request_signature(
+        &self,
+        client_id: &PublicId,
+        request: &VaultRequest,
+        message_id: MessageId,
+        timestamp: u64,
+        signature: &Signature,
+    ) -> bool {
+        let pub_key = match utils::own_key(client_id) {
+            Some(pk) => pk,
+            None => return false,
+        };
+        match pub_key.verify(
+            signature,
+            utils::serialise(&(request, message_id, timestamp)),
+        ) {
+            Ok(_) => true,
+            Err(error) => {
+                warn!(
+                    "{}: ({:?}/{:?}) from {} is invalid: {}",
+                    self, request, message_id, client_id, error
+                );
+                false
+            }
+        }
+    }
+
+    fn is_unique_vault_request(
+        &mut self,
+        client_id: &PublicId,
+        request: &VaultRequest,
+        message_id: MessageId,
+        timestamp: u64,
+        now_secs: u64,
+    ) -> bool {
+        let unique = match utils::own_key(client_id) {
+            Some(public_key) => {
+                !self.client_requests.contains_key(&message_id)
+                    && self.message_ids.insert(
+                        public_key,
+                        message_id,
+                        request.is_mutation(),
+                        Some(timestamp),
+                        now_secs,
+                    )
+            }
+            None => false,
+        };
+        if !unique {
+            warn!(
+                "{}: ({:?}/{:?}) from {} is a duplicate",
+                self, request, message_id, client_id
+            );
+        }
+        unique
+    }
+
+    // Gives the client the token of its connection's session, and keeps the session if the
+    // connection drops.
+    fn handle_start_session(
+        &mut self,
+        client: &ClientInfo,
+        message_id: MessageId,
+    ) -> Option<Action> {
+        let response = if self.sessions.make_resumable(client.session) {
+            Ok(client.session)
+        } else {
+            Err(NdError::InvalidOperation)
+        };
+        self.send_vault_response_to_client(
+            &client.public_id,
+            message_id,
+            VaultResponse::SessionStarted(response),
+        );
+        None
+    }
+
+    // Takes over the suspended session `token` of the same client: requests sent on its dropped
+    // connection are answered on this one from now on, starting with the responses produced while
+    // disconnected.
+    fn handle_resume_session(
+        &mut self,
+        peer_addr: SocketAddr,
+        client: &ClientInfo,
+        token: SessionToken,
+        message_id: MessageId,
+    ) -> Option<Action> {
+        let buffered_responses =
+            match self
+                .sessions
+                .resume(token, &client.public_id, Instant::now())
+            {
+                Some(buffered_responses) => buffered_responses,
+                None => {
+                    info!(
+                        "{}: {} can't resume session {:x}",
+                        self, client.public_id, token
+                    );
+                    self.send_vault_response_to_client(
+                        &client.public_id,
+                        message_id,
+                        VaultResponse::Mutation(Err(NdError::AccessDenied)),
+                    );
+                    return None;
+                }
+            };
+
+        for request in self.client_requests.values_mut() {
+            if request.session == token {
+                request.peer_addr = peer_addr;
+                request.session = client.session;
+            }
+        }
+        info!(
+            "{}: {} resumed session {:x} on {}, replaying {} responses",
+            self,
+            client.public_id,
+            token,
+            peer_addr,
+            buffered_responses.len()
+        );
+        self.send_vault_response_to_client(
+            &client.public_id,
+            message_id,
+            VaultResponse::Mutation(Ok(())),
+        );
+        for (_, bytes) in buffered_responses {
+            self.quic_p2p.send(Peer::Client { peer_addr }, bytes, 0);
+        }
+        None
+    }
+
+    fn handle_set_app_restrictions(
+        &mut self,
+        client: &ClientInfo,
+        key: PublicKey,
+        restrictions: AppRestrictions,
+        message_id: MessageId,
+    ) -> Option<Action> {
+        let client_id = utils::client(&client.public_id)?;
+        let result = self
+            .auth_keys
+            .set_app_restrictions(client_id, key, &restrictions);
+        self.send_vault_response_to_client(
+            &client.public_id,
+            message_id,
+            VaultResponse::Mutation(result),
+        );
+        None
+    }
 
+    #[allow(clippy::cognitive_complexity)]
+    fn dispatch_client_request(
+        &mut self,
+        client: &ClientInfo,
+        request: Request,
+        message_id: MessageId,
+    ) -> Option<Action> {
+        use Request::*;
         match request {
             //
             // ===== Immutable Data =====
@@ -683,21 +1422,29 @@ impl ClientHandler {
                 return;
             }
         };
-        if let Some(challenge) = self.client_candidates.remove(&peer_addr) {
-            match public_key.verify(&signature, challenge) {
+        if let Some(candidate) = self.remove_client_candidate(peer_addr) {
+            match public_key.verify(&signature, candidate.challenge) {
                 Ok(()) => {
-                    // See if we already have a peer connected with the same ID
-                    if let Some(old_peer_addr) = self.lookup_client_peer_addr(&public_id) {
+                    // See if we already have the maximum number of peers connected with the same ID
+                    let connections = self.lookup_client_peer_addrs(&public_id).len();
+                    if connections >= self.max_connections_per_client {
                         info!(
-                            "{}: We already have {} on {}. Cancelling the new connection from {}.",
-                            self, public_id, old_peer_addr, peer_addr
+                            "{}: We already have {} connections from {}. Cancelling the new \
+                             connection from {}.",
+                            self, connections, public_id, peer_addr
                         );
                         self.quic_p2p.disconnect_from(peer_addr);
                         return;
                     }
 
-                    info!("{}: Accepted {} on {}.", self, public_id, peer_addr,);
-                    let _ = self.clients.insert(peer_addr, ClientInfo { public_id });
+                    let session = self.sessions.start(&public_id, peer_addr, Instant::now());
+                    info!(
+                        "{}: Accepted {} on {} in session {:x}.",
+                        self, public_id, peer_addr, session
+                    );
+                    let _ = self
+                        .clients
+                        .insert(peer_addr, ClientInfo { public_id, session });
                 }
                 Err(err) => {
                     info!(
@@ -717,6 +1464,7 @@ impl ClientHandler {
     }
 
     pub fn handle_vault_rpc(&mut self, src: XorName, rpc: Rpc) -> Option<Action> {
+        let _log_context = log_context::enter_rpc(&rpc);
         match rpc {
             Rpc::Request {
                 request,
@@ -883,11 +1631,22 @@ impl ClientHandler {
             | ListMDataUserPermissions(..)
             | ListMDataPermissions(..)
             | GetMDataValue(..)
-            | Mutation(..)
-            | Transaction(..) => {
+            | Mutation(..) => {
                 self.send_response_to_client(&requester, message_id, response);
                 None
             }
+            Transaction(result) => {
+                if self.multisig.remove_in_flight(&message_id).is_some() {
+                    self.send_vault_response_to_client(
+                        &requester,
+                        message_id,
+                        VaultResponse::TransferApproval(result.map(Some)),
+                    );
+                } else {
+                    self.send_response_to_client(&requester, message_id, Transaction(result));
+                }
+                None
+            }
             //
             // ===== Invalid =====
             //
@@ -910,14 +1669,28 @@ impl ClientHandler {
         reason: NdError,
         message_id: MessageId,
     ) -> Option<Action> {
-        if let Err(error) = self.deposit(requester.name(), amount) {
+        let multisig_balance = self.multisig.remove_in_flight(&message_id);
+        let balance = multisig_balance.unwrap_or(*requester.name());
+        if let Err(error) = self.deposit(&balance, amount) {
             error!(
                 "{}: Failed to refund {} coins for {:?}: {:?}",
                 self, amount, requester, error,
             )
         }
 
-        self.send_response_to_client(&requester, message_id, Response::Transaction(Err(reason)));
+        if multisig_balance.is_some() {
+            self.send_vault_response_to_client(
+                &requester,
+                message_id,
+                VaultResponse::TransferApproval(Err(reason)),
+            );
+        } else {
+            self.send_response_to_client(
+                &requester,
+                message_id,
+                Response::Transaction(Err(reason)),
+            );
+        }
         None
     }
 
@@ -1040,6 +1813,90 @@ impl ClientHandler {
         }
     }
 
+    /// Makes transfers out of the requester's balance subject to `policy`.
+    fn handle_set_multisig_policy(
+        &mut self,
+        client: &ClientInfo,
+        policy: MultisigPolicy,
+        message_id: MessageId,
+    ) -> Option<Action> {
+        let balance = *utils::client(&client.public_id)?.name();
+        let result = if self.balance(&balance).is_none() {
+            Err(NdError::NoSuchBalance)
+        } else {
+            self.multisig.set_policy(&balance, &policy)
+        };
+        self.send_vault_response_to_client(
+            &client.public_id,
+            message_id,
+            VaultResponse::Mutation(result),
+        );
+        None
+    }
+
+    /// Adds the requester's approval to a transfer out of a multisig balance.  The approval is
+    /// signed as part of the request.  Once enough owners have approved it, the coins are withdrawn
+    /// and the transfer proceeds like a `TransferCoins` request from the final approver, except
+    /// that refunds go back to the multisig balance.  The transaction ID is used up at that point,
+    /// even if the withdrawal or the transfer then fails.
+    fn handle_multisig_transfer_approval(
+        &mut self,
+        client: &ClientInfo,
+        proposal: TransferProposal,
+        message_id: MessageId,
+    ) -> Option<Action> {
+        let requester = &client.public_id;
+        let owner = *utils::own_key(requester)?;
+        let result = if proposal.amount.as_nano() == 0 {
+            Err(NdError::InvalidOperation)
+        } else {
+            self.multisig
+                .approve(proposal, owner, utils::unix_time_secs())
+        }
+        .and_then(|approved| {
+            if !approved {
+                return Ok(false);
+            }
+            let (public_key, balance) = self
+                .balances
+                .get_key_value(&proposal.balance)
+                .ok_or(NdError::NoSuchBalance)?;
+            self.debit(&public_key, balance, proposal.amount)?;
+            Ok(true)
+        });
+
+        match result {
+            Ok(true) => {
+                self.multisig.insert_in_flight(message_id, proposal.balance);
+                Some(Action::ForwardClientRequest(Rpc::Request {
+                    request: Request::TransferCoins {
+                        destination: proposal.destination,
+                        amount: proposal.amount,
+                        transaction_id: proposal.transaction_id,
+                    },
+                    requester: requester.clone(),
+                    message_id,
+                }))
+            }
+            Ok(false) => {
+                self.send_vault_response_to_client(
+                    requester,
+                    message_id,
+                    VaultResponse::TransferApproval(Ok(None)),
+                );
+                None
+            }
+            Err(error) => {
+                self.send_vault_response_to_client(
+                    requester,
+                    message_id,
+                    VaultResponse::TransferApproval(Err(error)),
+                );
+                None
+            }
+        }
+    }
+
     fn handle_transfer_coins_vault_req(
         &mut self,
         requester: PublicId,
@@ -1050,6 +1907,8 @@ impl ClientHandler {
     ) -> Option<Action> {
         let rpc = match self.deposit(&destination, amount) {
             Ok(()) => {
+                self.metrics.coins_transferred(amount);
+
                 let transaction = Transaction {
                     id: transaction_id,
                     amount,
@@ -1082,8 +1941,15 @@ impl ClientHandler {
     }
 
     fn notify_destination_owners(&mut self, destination: &XorName, transaction: Transaction) {
-        for client_id in self.lookup_client_and_its_apps(destination) {
-            self.send_notification_to_client(client_id, Notification(transaction));
+        let peer_addrs = self.lookup_client_and_its_apps(destination);
+        if peer_addrs.is_empty() {
+            info!(
+                "{}: can't notify {} as it's not connected.",
+                self, destination
+            );
+        }
+        for peer_addr in peer_addrs {
+            self.send_notification_to_client(peer_addr, Notification(transaction));
         }
     }
 
@@ -1102,72 +1968,228 @@ impl ClientHandler {
                 self, owner_key
             );
 
-            Err(NdError::BalanceExists)
-        } else {
-            let balance = Balance { coins: amount };
-            self.put_balance(&owner_key, &balance)?;
-            Ok(())
+            Err(NdError::BalanceExists)
+        } else {
+            let balance = Balance { coins: amount };
+            self.put_balance(&owner_key, &balance)?;
+            Ok(())
+        }
+    }
+
+    fn send<T: Serialize>(&mut self, recipient: Peer, msg: &T) {
+        let msg = utils::serialise(msg);
+        let msg = Bytes::from(msg);
+        self.quic_p2p.send(recipient, msg, 0)
+    }
+
+    fn send_notification_to_client(&mut self, peer_addr: SocketAddr, notification: Notification) {
+        self.send(
+            Peer::Client { peer_addr },
+            &Message::Notification { notification },
+        )
+    }
+
+    fn send_response_to_client(
+        &mut self,
+        client_id: &PublicId,
+        message_id: MessageId,
+        response: Response,
+    ) {
+        let error_kind = utils::response_error(&response).map(utils::error_kind);
+        self.record_response(message_id, error_kind);
+        self.settle_request(&message_id, error_kind.is_none());
+        let bytes = Bytes::from(utils::serialise(&Message::Response {
+            response,
+            message_id,
+        }));
+        self.send_to_requester(client_id, message_id, bytes)
+    }
+
+    // Sends the serialised response to the connection the request was received on.  If that has
+    // dropped, the response is buffered in its session until the client resumes it, or failing
+    // that, is sent to any other connection of the same client.
+    fn send_to_requester(&mut self, client_id: &PublicId, message_id: MessageId, bytes: Bytes) {
+        if let Some(request) = self.client_requests.remove(&message_id) {
+            let still_connected = self
+                .clients
+                .get(&request.peer_addr)
+                .map(|client| &client.public_id == client_id && client.session == request.session)
+                .unwrap_or(false);
+            if still_connected {
+                debug!(
+                    "{}: Sending response to {:?} on {}",
+                    self, message_id, request.peer_addr
+                );
+                self.quic_p2p.send(
+                    Peer::Client {
+                        peer_addr: request.peer_addr,
+                    },
+                    bytes,
+                    0,
+                );
+                return;
+            }
+            if self.sessions.buffer_response(
+                request.session,
+                message_id,
+                bytes.clone(),
+                Instant::now(),
+            ) {
+                info!(
+                    "{}: client {} not connected, buffering response to {:?}",
+                    self, client_id, message_id
+                );
+                return;
+            }
+        }
+
+        match self.lookup_client_peer_addrs(client_id).first() {
+            Some(peer_addr) => self.quic_p2p.send(
+                Peer::Client {
+                    peer_addr: *peer_addr,
+                },
+                bytes,
+                0,
+            ),
+            None => info!("{}: client {} not found", self, client_id),
         }
     }
 
-    fn send<T: Serialize>(&mut self, recipient: Peer, msg: &T) {
-        let msg = utils::serialise(msg);
-        let msg = Bytes::from(msg);
-        self.quic_p2p.send(recipient, msg, 0)
+    // Sends the response on the given connection, e.g. the one a rejected request came in on.
+    fn send_response_to_peer(
+        &mut self,
+        peer_addr: SocketAddr,
+        message_id: MessageId,
+        response: Response,
+    ) {
+        self.record_response(
+            message_id,
+            utils::response_error(&response).map(utils::error_kind),
+        );
+        debug!(
+            "{}: Sending response to {:?} on {}",
+            self, message_id, peer_addr
+        );
+        self.send(
+            Peer::Client { peer_addr },
+            &Message::Response {
+                response,
+                message_id,
+            },
+        )
     }
 
-    fn send_notification_to_client(&mut self, client_id: PublicId, notification: Notification) {
-        let peer_addr = if let Some(peer_addr) = self.lookup_client_peer_addr(&client_id) {
-            *peer_addr
-        } else {
-            info!(
-                "{}: can't notify {} as it's not connected.",
-                self, client_id
-            );
-            return;
-        };
-
+    // Rejects a `safe_nd` request over the rate limits with an error response of the request's
+    // type, counted as a rate limit rejection rather than by the error's variant.
+    fn send_rate_limit_error(
+        &mut self,
+        peer_addr: SocketAddr,
+        request: &Request,
+        message_id: MessageId,
+        retry_after_ms: u64,
+    ) {
+        let error = NdError::from(format!(
+            "Rate limit exceeded, retry after {} ms",
+            retry_after_ms
+        ));
+        self.record_response(message_id, Some(RATE_LIMIT_EXCEEDED));
         self.send(
             Peer::Client { peer_addr },
-            &Message::Notification { notification },
+            &Message::Response {
+                response: request.error_response(error),
+                message_id,
+            },
         )
     }
 
-    fn send_response_to_client(
+    // Settles what was held back for an accepted request of an app until its outcome is known:
+    // the reserved spending allowance and the audit log entry.
+    fn settle_request(&mut self, message_id: &MessageId, succeeded: bool) {
+        self.auth_keys
+            .settle_spending_allowance(message_id, succeeded);
+        self.audit_log.settle(message_id, succeeded);
+    }
+
+    fn send_vault_message(&mut self, peer_addr: SocketAddr, message: &VaultMessage) {
+        self.quic_p2p.send(
+            Peer::Client { peer_addr },
+            Bytes::from(message.serialise()),
+            0,
+        )
+    }
+
+    fn send_vault_response_to_client(
         &mut self,
         client_id: &PublicId,
         message_id: MessageId,
-        response: Response,
+        response: VaultResponse,
     ) {
-        let peer_addr = if let Some(peer_addr) = self.lookup_client_peer_addr(client_id) {
-            *peer_addr
-        } else {
-            info!("{}: client {} not found", self, client_id);
-            return;
+        self.record_response(message_id, response.error_kind());
+        let message = VaultMessage::Response {
+            response,
+            message_id,
         };
+        self.send_to_requester(client_id, message_id, Bytes::from(message.serialise()))
+    }
 
-        self.send(
-            Peer::Client { peer_addr },
-            &Message::Response {
+    fn send_vault_response_to_peer(
+        &mut self,
+        peer_addr: SocketAddr,
+        message_id: MessageId,
+        response: VaultResponse,
+    ) {
+        self.record_response(message_id, response.error_kind());
+        self.send_vault_message(
+            peer_addr,
+            &VaultMessage::Response {
                 response,
                 message_id,
             },
         )
     }
 
-    fn lookup_client_peer_addr(&self, id: &PublicId) -> Option<&SocketAddr> {
+    // Records the outcome of a response of either protocol: `None` for success, otherwise the
+    // error type.
+    fn record_response(&self, message_id: MessageId, error_kind: Option<&'static str>) {
+        self.metrics
+            .response_sent(message_id, error_kind, Instant::now());
+        log_context::set_outcome(error_kind);
+    }
+
+    fn lookup_client_peer_addrs(&self, id: &PublicId) -> Vec<SocketAddr> {
+        self.clients
+            .iter()
+            .filter_map(|(peer_addr, client)| {
+                if &client.public_id == id {
+                    Some(*peer_addr)
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    // Returns the addresses of all connections signing with `key`, whether of a client or an app.
+    fn lookup_own_key_peer_addrs(&self, key: &PublicKey) -> Vec<SocketAddr> {
         self.clients
             .iter()
-            .find(|(_, client)| &client.public_id == id)
-            .map(|(peer_addr, _)| peer_addr)
+            .filter_map(|(peer_addr, client)| {
+                if utils::own_key(&client.public_id) == Some(key) {
+                    Some(*peer_addr)
+                } else {
+                    None
+                }
+            })
+            .collect()
     }
 
-    fn lookup_client_and_its_apps(&self, name: &XorName) -> Vec<PublicId> {
+    // Returns the addresses of all connections of the client and of its apps.
+    fn lookup_client_and_its_apps(&self, name: &XorName) -> Vec<SocketAddr> {
         self.clients
-            .values()
-            .filter_map(|client| {
+            .iter()
+            .filter_map(|(peer_addr, client)| {
                 if client.public_id.name() == name {
-                    Some(client.public_id.clone())
+                    Some(*peer_addr)
                 } else {
                     None
                 }
@@ -1183,15 +2205,29 @@ impl ClientHandler {
         if amount.as_nano() == 0 {
             return Err(NdError::InvalidOperation);
         }
-        let (public_key, mut balance) = self
+        let (public_key, balance) = self
             .balances
             .get_key_value(key)
             .ok_or(NdError::NoSuchBalance)?;
+        if self.multisig.is_multisig(&XorName::from(public_key)) {
+            // Transfers out of multisig balances need to go through
+            // `handle_multisig_transfer_approval` instead.
+            return Err(NdError::AccessDenied);
+        }
+        self.debit(&public_key, balance, amount)
+    }
+
+    fn debit(
+        &mut self,
+        public_key: &PublicKey,
+        mut balance: Balance,
+        amount: Coins,
+    ) -> Result<(), NdError> {
         balance.coins = balance
             .coins
             .checked_sub(amount)
             .ok_or(NdError::InsufficientBalance)?;
-        self.put_balance(&public_key, &balance)
+        self.put_balance(public_key, &balance)
     }
 
     fn deposit<K: balance::Key>(&mut self, key: &K, amount: Coins) -> Result<(), NdError> {
@@ -1285,13 +2321,7 @@ impl ClientHandler {
         login_packet: &LoginPacket,
         message_id: MessageId,
     ) -> Option<Action> {
-        let result = if self.login_packets.has(login_packet.destination()) {
-            Err(NdError::LoginPacketExists)
-        } else {
-            self.login_packets
-                .put(login_packet)
-                .map_err(|error| error.to_string().into())
-        };
+        let result = self.login_packets.create(login_packet);
         Some(Action::RespondToClientHandlers {
             sender: *login_packet.destination(),
             rpc: Rpc::Response {
@@ -1388,17 +2418,13 @@ impl ClientHandler {
 
             // TODO - (after phase one) On failure, respond to src to allow them to refund the
             //        original payer
-            let result = if self.login_packets.has(login_packet.destination()) {
-                Err(NdError::LoginPacketExists)
-            } else {
-                self.login_packets
-                    .put(&login_packet)
-                    .map(|_| Transaction {
-                        id: transaction_id,
-                        amount,
-                    })
-                    .map_err(|error| error.to_string().into())
-            };
+            let result = self
+                .login_packets
+                .create(&login_packet)
+                .map(|_| Transaction {
+                    id: transaction_id,
+                    amount,
+                });
             Some(Action::RespondToClientHandlers {
                 sender: *login_packet.destination(),
                 rpc: Rpc::Response {
@@ -1410,12 +2436,50 @@ impl ClientHandler {
         }
     }
 
+    // `safe_nd::Request::UpdateLoginPacket` doesn't say which version it's based on, so it replaces
+    // the packet unconditionally.  Clients updating from several devices should use
+    // `VaultRequest::UpdateLoginPacket` instead.
     fn handle_update_login_packet_req(
         &mut self,
         client_id: &PublicId,
         updated_login_packet: &LoginPacket,
         message_id: MessageId,
     ) -> Option<Action> {
+        let result = self
+            .update_login_packet(client_id, updated_login_packet, None)?
+            .map(|_new_version| ());
+        self.send_response_to_client(client_id, message_id, Response::Mutation(result));
+        None
+    }
+
+    fn handle_update_login_packet_vault_req(
+        &mut self,
+        client: &ClientInfo,
+        updated_login_packet: &LoginPacket,
+        expected_version: u64,
+        message_id: MessageId,
+    ) -> Option<Action> {
+        let result = self.update_login_packet(
+            &client.public_id,
+            updated_login_packet,
+            Some(expected_version),
+        )?;
+        self.send_vault_response_to_client(
+            &client.public_id,
+            message_id,
+            VaultResponse::LoginPacketVersion(result),
+        );
+        None
+    }
+
+    // Returns the new version of the login packet, or `None` if the requester isn't a client or
+    // an app.
+    fn update_login_packet(
+        &mut self,
+        client_id: &PublicId,
+        updated_login_packet: &LoginPacket,
+        expected_version: Option<u64>,
+    ) -> Option<NdResult<u64>> {
         let result = self
             .login_packet(
                 utils::own_key(client_id)?,
@@ -1426,10 +2490,43 @@ impl ClientHandler {
                     return Err(NdError::ExceededSize);
                 }
                 self.login_packets
-                    .put(updated_login_packet)
-                    .map_err(|err| err.to_string().into())
+                    .update(updated_login_packet, expected_version)
             });
-        self.send_response_to_client(client_id, message_id, Response::Mutation(result));
+        Some(result)
+    }
+
+    fn handle_list_login_packet_versions(
+        &mut self,
+        client: &ClientInfo,
+        name: &XorName,
+        message_id: MessageId,
+    ) -> Option<Action> {
+        let result = self
+            .login_packet(utils::own_key(&client.public_id)?, name)
+            .and_then(|_| self.login_packets.versions(name));
+        self.send_vault_response_to_client(
+            &client.public_id,
+            message_id,
+            VaultResponse::LoginPacketVersions(result),
+        );
+        None
+    }
+
+    fn handle_rollback_login_packet(
+        &mut self,
+        client: &ClientInfo,
+        name: &XorName,
+        version: u64,
+        message_id: MessageId,
+    ) -> Option<Action> {
+        let result = self
+            .login_packet(utils::own_key(&client.public_id)?, name)
+            .and_then(|_| self.login_packets.rollback(name, version));
+        self.send_vault_response_to_client(
+            &client.public_id,
+            message_id,
+            VaultResponse::LoginPacketVersion(result),
+        );
         None
     }
 
@@ -1446,6 +2543,108 @@ impl ClientHandler {
         None
     }
 
+    /// Sets the recovery keys, threshold and delay of the requester's login packet.
+    fn handle_set_recovery_config_req(
+        &mut self,
+        client: &ClientInfo,
+        address: &XorName,
+        config: &RecoveryConfig,
+        message_id: MessageId,
+    ) -> Option<Action> {
+        let result = self
+            .login_packet(utils::own_key(&client.public_id)?, address)
+            .and_then(|_| self.recovery.set_config(address, config));
+        self.send_vault_response_to_client(
+            &client.public_id,
+            message_id,
+            VaultResponse::Mutation(result),
+        );
+        None
+    }
+
+    /// Adds the requester's approval, as one of the recovery keys, to replacing a login packet.
+    /// The approval is signed as part of the request.  Once the threshold is reached, the current
+    /// owner's connections are notified so that they can veto the recovery in time.
+    fn handle_recovery_approval_req(
+        &mut self,
+        client: &ClientInfo,
+        new_login_packet: LoginPacket,
+        message_id: MessageId,
+    ) -> Option<Action> {
+        let approver = *utils::own_key(&client.public_id)?;
+        let address = *new_login_packet.destination();
+        let current_owner = self
+            .login_packets
+            .get(&address)
+            .map(|login_packet| *login_packet.authorised_getter());
+        let result = if !new_login_packet.size_is_valid() {
+            Err(NdError::ExceededSize)
+        } else if let Err(error) = &current_owner {
+            Err(error.clone())
+        } else {
+            self.recovery
+                .approve(new_login_packet, approver, utils::unix_time_secs())
+        };
+
+        if let (Ok(owner), Ok(Some(due_at))) = (current_owner, &result) {
+            info!(
+                "{}: Recovery of login packet {} approved, taking effect at {} unless vetoed",
+                self, address, due_at
+            );
+            let notification = VaultMessage::Notification(VaultNotification::RecoveryApproved {
+                name: address,
+                due_at: *due_at,
+            });
+            for peer_addr in self.lookup_own_key_peer_addrs(&owner) {
+                self.send_vault_message(peer_addr, &notification);
+            }
+        }
+        self.send_vault_response_to_client(
+            &client.public_id,
+            message_id,
+            VaultResponse::RecoveryApproval(result),
+        );
+        None
+    }
+
+    /// Cancels a recovery in progress for the requester's login packet.
+    fn handle_recovery_veto_req(
+        &mut self,
+        client: &ClientInfo,
+        address: &XorName,
+        message_id: MessageId,
+    ) -> Option<Action> {
+        let result = self
+            .login_packet(utils::own_key(&client.public_id)?, address)
+            .and_then(|_| self.recovery.veto(address));
+        self.send_vault_response_to_client(
+            &client.public_id,
+            message_id,
+            VaultResponse::Mutation(result),
+        );
+        None
+    }
+
+    // Replaces the login packets whose recovery delay has elapsed without a veto.
+    fn finalise_due_recoveries(&mut self) {
+        for login_packet in self.recovery.take_due(utils::unix_time_secs()) {
+            match self.login_packets.update(&login_packet, None) {
+                Ok(version) => info!(
+                    "{}: Recovered login packet {} as version {}",
+                    self,
+                    login_packet.destination(),
+                    version
+                ),
+                Err(error) => error!(
+                    "{}: Failed to recover login packet {}: {}",
+                    self,
+                    login_packet.destination(),
+                    error
+                ),
+            }
+        }
+    }
+
     fn login_packet(
         &self,
         requester_pub_key: &PublicKey,
@@ -1453,10 +2652,6 @@ impl ClientHandler {
     ) -> NdResult<LoginPacket> {
         self.login_packets
             .get(packet_name)
-            .map_err(|e| match e {
-                ChunkStoreError::NoSuchChunk => NdError::NoSuchLoginPacket,
-                error => error.to_string().into(),
-            })
             .and_then(|login_packet| {
                 if login_packet.authorised_getter() == requester_pub_key {
                     Ok(login_packet)
@@ -1491,12 +2686,14 @@ impl ClientHandler {
         permissions: AppPermissions,
         message_id: MessageId,
     ) -> Option<Action> {
-        let result = self.auth_keys.ins_auth_key(
-            utils::client(&client.public_id)?,
-            key,
-            new_version,
-            permissions,
-        );
+        let client_id = utils::client(&client.public_id)?;
+        let result = self
+            .auth_keys
+            .ins_auth_key(client_id, key, new_version, permissions);
+        if result.is_ok() {
+            self.audit_log
+                .record(client_id, AuditEvent::AuthKeyInserted { key, permissions });
+        }
         self.send_response_to_client(&client.public_id, message_id, Response::Mutation(result));
         None
     }
@@ -1508,16 +2705,75 @@ impl ClientHandler {
         new_version: u64,
         message_id: MessageId,
     ) -> Option<Action> {
-        let result =
-            self.auth_keys
-                .del_auth_key(utils::client(&client.public_id)?, key, new_version);
+        let client_id = utils::client(&client.public_id)?;
+        let result = self.auth_keys.del_auth_key(client_id, key, new_version);
+        if result.is_ok() {
+            self.audit_log
+                .record(client_id, AuditEvent::AuthKeyDeleted { key });
+        }
         self.send_response_to_client(&client.public_id, message_id, Response::Mutation(result));
         None
     }
 
+    /// Sends the audit log to the requesting client.  Apps can't read their owner's log.
+    fn handle_get_audit_log(
+        &mut self,
+        client: &ClientInfo,
+        message_id: MessageId,
+    ) -> Option<Action> {
+        let entries = self.audit_log.entries(utils::client(&client.public_id)?);
+        self.send_vault_response_to_client(
+            &client.public_id,
+            message_id,
+            VaultResponse::GetAuditLog(Ok(entries)),
+        );
+        None
+    }
+
+    // Charge the request against the rate limits of the client and of its IP address.
+    //
+    // TODO: Instead of rejecting outright, we could let clients over the limit pay with a proof of
+    // work, e.g. by requiring a signature over a seed which matches an increasingly difficult
+    // pattern (see the `GetIData` TODO in `dispatch_client_request`).
+    //
+    // The request is only charged if both limits allow it, so a rejection by one doesn't use up the
+    // other.  Returns the time in milliseconds after which a retry will be accepted if the request
+    // is rejected.  The caller answers it in the request's own protocol, as a `safe_nd` client
+    // can't decode a `VaultResponse`.
+    fn check_rate_limits<R: Debug>(
+        &mut self,
+        peer_addr: SocketAddr,
+        public_id: &PublicId,
+        request: &R,
+        message_id: MessageId,
+    ) -> Option<u64> {
+        let now = Instant::now();
+        let wait_time = self
+            .client_rate_limiter
+            .wait_time(public_id.name(), now)
+            .into_iter()
+            .chain(self.ip_rate_limiter.wait_time(&peer_addr.ip(), now))
+            .max();
+        let wait_time = match wait_time {
+            Some(wait_time) => wait_time,
+            None => {
+                self.client_rate_limiter.acquire(*public_id.name(), now);
+                self.ip_rate_limiter.acquire(peer_addr.ip(), now);
+                return None;
+            }
+        };
+
+        warn!(
+            "{}: Rejecting ({:?}/{:?}) from {} on {}: rate limit exceeded",
+            self, request, message_id, public_id, peer_addr
+        );
+        Some(wait_time.as_secs() * 1000 + u64::from(wait_time.subsec_millis()) + 1)
+    }
+
     // Verify that valid signature is provided if the request requires it.
     fn verify_signature(
         &mut self,
+        peer_addr: SocketAddr,
         public_id: &PublicId,
         request: &Request,
         message_id: MessageId,
@@ -1547,8 +2803,8 @@ impl ClientHandler {
         if valid {
             Some(())
         } else {
-            self.send_response_to_client(
-                public_id,
+            self.send_response_to_peer(
+                peer_addr,
                 message_id,
                 request.error_response(NdError::InvalidSignature),
             );
@@ -1556,9 +2812,55 @@ impl ClientHandler {
         }
     }
 
+    // Verify that a signed request hasn't been seen before, so that it can't be replayed, and that
+    // no request with the same ID is still awaiting a response.
+    fn verify_unique_message_id(
+        &mut self,
+        peer_addr: SocketAddr,
+        public_id: &PublicId,
+        request: &Request,
+        message_id: MessageId,
+    ) -> Option<()> {
+        // Only mutations need to be remembered across restarts.  Unsigned requests are only
+        // checked against the ones in flight.  These requests carry no timestamp, so their IDs are
+        // remembered for the replay window from when they're seen.
+        let is_mutation = match utils::authorisation_kind(request) {
+            AuthorisationKind::GetPub => None,
+            AuthorisationKind::GetUnpub | AuthorisationKind::GetBalance => Some(false),
+            AuthorisationKind::Mut => Some(true),
+        };
+        let public_key = utils::own_key(public_id)?;
+        let unique = !self.client_requests.contains_key(&message_id)
+            && is_mutation.map_or(true, |is_mutation| {
+                self.message_ids.insert(
+                    public_key,
+                    message_id,
+                    is_mutation,
+                    None,
+                    utils::unix_time_secs(),
+                )
+            });
+
+        if unique {
+            Some(())
+        } else {
+            warn!(
+                "{}: ({:?}/{:?}) from {} is a duplicate",
+                self, request, message_id, public_id
+            );
+            self.send_response_to_peer(
+                peer_addr,
+                message_id,
+                request.error_response(NdError::DuplicateMessageId),
+            );
+            None
+        }
+    }
+
     // If the client is app, check if it is authorised to perform the given request.
     fn authorise_app(
         &mut self,
+        peer_addr: SocketAddr,
         public_id: &PublicId,
         request: &Request,
         message_id: MessageId,
@@ -1578,14 +2880,14 @@ impl ClientHandler {
             AuthorisationKind::Mut => {
                 self.check_app_permissions(app_id, |perms| perms.transfer_coins)
             }
-        };
+        }
+        .and_then(|()| self.check_app_restrictions(app_id, request));
 
         if let Err(error) = result {
-            self.send_response_to_client(public_id, message_id, request.error_response(error));
-            None
-        } else {
-            Some(())
+            self.send_response_to_peer(peer_addr, message_id, request.error_response(error));
+            return None;
         }
+        Some(())
     }
 
     fn check_app_permissions(
@@ -1605,9 +2907,25 @@ impl ClientHandler {
         }
     }
 
+    // Checks the app's expiry, spending allowance and allowed addresses.  Publicly readable data is
+    // never restricted.
+    fn check_app_restrictions(
+        &self,
+        app_id: &AppPublicId,
+        request: &Request,
+    ) -> Result<(), NdError> {
+        if let AuthorisationKind::GetPub = utils::authorisation_kind(request) {
+            return Ok(());
+        }
+
+        self.auth_keys
+            .app_restrictions(app_id)
+            .check(request, utils::unix_time_secs())
+    }
+
     fn verify_consistent_address(
         &mut self,
-        public_id: &PublicId,
+        peer_addr: SocketAddr,
         request: &Request,
         message_id: MessageId,
     ) -> Option<()> {
@@ -1619,8 +2937,8 @@ impl ClientHandler {
             _ => true,
         };
         if !consistent {
-            self.send_response_to_client(
-                public_id,
+            self.send_response_to_peer(
+                peer_addr,
                 message_id,
                 Response::Mutation(Err(NdError::InvalidOperation)),
             );
@@ -1636,3 +2954,23 @@ impl Display for ClientHandler {
         write!(formatter, "{}", self.id.name())
     }
 }
+
+/// Reads the balance, authorised keys and auth keys version of the client called `name` from the
+/// DBs in `root_dir`, for inspecting a stopped vault.  The DBs are opened read-only.
+pub(crate) fn read_client_account(
+    root_dir: &Path,
+    name: &XorName,
+) -> Result<(
+    Option<Coins>,
+    BTreeMap<PublicKey, AppPermissions>,
+    Option<u64>,
+)> {
+    let balance = BalancesDb::load_read_only(root_dir)?
+        .get(name)
+        .map(|balance| balance.coins);
+    let (auth_keys, version) = match AuthKeysDb::load_read_only(root_dir)?.find_by_name(name) {
+        Some((auth_keys, version)) => (auth_keys, Some(version)),
+        None => (BTreeMap::new(), None),
+    };
+    Ok((balance, auth_keys, version))
+}
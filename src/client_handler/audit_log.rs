@@ -0,0 +1,172 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{utils, vault::Init, Result, ToDbKey};
+use log::warn;
+use pickledb::PickleDb;
+use safe_nd::{AppPermissions, ClientPublicId, MessageId, PublicKey, XorName};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    path::Path,
+};
+
+const AUDIT_LOG_DB_NAME: &str = "audit_log.db";
+/// Maximum number of entries kept per client.  The oldest entries are dropped first.
+const MAX_ENTRIES_PER_CLIENT: usize = 1000;
+
+/// An event affecting a client's account.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum AuditEvent {
+    /// An app key was authorised, or its permissions were changed.
+    AuthKeyInserted {
+        /// The app's key.
+        key: PublicKey,
+        /// The app's new permissions.
+        permissions: AppPermissions,
+    },
+    /// An app key was revoked.
+    AuthKeyDeleted {
+        /// The app's key.
+        key: PublicKey,
+    },
+    /// An authorised app successfully performed a mutation on behalf of the client.
+    AppMutation {
+        /// The app's key.
+        app: PublicKey,
+        /// Kind of the request, e.g. `PutIData`.
+        request_kind: String,
+        /// Address of the mutated data, if any.
+        destination: Option<XorName>,
+        /// ID of the request.
+        message_id: MessageId,
+    },
+}
+
+/// A timestamped `AuditEvent`, as returned by `VaultRequest::GetAuditLog`.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct AuditEntry {
+    /// Time of the event, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// The event.
+    pub event: AuditEvent,
+}
+
+/// Durable, bounded per-client log of auth key changes and app activity.
+pub(super) struct AuditLogDb {
+    db: PickleDb,
+    // Events of requests which are still in progress, only recorded if they succeed.
+    pending: HashMap<MessageId, (ClientPublicId, AuditEvent)>,
+}
+
+impl AuditLogDb {
+    pub fn new<R: AsRef<Path>>(root_dir: R, init_mode: Init) -> Result<Self> {
+        Ok(Self {
+            db: utils::new_db(root_dir, AUDIT_LOG_DB_NAME, init_mode)?,
+            pending: HashMap::new(),
+        })
+    }
+
+    /// Holds `event` of the request with `message_id` until the request's outcome is known.
+    pub fn record_on_success(
+        &mut self,
+        message_id: MessageId,
+        client_id: &ClientPublicId,
+        event: AuditEvent,
+    ) {
+        let _ = self.pending.insert(message_id, (client_id.clone(), event));
+    }
+
+    /// Records the event held for the request with `message_id`, if the request `succeeded`, or
+    /// else discards it.
+    pub fn settle(&mut self, message_id: &MessageId, succeeded: bool) {
+        if let Some((client_id, event)) = self.pending.remove(message_id) {
+            if succeeded {
+                self.record(&client_id, event);
+            }
+        }
+    }
+
+    /// Appends `event` to the log of the specified client.
+    pub fn record(&mut self, client_id: &ClientPublicId, event: AuditEvent) {
+        let timestamp = utils::unix_time_secs();
+        let db_key = client_id.to_db_key();
+        let mut entries = self
+            .db
+            .get::<VecDeque<AuditEntry>>(&db_key)
+            .unwrap_or_default();
+        if entries.len() >= MAX_ENTRIES_PER_CLIENT {
+            let _ = entries.pop_front();
+        }
+        entries.push_back(AuditEntry { timestamp, event });
+
+        if let Err(error) = self.db.set(&db_key, &entries) {
+            warn!("Failed to write audit log entry to DB: {:?}", error);
+        }
+    }
+
+    /// Returns the logged entries of the specified client, oldest first.
+    pub fn entries(&self, client_id: &ClientPublicId) -> Vec<AuditEntry> {
+        self.db
+            .get::<VecDeque<AuditEntry>>(&client_id.to_db_key())
+            .map(Vec::from)
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use safe_nd::ClientFullId;
+    use tempdir::TempDir;
+    use unwrap::unwrap;
+
+    #[test]
+    fn log_is_per_client_and_bounded() {
+        let root = unwrap!(TempDir::new("audit_log"));
+        let mut audit_log = unwrap!(AuditLogDb::new(root.path(), Init::New));
+
+        let mut rng = rand::thread_rng();
+        let client_id = ClientFullId::new_ed25519(&mut rng).public_id().clone();
+        let other_client_id = ClientFullId::new_ed25519(&mut rng).public_id().clone();
+        let key = *ClientFullId::new_ed25519(&mut rng).public_id().public_key();
+
+        audit_log.record(&client_id, AuditEvent::AuthKeyDeleted { key });
+        assert!(audit_log.entries(&other_client_id).is_empty());
+
+        // Events of failed requests aren't recorded.
+        let failed_id = MessageId::new();
+        let event = AuditEvent::AppMutation {
+            app: key,
+            request_kind: "PutIData".to_string(),
+            destination: None,
+            message_id: failed_id,
+        };
+        audit_log.record_on_success(failed_id, &client_id, event);
+        audit_log.settle(&failed_id, false);
+        assert_eq!(audit_log.entries(&client_id).len(), 1);
+
+        for _ in 0..MAX_ENTRIES_PER_CLIENT {
+            audit_log.record(
+                &client_id,
+                AuditEvent::AppMutation {
+                    app: key,
+                    request_kind: "PutIData".to_string(),
+                    destination: None,
+                    message_id: MessageId::new(),
+                },
+            );
+        }
+        let entries = audit_log.entries(&client_id);
+        assert_eq!(entries.len(), MAX_ENTRIES_PER_CLIENT);
+        match entries[0].event {
+            AuditEvent::AppMutation { .. } => (),
+            ref event => panic!("Unexpected oldest entry: {:?}", event),
+        }
+    }
+}
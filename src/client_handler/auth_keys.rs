@@ -6,21 +6,23 @@
 // KIND, either express or implied. Please review the Licences for the specific language governing
 // permissions and limitations relating to use of the SAFE Network Software.
 
-use crate::{utils, vault::Init, Result, ToDbKey};
+use crate::{from_db_key, utils, vault::Init, Result, ToDbKey};
 use log::{trace, warn};
 use pickledb::PickleDb;
 use safe_nd::{
-    AppPermissions, AppPublicId, ClientPublicId, Error as NdError, PublicKey, Result as NdResult,
+    AppPermissions, AppPublicId, ClientPublicId, Coins, Error as NdError, MessageId, PublicKey,
+    Request, Result as NdResult, XorName,
 };
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap},
     path::Path,
 };
 
 type AuthKeysAsTuple = (BTreeMap<PublicKey, AppPermissions>, u64);
 
 const AUTH_KEYS_DB_NAME: &str = "auth_keys.db";
+const APP_RESTRICTIONS_DB_NAME: &str = "app_restrictions.db";
 
 #[derive(Default, Serialize, Deserialize, Debug)]
 pub(super) struct AuthKeys {
@@ -34,14 +36,69 @@ impl AuthKeys {
     }
 }
 
+/// Optional restrictions on an authorised app, on top of its `AppPermissions`.  Set by the owner
+/// with `VaultRequest::SetAppRestrictions`.
+#[derive(Clone, Default, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct AppRestrictions {
+    /// Time after which the app is no longer authorised, in seconds since the Unix epoch.
+    pub expires_at: Option<u64>,
+    /// Remaining amount of coins the app may transfer out of its owner's balance.  Allowance is
+    /// only consumed once a transfer succeeds.
+    pub spending_allowance: Option<Coins>,
+    /// If set, the only data addresses the app may access.
+    pub allowed_addresses: Option<BTreeSet<XorName>>,
+}
+
+impl AppRestrictions {
+    /// Checks `request` against these restrictions at time `now`, in seconds since the Unix epoch.
+    pub fn check(&self, request: &Request, now: u64) -> NdResult<()> {
+        if let Some(expires_at) = self.expires_at {
+            if now >= expires_at {
+                return Err(NdError::AccessDenied);
+            }
+        }
+
+        if let Some(ref allowed_addresses) = self.allowed_addresses {
+            if let Some(address) = data_address(request) {
+                if !allowed_addresses.contains(&address) {
+                    return Err(NdError::AccessDenied);
+                }
+            }
+        }
+
+        if let (Some(allowance), Some(amount)) = (self.spending_allowance, transfer_amount(request))
+        {
+            if amount.as_nano() > allowance.as_nano() {
+                return Err(NdError::AccessDenied);
+            }
+        }
+
+        Ok(())
+    }
+}
+
 pub(super) struct AuthKeysDb {
     db: PickleDb,
+    restrictions: PickleDb,
+    // Spending allowance held back for apps' transfers which haven't completed yet.
+    reserved: HashMap<MessageId, (AppPublicId, Coins)>,
 }
 
 impl AuthKeysDb {
     pub fn new<R: AsRef<Path>>(root_dir: R, init_mode: Init) -> Result<Self> {
         Ok(Self {
-            db: utils::new_db(root_dir, AUTH_KEYS_DB_NAME, init_mode)?,
+            db: utils::new_db(&root_dir, AUTH_KEYS_DB_NAME, init_mode)?,
+            restrictions: utils::new_db(&root_dir, APP_RESTRICTIONS_DB_NAME, init_mode)?,
+            reserved: HashMap::new(),
+        })
+    }
+
+    /// Opens the existing auth keys without ever writing them, e.g. for `safe_vault admin`.
+    pub fn load_read_only<R: AsRef<Path>>(root_dir: R) -> Result<Self> {
+        Ok(Self {
+            db: utils::load_db_read_only(&root_dir, AUTH_KEYS_DB_NAME)?,
+            restrictions: utils::load_db_read_only(&root_dir, APP_RESTRICTIONS_DB_NAME)?,
+            reserved: HashMap::new(),
         })
     }
 
@@ -51,6 +108,109 @@ impl AuthKeysDb {
             .and_then(|auth_keys: AuthKeys| auth_keys.apps.get(app_public_id.public_key()).cloned())
     }
 
+    /// Returns the restrictions of the app, or the default (unrestricted) ones if none are set.
+    /// The spending allowance excludes the amounts reserved for transfers still in progress.
+    pub fn app_restrictions(&self, app_public_id: &AppPublicId) -> AppRestrictions {
+        let mut restrictions = self.stored_restrictions(app_public_id);
+        if let Some(allowance) = restrictions.spending_allowance {
+            let reserved = self
+                .reserved
+                .values()
+                .filter(|(app_id, _)| app_id == app_public_id)
+                .fold(0, |total: u64, (_, amount)| {
+                    total.saturating_add(amount.as_nano())
+                });
+            restrictions.spending_allowance = Some(
+                Coins::from_nano(allowance.as_nano().saturating_sub(reserved)).unwrap_or(allowance),
+            );
+        }
+        restrictions
+    }
+
+    /// Sets the restrictions of the authorised app `key` of the specified client.  Returns
+    /// `NoSuchKey` if `key` isn't authorised.
+    pub fn set_app_restrictions(
+        &mut self,
+        client_id: &ClientPublicId,
+        key: PublicKey,
+        restrictions: &AppRestrictions,
+    ) -> NdResult<()> {
+        let is_authorised = self
+            .db
+            .get::<AuthKeys>(&client_id.to_db_key())
+            .map(|auth_keys| auth_keys.apps.contains_key(&key))
+            .unwrap_or(false);
+        if !is_authorised {
+            return Err(NdError::NoSuchKey);
+        }
+
+        let db_key = restrictions_db_key(client_id.name(), &key);
+        if let Err(error) = self.restrictions.set(&db_key, restrictions) {
+            warn!("Failed to write AppRestrictions to DB: {:?}", error);
+            return Err(NdError::from("Failed to set app restrictions."));
+        }
+        Ok(())
+    }
+
+    /// Holds back `amount` of the app's spending allowance for the transfer requested with
+    /// `message_id`, until it's settled.  The request must already have passed
+    /// `AppRestrictions::check`.
+    pub fn reserve_spending_allowance(
+        &mut self,
+        app_public_id: &AppPublicId,
+        message_id: MessageId,
+        amount: Coins,
+    ) {
+        if self
+            .stored_restrictions(app_public_id)
+            .spending_allowance
+            .is_some()
+        {
+            let _ = self
+                .reserved
+                .insert(message_id, (app_public_id.clone(), amount));
+        }
+    }
+
+    /// Releases the allowance reserved for the transfer requested with `message_id`, deducting it
+    /// from the app's spending allowance if the transfer `succeeded`.
+    pub fn settle_spending_allowance(&mut self, message_id: &MessageId, succeeded: bool) {
+        let (app_public_id, amount) = match self.reserved.remove(message_id) {
+            Some(reservation) => reservation,
+            None => return,
+        };
+        if !succeeded {
+            return;
+        }
+
+        let mut restrictions = self.stored_restrictions(&app_public_id);
+        let allowance = match restrictions.spending_allowance {
+            Some(allowance) => allowance,
+            None => return,
+        };
+        restrictions.spending_allowance =
+            Some(allowance.checked_sub(amount).unwrap_or_else(|| {
+                warn!(
+                    "Transfer by {:?} exceeded its remaining spending allowance",
+                    app_public_id
+                );
+                Coins::from_nano(0).unwrap_or(allowance)
+            }));
+
+        let db_key = restrictions_db_key(app_public_id.owner().name(), app_public_id.public_key());
+        if let Err(error) = self.restrictions.set(&db_key, &restrictions) {
+            warn!("Failed to write AppRestrictions to DB: {:?}", error);
+        }
+    }
+
+    /// Returns the auth keys of the client called `name`, if it has any.
+    pub fn find_by_name(&self, name: &XorName) -> Option<AuthKeysAsTuple> {
+        let db_key = self.db.get_all().into_iter().find(|key| {
+            from_db_key::<ClientPublicId>(key).map_or(false, |client_id| client_id.name() == name)
+        })?;
+        self.db.get::<AuthKeys>(&db_key).map(AuthKeys::into_tuple)
+    }
+
     /// If the specified auth_key doesn't exist, a default `AuthKeysAsTuple` is returned.
     pub fn list_auth_keys_and_version(&self, client_id: &ClientPublicId) -> AuthKeysAsTuple {
         let db_key = client_id.to_db_key();
@@ -101,10 +261,25 @@ impl AuthKeysDb {
             warn!("Failed to write AuthKey to DB: {:?}", error);
             return Err(NdError::from("Failed to insert authorised key."));
         }
+        if let Err(error) = self
+            .restrictions
+            .rem(&restrictions_db_key(client_id.name(), &key))
+        {
+            warn!("Failed to delete AppRestrictions from DB: {:?}", error);
+        }
 
         Ok(())
     }
 
+    fn stored_restrictions(&self, app_public_id: &AppPublicId) -> AppRestrictions {
+        self.restrictions
+            .get(&restrictions_db_key(
+                app_public_id.owner().name(),
+                app_public_id.public_key(),
+            ))
+            .unwrap_or_default()
+    }
+
     fn get_auth_keys_and_increment_version(
         &self,
         db_key: &str,
@@ -124,3 +299,101 @@ impl AuthKeysDb {
         Ok(auth_keys)
     }
 }
+
+fn restrictions_db_key(owner: &XorName, key: &PublicKey) -> String {
+    base64::encode(&utils::serialise(&(owner, key)))
+}
+
+// Returns the address of the data targeted by `request`, or `None` for non-data requests.
+fn data_address(request: &Request) -> Option<XorName> {
+    use Request::*;
+    match request {
+        TransferCoins { .. }
+        | GetBalance
+        | CreateBalance { .. }
+        | CreateLoginPacket(_)
+        | CreateLoginPacketFor { .. }
+        | UpdateLoginPacket(_)
+        | GetLoginPacket(_)
+        | ListAuthKeysAndVersion
+        | InsAuthKey { .. }
+        | DelAuthKey { .. } => None,
+        _ => utils::destination_address(request).map(|address| *address),
+    }
+}
+
+/// Returns the amount of coins moved out of the requester's balance by `request`.
+pub(super) fn transfer_amount(request: &Request) -> Option<Coins> {
+    use Request::*;
+    match request {
+        TransferCoins { amount, .. }
+        | CreateBalance { amount, .. }
+        | CreateLoginPacketFor { amount, .. } => Some(*amount),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use safe_nd::IDataAddress;
+    use unwrap::unwrap;
+
+    fn coins(nano: u64) -> Coins {
+        unwrap!(Coins::from_nano(nano))
+    }
+
+    #[test]
+    fn expired_app_is_denied() {
+        let restrictions = AppRestrictions {
+            expires_at: Some(100),
+            ..Default::default()
+        };
+        let request = Request::GetIData(IDataAddress::Unpub(rand::random()));
+        assert_eq!(restrictions.check(&request, 99), Ok(()));
+        assert_eq!(
+            restrictions.check(&request, 100),
+            Err(NdError::AccessDenied)
+        );
+    }
+
+    #[test]
+    fn app_is_restricted_to_allowed_addresses() {
+        let allowed: XorName = rand::random();
+        let restrictions = AppRestrictions {
+            allowed_addresses: Some(vec![allowed].into_iter().collect()),
+            ..Default::default()
+        };
+        let request = Request::GetIData(IDataAddress::Unpub(allowed));
+        assert_eq!(restrictions.check(&request, 0), Ok(()));
+
+        let request = Request::GetIData(IDataAddress::Unpub(rand::random()));
+        assert_eq!(restrictions.check(&request, 0), Err(NdError::AccessDenied));
+
+        // Coin transfers aren't data requests, so aren't affected.
+        let request = Request::TransferCoins {
+            destination: rand::random(),
+            amount: coins(1),
+            transaction_id: 0,
+        };
+        assert_eq!(restrictions.check(&request, 0), Ok(()));
+    }
+
+    #[test]
+    fn app_transfers_are_limited_by_allowance() {
+        let restrictions = AppRestrictions {
+            spending_allowance: Some(coins(5)),
+            ..Default::default()
+        };
+        let transfer = |amount| Request::TransferCoins {
+            destination: rand::random(),
+            amount: coins(amount),
+            transaction_id: 0,
+        };
+        assert_eq!(restrictions.check(&transfer(5), 0), Ok(()));
+        assert_eq!(
+            restrictions.check(&transfer(6), 0),
+            Err(NdError::AccessDenied)
+        );
+    }
+}
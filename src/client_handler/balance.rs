@@ -30,7 +30,22 @@ pub(super) struct BalancesDb {
 
 impl BalancesDb {
     pub fn new<R: AsRef<Path>>(root_dir: R, init_mode: Init) -> Result<Self> {
-        let db = utils::new_db(root_dir, BALANCES_DB_NAME, init_mode)?;
+        Ok(Self::with_db(utils::new_db(
+            root_dir,
+            BALANCES_DB_NAME,
+            init_mode,
+        )?))
+    }
+
+    /// Opens the existing balances without ever writing them, e.g. for `safe_vault admin`.
+    pub fn load_read_only<R: AsRef<Path>>(root_dir: R) -> Result<Self> {
+        Ok(Self::with_db(utils::load_db_read_only(
+            root_dir,
+            BALANCES_DB_NAME,
+        )?))
+    }
+
+    fn with_db(db: PickleDb) -> Self {
         let index = db
             .get_all()
             .into_iter()
@@ -42,7 +57,7 @@ impl BalancesDb {
             .map(|public_key| (public_key.into(), public_key))
             .collect();
 
-        Ok(Self { db, index })
+        Self { db, index }
     }
 
     pub fn exists<K: Key>(&self, key: &K) -> bool {
@@ -0,0 +1,301 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{
+    chunk_store::{
+        error::Error as ChunkStoreError, LoginPacketChunkStore, LoginPacketHistoryChunkStore,
+        LoginPacketVersion, LoginPacketVersionId,
+    },
+    from_db_key, utils,
+    vault::Init,
+    Result, ToDbKey,
+};
+use log::warn;
+use pickledb::PickleDb;
+use safe_nd::{Error as NdError, LoginPacket, Result as NdResult, XorName};
+use std::{cell::Cell, path::Path, rc::Rc};
+
+const LOGIN_PACKET_VERSIONS_DB_NAME: &str = "login_packet_versions.db";
+/// Number of superseded versions kept per login packet.
+const MAX_HISTORY_VERSIONS: u64 = 10;
+
+/// Store of login packets which keeps the last `MAX_HISTORY_VERSIONS` superseded versions of each
+/// packet, so that the owner can roll back a bad update.
+///
+/// A newly created packet has version 0, and each update or rollback increments the version.
+///
+/// The versions DB is the commit point of an update: the new packet is first staged in the history
+/// under its new version, then the version is written, and only then is the packet moved into the
+/// current store.  A history entry with the current version therefore means an update was
+/// interrupted, and holds the current packet either way: the staged one if the update committed,
+/// else the copy of the packet it would have superseded.  Such an update is finished when the store
+/// is loaded, and until then `get` returns that entry.  An update interrupted before it committed
+/// also leaves a staged entry beyond the current version, which the next update overwrites.
+pub(super) struct LoginPacketStore {
+    current: LoginPacketChunkStore,
+    history: LoginPacketHistoryChunkStore,
+    // Version of the current packet, per packet name.  Absent means version 0.
+    versions: PickleDb,
+}
+
+impl LoginPacketStore {
+    pub fn new<P: AsRef<Path>>(
+        root_dir: P,
+        max_capacity: u64,
+        total_used_space: &Rc<Cell<u64>>,
+        init_mode: Init,
+    ) -> Result<Self> {
+        let current = LoginPacketChunkStore::new(
+            &root_dir,
+            max_capacity,
+            Rc::clone(total_used_space),
+            init_mode,
+        )?;
+        let history = LoginPacketHistoryChunkStore::new(
+            &root_dir,
+            max_capacity,
+            Rc::clone(total_used_space),
+            init_mode,
+        )?;
+        let versions = utils::new_db(&root_dir, LOGIN_PACKET_VERSIONS_DB_NAME, init_mode)?;
+        let mut store = Self {
+            current,
+            history,
+            versions,
+        };
+        for name in store
+            .versions
+            .get_all()
+            .iter()
+            .filter_map(|db_key| from_db_key::<XorName>(db_key))
+        {
+            let version = store.version(&name);
+            if store.history.has(&version_id(name, version)) {
+                if let Err(error) = store.finish_update(name, version) {
+                    warn!(
+                        "Failed to finish interrupted login packet update: {}",
+                        error
+                    );
+                }
+            }
+        }
+        Ok(store)
+    }
+
+    pub fn set_max_capacity(&mut self, max_capacity: u64) {
+        self.current.set_max_capacity(max_capacity);
+        self.history.set_max_capacity(max_capacity);
+    }
+
+    pub fn has(&self, name: &XorName) -> bool {
+        self.current.has(name)
+    }
+
+    pub fn get(&self, name: &XorName) -> NdResult<LoginPacket> {
+        let staged_id = version_id(*name, self.version(name));
+        if self.history.has(&staged_id) {
+            return self
+                .history
+                .get(&staged_id)
+                .map(LoginPacketVersion::into_packet)
+                .map_err(to_nd_error);
+        }
+        self.current.get(name).map_err(to_nd_error)
+    }
+
+    /// Stores a new login packet.  Returns `LoginPacketExists` if there's one already.
+    pub fn create(&mut self, packet: &LoginPacket) -> NdResult<()> {
+        if self.has(packet.destination()) {
+            return Err(NdError::LoginPacketExists);
+        }
+        self.current.put(packet).map_err(to_nd_error)
+    }
+
+    /// Replaces the current version of the packet, moving it into the history.
+    ///
+    /// If `expected_version` is given and doesn't match the current version, the update is
+    /// rejected with `InvalidSuccessor` holding the current version.  Returns the new version.
+    pub fn update(&mut self, packet: &LoginPacket, expected_version: Option<u64>) -> NdResult<u64> {
+        let name = *packet.destination();
+        let existing = self.get(&name)?;
+        let version = self.version(&name);
+        if expected_version.map_or(false, |expected| expected != version) {
+            return Err(NdError::InvalidSuccessor(version));
+        }
+
+        let new_version = version + 1;
+        self.history
+            .put(&LoginPacketVersion::new(existing, version))
+            .map_err(to_nd_error)?;
+        self.history
+            .put(&LoginPacketVersion::new(packet.clone(), new_version))
+            .map_err(to_nd_error)?;
+        if let Err(error) = self.versions.set(&name.to_db_key(), &new_version) {
+            warn!("Failed to write login packet version to DB: {:?}", error);
+            return Err(NdError::from("Failed to update login packet version."));
+        }
+
+        // The update is committed now, so it's finished on the next load if this fails.
+        if let Err(error) = self.finish_update(name, new_version) {
+            warn!("Failed to finish login packet update: {}", error);
+        }
+        if new_version > MAX_HISTORY_VERSIONS {
+            let id = version_id(name, new_version - MAX_HISTORY_VERSIONS - 1);
+            if let Err(error) = self.history.delete(&id) {
+                warn!("Failed to delete old login packet version: {}", error);
+            }
+        }
+        Ok(new_version)
+    }
+
+    /// Returns the version of the current packet along with the superseded versions still held,
+    /// oldest first.
+    pub fn versions(&self, name: &XorName) -> NdResult<(u64, Vec<u64>)> {
+        if !self.has(name) {
+            return Err(NdError::NoSuchLoginPacket);
+        }
+        let version = self.version(name);
+        let history = (version.saturating_sub(MAX_HISTORY_VERSIONS)..version)
+            .filter(|old_version| self.history.has(&version_id(*name, *old_version)))
+            .collect();
+        Ok((version, history))
+    }
+
+    /// Makes superseded `version` the current packet again.  The rolled-back packet is stored as
+    /// a new version, so the rollback can itself be undone.  Returns the new version.
+    pub fn rollback(&mut self, name: &XorName, version: u64) -> NdResult<u64> {
+        let old_packet = self
+            .history
+            .get(&version_id(*name, version))
+            .map_err(to_nd_error)?
+            .into_packet();
+        let current_version = self.version(name);
+        self.update(&old_packet, Some(current_version))
+    }
+
+    fn version(&self, name: &XorName) -> u64 {
+        self.versions.get(&name.to_db_key()).unwrap_or(0)
+    }
+
+    // Moves the packet staged as `version` into the current store, once that version has been
+    // committed.
+    fn finish_update(&mut self, name: XorName, version: u64) -> Result<(), ChunkStoreError> {
+        let id = version_id(name, version);
+        let staged = self.history.get(&id)?.into_packet();
+        self.current.put(&staged)?;
+        self.history.delete(&id)
+    }
+}
+
+fn version_id(name: XorName, version: u64) -> LoginPacketVersionId {
+    LoginPacketVersionId { name, version }
+}
+
+fn to_nd_error(error: ChunkStoreError) -> NdError {
+    match error {
+        ChunkStoreError::NoSuchChunk => NdError::NoSuchLoginPacket,
+        error => error.to_string().into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use safe_nd::ClientFullId;
+    use tempdir::TempDir;
+    use unwrap::unwrap;
+
+    fn new_packet(full_id: &ClientFullId, name: XorName, data: Vec<u8>) -> LoginPacket {
+        let signature = full_id.sign(&data);
+        unwrap!(LoginPacket::new(
+            name,
+            *full_id.public_id().public_key(),
+            data,
+            signature
+        ))
+    }
+
+    #[test]
+    fn update_keeps_history_and_rollback_restores_it() {
+        let root = unwrap!(TempDir::new("login_packets"));
+        let used_space = Rc::new(Cell::new(0));
+        let mut store = unwrap!(LoginPacketStore::new(
+            root.path(),
+            u64::max_value(),
+            &used_space,
+            Init::New
+        ));
+
+        let full_id = ClientFullId::new_ed25519(&mut rand::thread_rng());
+        let name: XorName = rand::random();
+        unwrap!(store.create(&new_packet(&full_id, name, vec![0])));
+        assert_eq!(
+            store.create(&new_packet(&full_id, name, vec![0])),
+            Err(NdError::LoginPacketExists)
+        );
+
+        // Compare-and-swap on the version.
+        assert_eq!(
+            store.update(&new_packet(&full_id, name, vec![1]), Some(0)),
+            Ok(1)
+        );
+        assert_eq!(
+            store.update(&new_packet(&full_id, name, vec![2]), Some(0)),
+            Err(NdError::InvalidSuccessor(1))
+        );
+
+        for data in 2..=MAX_HISTORY_VERSIONS + 1 {
+            let _ = unwrap!(store.update(&new_packet(&full_id, name, vec![data as u8]), None));
+        }
+        let (version, history) = unwrap!(store.versions(&name));
+        assert_eq!(version, MAX_HISTORY_VERSIONS + 1);
+        assert_eq!(history, (1..=MAX_HISTORY_VERSIONS).collect::<Vec<_>>());
+
+        assert_eq!(store.rollback(&name, 1), Ok(MAX_HISTORY_VERSIONS + 2));
+        assert_eq!(
+            unwrap!(store.get(&name)).into_data_and_signature().0,
+            vec![1]
+        );
+        assert_eq!(store.rollback(&name, 0), Err(NdError::NoSuchLoginPacket));
+    }
+
+    #[test]
+    fn committed_update_is_finished_on_load() {
+        let root = unwrap!(TempDir::new("login_packets"));
+        let used_space = Rc::new(Cell::new(0));
+        let mut store = unwrap!(LoginPacketStore::new(
+            root.path(),
+            u64::max_value(),
+            &used_space,
+            Init::New
+        ));
+
+        let full_id = ClientFullId::new_ed25519(&mut rand::thread_rng());
+        let name: XorName = rand::random();
+        unwrap!(store.create(&new_packet(&full_id, name, vec![0])));
+
+        // Stage and commit an update without moving it into the current store, as if the vault
+        // stopped right after the commit.
+        let packet = new_packet(&full_id, name, vec![1]);
+        unwrap!(store
+            .history
+            .put(&LoginPacketVersion::new(packet.clone(), 1)));
+        unwrap!(store.versions.set(&name.to_db_key(), &1u64));
+        assert_eq!(store.get(&name), Ok(packet.clone()));
+
+        let store = unwrap!(LoginPacketStore::new(
+            root.path(),
+            u64::max_value(),
+            &used_space,
+            Init::Load
+        ));
+        assert_eq!(unwrap!(store.current.get(&name)), packet);
+        assert!(!store.history.has(&version_id(name, 1)));
+        assert_eq!(store.versions(&name), Ok((1, vec![])));
+    }
+}
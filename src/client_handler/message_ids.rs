@@ -0,0 +1,256 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{from_db_key, utils, vault::Init, Result, ToDbKey};
+use log::warn;
+use pickledb::{PickleDb, PickleDbDumpPolicy};
+use safe_nd::{MessageId, PublicKey};
+use std::{
+    cmp,
+    collections::{HashMap, VecDeque},
+    path::Path,
+};
+
+const SEEN_MESSAGE_IDS_DB_NAME: &str = "seen_message_ids.db";
+/// Time in seconds for which the ID of a mutation is remembered.
+pub(super) const MUTATION_REPLAY_WINDOW_SECS: u64 = 24 * 60 * 60;
+/// Time in seconds for which the ID of a read is remembered.
+pub(super) const READ_REPLAY_WINDOW_SECS: u64 = 10 * 60;
+/// Time in seconds by which the timestamp of a request may be ahead of the vault's clock.
+pub(super) const MAX_CLOCK_SKEW_SECS: u64 = 5 * 60;
+
+fn replay_window_secs(is_mutation: bool) -> u64 {
+    if is_mutation {
+        MUTATION_REPLAY_WINDOW_SECS
+    } else {
+        READ_REPLAY_WINDOW_SECS
+    }
+}
+
+/// Returns whether a request made at `timestamp` is recent enough for its ID to still be
+/// remembered, and not too far in the future.  Requests outside the window must be rejected, as a
+/// replay of them wouldn't be detected.
+pub(super) fn is_within_replay_window(timestamp: u64, is_mutation: bool, now: u64) -> bool {
+    timestamp <= now + MAX_CLOCK_SKEW_SECS && timestamp + replay_window_secs(is_mutation) > now
+}
+
+#[derive(Default)]
+struct Window {
+    // Every remembered ID, with the time it's forgotten, in seconds since the Unix epoch.
+    expiries: HashMap<MessageId, u64>,
+    // IDs of reads and of mutations, each roughly in the order they're forgotten.
+    reads: VecDeque<MessageId>,
+    mutations: VecDeque<MessageId>,
+    // Set when the mutations changed since they were last written to the DB.
+    dirty: bool,
+}
+
+impl Window {
+    fn expire(&mut self, now: u64) {
+        let _ = expire_front(&mut self.reads, &mut self.expiries, now);
+        if expire_front(&mut self.mutations, &mut self.expiries, now) {
+            self.dirty = true;
+        }
+    }
+}
+
+// Forgets the expired IDs at the front of `ids`.  Returns whether any were forgotten.
+fn expire_front(
+    ids: &mut VecDeque<MessageId>,
+    expiries: &mut HashMap<MessageId, u64>,
+    now: u64,
+) -> bool {
+    let mut expired = false;
+    while let Some(message_id) = ids.front() {
+        if expiries
+            .get(message_id)
+            .map_or(false, |expiry| *expiry > now)
+        {
+            break;
+        }
+        let _ = expiries.remove(message_id);
+        let _ = ids.pop_front();
+        expired = true;
+    }
+    expired
+}
+
+/// The `MessageId`s seen per signing key, used to reject replayed requests.
+///
+/// Each ID is remembered until the replay window of its request has passed, counted from when it
+/// was seen or from its timestamp, whichever is later.  IDs of mutations are written to the DB in
+/// batches by `flush`, so that mutations can't be replayed across a restart either, except for
+/// those seen since the last flush.
+///
+/// Requests with a timestamp are rejected by the caller once they're older than the window (see
+/// `is_within_replay_window`).  safe_nd requests carry no timestamp, so a replay of one can't be
+/// detected once its ID has been forgotten.
+pub(super) struct MessageIdsDb {
+    db: PickleDb,
+    windows: HashMap<PublicKey, Window>,
+}
+
+impl MessageIdsDb {
+    pub fn new<R: AsRef<Path>>(root_dir: R, init_mode: Init) -> Result<Self> {
+        let db = utils::new_db_with_dump_policy(
+            root_dir,
+            SEEN_MESSAGE_IDS_DB_NAME,
+            init_mode,
+            PickleDbDumpPolicy::DumpUponRequest,
+        )?;
+        let mut windows = HashMap::new();
+        for db_key in db.get_all() {
+            let key = match from_db_key::<PublicKey>(&db_key) {
+                Some(key) => key,
+                None => continue,
+            };
+            let mutations: Vec<(MessageId, u64)> = db.get(&db_key).unwrap_or_default();
+            let window: &mut Window = windows.entry(key).or_default();
+            for (message_id, expiry) in mutations {
+                let _ = window.expiries.insert(message_id, expiry);
+                window.mutations.push_back(message_id);
+            }
+        }
+        Ok(Self { db, windows })
+    }
+
+    /// Records `message_id` as seen for `key` at `now`, in seconds since the Unix epoch.  Returns
+    /// `false` if it had already been seen.
+    ///
+    /// `timestamp` is the time the request claims to have been made, if it carries one.
+    pub fn insert(
+        &mut self,
+        key: &PublicKey,
+        message_id: MessageId,
+        is_mutation: bool,
+        timestamp: Option<u64>,
+        now: u64,
+    ) -> bool {
+        let window = self.windows.entry(*key).or_default();
+        if window.expiries.contains_key(&message_id) {
+            return false;
+        }
+
+        let expiry = cmp::max(now, timestamp.unwrap_or(now)) + replay_window_secs(is_mutation);
+        let _ = window.expiries.insert(message_id, expiry);
+        if is_mutation {
+            window.mutations.push_back(message_id);
+            window.dirty = true;
+        } else {
+            window.reads.push_back(message_id);
+        }
+        true
+    }
+
+    /// Forgets the IDs whose replay window has passed at `now`, and writes the changes to the DB.
+    pub fn prune(&mut self, now: u64) {
+        for window in self.windows.values_mut() {
+            window.expire(now);
+        }
+        self.flush();
+        self.windows.retain(|_, window| !window.expiries.is_empty());
+    }
+
+    /// Writes the IDs of the mutations seen since the last flush to the DB.
+    pub fn flush(&mut self) {
+        let mut changed = false;
+        for (key, window) in self.windows.iter_mut().filter(|(_, window)| window.dirty) {
+            window.dirty = false;
+            changed = true;
+            let result = if window.mutations.is_empty() {
+                self.db.rem(&key.to_db_key()).map(|_| ())
+            } else {
+                let expiries = &window.expiries;
+                let mutations: Vec<(MessageId, u64)> = window
+                    .mutations
+                    .iter()
+                    .filter_map(|message_id| Some((*message_id, *expiries.get(message_id)?)))
+                    .collect();
+                self.db.set(&key.to_db_key(), &mutations)
+            };
+            if let Err(error) = result {
+                warn!("Failed to write seen message IDs to DB: {:?}", error);
+            }
+        }
+        if changed {
+            if let Err(error) = self.db.dump() {
+                warn!("Failed to write seen message IDs to disk: {:?}", error);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use safe_nd::ClientFullId;
+    use tempdir::TempDir;
+    use unwrap::unwrap;
+
+    #[test]
+    fn ids_are_remembered_for_their_replay_window() {
+        let root_dir = unwrap!(TempDir::new("message_ids"));
+        let mut db = unwrap!(MessageIdsDb::new(root_dir.path(), Init::New));
+        let key = *ClientFullId::new_ed25519(&mut rand::thread_rng())
+            .public_id()
+            .public_key();
+        let now = 1_000_000;
+
+        let mutation_id = MessageId::new();
+        let read_id = MessageId::new();
+        assert!(db.insert(&key, mutation_id, true, None, now));
+        assert!(db.insert(&key, read_id, false, None, now));
+        assert!(!db.insert(&key, read_id, false, None, now));
+
+        // Only the flushed mutation is still known after a restart.
+        db.flush();
+        let mut reloaded = unwrap!(MessageIdsDb::new(root_dir.path(), Init::Load));
+        assert!(!reloaded.insert(&key, mutation_id, true, None, now));
+        assert!(reloaded.insert(&key, read_id, false, None, now));
+
+        // The read is forgotten first, then the mutation, and the key's DB entry with it.
+        let later = now + READ_REPLAY_WINDOW_SECS;
+        db.prune(later);
+        assert!(!db.insert(&key, mutation_id, true, None, later));
+        assert!(db.insert(&key, read_id, false, None, later));
+
+        db.prune(later + MUTATION_REPLAY_WINDOW_SECS);
+        assert!(db.windows.is_empty());
+        assert!(db.db.get_all().is_empty());
+    }
+
+    #[test]
+    fn requests_outside_replay_window_are_stale() {
+        let now = 1_000_000;
+        assert!(is_within_replay_window(
+            now - READ_REPLAY_WINDOW_SECS + 1,
+            false,
+            now
+        ));
+        assert!(!is_within_replay_window(
+            now - READ_REPLAY_WINDOW_SECS,
+            false,
+            now
+        ));
+        assert!(is_within_replay_window(
+            now - READ_REPLAY_WINDOW_SECS,
+            true,
+            now
+        ));
+        assert!(is_within_replay_window(
+            now + MAX_CLOCK_SKEW_SECS,
+            true,
+            now
+        ));
+        assert!(!is_within_replay_window(
+            now + MAX_CLOCK_SKEW_SECS + 1,
+            true,
+            now
+        ));
+    }
+}
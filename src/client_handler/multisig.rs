@@ -0,0 +1,413 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use super::message_ids::MUTATION_REPLAY_WINDOW_SECS;
+use crate::{utils, vault::Init, Result, ToDbKey};
+use log::{trace, warn};
+use pickledb::PickleDb;
+use safe_nd::{
+    Coins, Error as NdError, MessageId, PublicKey, Result as NdResult, TransactionId, XorName,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeSet, path::Path, time::Duration};
+
+const MULTISIG_POLICIES_DB_NAME: &str = "multisig_policies.db";
+const MULTISIG_TRANSFERS_DB_NAME: &str = "multisig_transfers.db";
+/// How long a proposed transfer waits for enough approvals before it's dropped.
+const PENDING_TRANSFER_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+/// How long the transaction ID of a completed transfer is remembered.  An approval can't be
+/// replayed after this, as the request carrying it is then rejected as stale.
+const COMPLETED_TRANSFER_RETENTION: Duration = Duration::from_secs(MUTATION_REPLAY_WINDOW_SECS);
+
+// Prefixes of the keys in the transfers DB, one per kind of record.
+const PENDING_PREFIX: &str = "pending/";
+const COMPLETED_PREFIX: &str = "completed/";
+const IN_FLIGHT_PREFIX: &str = "in_flight/";
+
+/// M-of-N owners required to approve transfers out of a balance.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct MultisigPolicy {
+    owners: BTreeSet<PublicKey>,
+    threshold: usize,
+}
+
+impl MultisigPolicy {
+    /// Returns `InvalidOperation` unless `1 <= threshold <= owners.len()`.
+    pub fn new(owners: BTreeSet<PublicKey>, threshold: usize) -> NdResult<Self> {
+        let policy = Self { owners, threshold };
+        if !policy.is_valid() {
+            return Err(NdError::InvalidOperation);
+        }
+        Ok(policy)
+    }
+
+    // Policies also arrive deserialised from clients, bypassing `new`.
+    fn is_valid(&self) -> bool {
+        self.threshold > 0 && self.threshold <= self.owners.len()
+    }
+}
+
+/// A transfer out of a multisig balance, as approved by each owner.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct TransferProposal {
+    /// Name of the balance to transfer from.
+    pub balance: XorName,
+    /// Name of the balance to transfer to.
+    pub destination: XorName,
+    /// Amount to transfer.
+    pub amount: Coins,
+    /// ID of the transfer, which can only be used once per balance.
+    pub transaction_id: TransactionId,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PendingTransfer {
+    proposal: TransferProposal,
+    approvals: BTreeSet<PublicKey>,
+    // Seconds since the Unix epoch, as pending transfers survive restarts.
+    created_at: u64,
+}
+
+/// Multisig policies of balances, along with the approvals collected so far for their pending
+/// transfers.
+///
+/// Everything is persisted: policies, pending approvals, the transaction IDs of completed transfers
+/// (so that they can't be approved and executed again while their approvals could still be
+/// replayed) and the transfers still awaiting the destination's response (so that a refund goes
+/// back to the multisig balance even after a restart).
+///
+/// Pending and completed transfers are indexed by the time they expire, so that pruning them
+/// doesn't have to read the whole DB.
+pub(super) struct MultisigDb {
+    policies: PickleDb,
+    transfers: PickleDb,
+    expiries: BTreeSet<(u64, String)>,
+}
+
+impl MultisigDb {
+    pub fn new<R: AsRef<Path>>(root_dir: R, init_mode: Init) -> Result<Self> {
+        let transfers = utils::new_db(&root_dir, MULTISIG_TRANSFERS_DB_NAME, init_mode)?;
+        // Records which can't be read are expired straight away.
+        let expiries = transfers
+            .get_all()
+            .into_iter()
+            .filter_map(|key| {
+                let expiry = if key.starts_with(PENDING_PREFIX) {
+                    transfers
+                        .get::<PendingTransfer>(&key)
+                        .map_or(0, |pending| pending_expiry(pending.created_at))
+                } else if key.starts_with(COMPLETED_PREFIX) {
+                    transfers.get::<u64>(&key).map_or(0, completed_expiry)
+                } else {
+                    return None;
+                };
+                Some((expiry, key))
+            })
+            .collect();
+        Ok(Self {
+            policies: utils::new_db(&root_dir, MULTISIG_POLICIES_DB_NAME, init_mode)?,
+            transfers,
+            expiries,
+        })
+    }
+
+    /// Returns true if transfers out of `balance` require multiple approvals.
+    pub fn is_multisig(&self, balance: &XorName) -> bool {
+        self.policies.exists(&balance.to_db_key())
+    }
+
+    /// Makes transfers out of `balance` subject to `policy`.  A policy can't be replaced once set,
+    /// as that would let a single owner bypass the others.
+    pub fn set_policy(&mut self, balance: &XorName, policy: &MultisigPolicy) -> NdResult<()> {
+        if !policy.is_valid() || self.is_multisig(balance) {
+            return Err(NdError::InvalidOperation);
+        }
+        if let Err(error) = self.policies.set(&balance.to_db_key(), policy) {
+            warn!("Failed to write MultisigPolicy to DB: {:?}", error);
+            return Err(NdError::from("Failed to set multisig policy."));
+        }
+        Ok(())
+    }
+
+    /// Adds the approval of `owner` to `proposal`.
+    ///
+    /// Returns `true` once the threshold is reached, in which case the transfer is no longer
+    /// pending and should be executed.  A proposal conflicting with a pending one which has the
+    /// same balance and transaction ID is rejected, as is one whose transaction ID was already
+    /// used by a completed transfer.
+    pub fn approve(
+        &mut self,
+        proposal: TransferProposal,
+        owner: PublicKey,
+        now_secs: u64,
+    ) -> NdResult<bool> {
+        self.prune(now_secs);
+
+        let policy: MultisigPolicy = self
+            .policies
+            .get(&proposal.balance.to_db_key())
+            .ok_or(NdError::NoSuchBalance)?;
+        if !policy.owners.contains(&owner) {
+            return Err(NdError::AccessDenied);
+        }
+
+        let completed_key = transfer_db_key(COMPLETED_PREFIX, &proposal);
+        if self.transfers.exists(&completed_key) {
+            return Err(NdError::InvalidOperation);
+        }
+
+        let pending_key = transfer_db_key(PENDING_PREFIX, &proposal);
+        let mut pending = match self.transfers.get(&pending_key) {
+            Some(pending) => pending,
+            None => {
+                let _ = self
+                    .expiries
+                    .insert((pending_expiry(now_secs), pending_key.clone()));
+                PendingTransfer {
+                    proposal,
+                    approvals: BTreeSet::new(),
+                    created_at: now_secs,
+                }
+            }
+        };
+        if pending.proposal != proposal {
+            return Err(NdError::InvalidOperation);
+        }
+        let _ = pending.approvals.insert(owner);
+        trace!(
+            "Transfer {} from {} has {}/{} approvals",
+            proposal.transaction_id,
+            proposal.balance,
+            pending.approvals.len(),
+            policy.threshold
+        );
+
+        let result = if pending.approvals.len() >= policy.threshold {
+            let _ = self
+                .expiries
+                .remove(&(pending_expiry(pending.created_at), pending_key.clone()));
+            let _ = self
+                .expiries
+                .insert((completed_expiry(now_secs), completed_key.clone()));
+            self.transfers
+                .rem(&pending_key)
+                .and_then(|_| self.transfers.set(&completed_key, &now_secs))
+                .map(|_| true)
+        } else {
+            self.transfers.set(&pending_key, &pending).map(|_| false)
+        };
+        result.map_err(|error| {
+            warn!("Failed to write multisig transfer to DB: {:?}", error);
+            NdError::from("Failed to approve multisig transfer.")
+        })
+    }
+
+    /// Drops the pending transfers which didn't collect enough approvals in time, and forgets the
+    /// completed ones whose approvals can no longer be replayed.
+    pub fn prune(&mut self, now_secs: u64) {
+        while let Some((expiry, key)) = self.expiries.iter().next().cloned() {
+            if expiry > now_secs {
+                break;
+            }
+            let _ = self.expiries.remove(&(expiry, key.clone()));
+            if let Err(error) = self.transfers.rem(&key) {
+                warn!("Failed to remove expired multisig transfer: {:?}", error);
+            }
+        }
+    }
+
+    /// Records that the transfer sent with `message_id` was withdrawn from `balance`.
+    pub fn insert_in_flight(&mut self, message_id: MessageId, balance: XorName) {
+        if let Err(error) = self.transfers.set(&in_flight_db_key(&message_id), &balance) {
+            warn!("Failed to record in-flight multisig transfer: {:?}", error);
+        }
+    }
+
+    /// Returns the balance the transfer sent with `message_id` was withdrawn from, if it was a
+    /// multisig transfer.
+    pub fn remove_in_flight(&mut self, message_id: &MessageId) -> Option<XorName> {
+        let key = in_flight_db_key(message_id);
+        let balance = self.transfers.get(&key)?;
+        if let Err(error) = self.transfers.rem(&key) {
+            warn!("Failed to remove in-flight multisig transfer: {:?}", error);
+        }
+        Some(balance)
+    }
+}
+
+fn pending_expiry(created_at: u64) -> u64 {
+    created_at + PENDING_TRANSFER_TIMEOUT.as_secs()
+}
+
+fn completed_expiry(completed_at: u64) -> u64 {
+    completed_at + COMPLETED_TRANSFER_RETENTION.as_secs()
+}
+
+fn transfer_db_key(prefix: &str, proposal: &TransferProposal) -> String {
+    let id = (proposal.balance, proposal.transaction_id);
+    format!("{}{}", prefix, base64::encode(&utils::serialise(&id)))
+}
+
+fn in_flight_db_key(message_id: &MessageId) -> String {
+    format!(
+        "{}{}",
+        IN_FLIGHT_PREFIX,
+        base64::encode(&utils::serialise(message_id))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use safe_nd::ClientFullId;
+    use tempdir::TempDir;
+    use unwrap::unwrap;
+
+    fn proposal(balance: XorName, amount: u64) -> TransferProposal {
+        TransferProposal {
+            balance,
+            destination: rand::random(),
+            amount: unwrap!(Coins::from_nano(amount)),
+            transaction_id: 1,
+        }
+    }
+
+    #[test]
+    fn transfer_requires_threshold_approvals() {
+        let root = unwrap!(TempDir::new("multisig"));
+        let mut multisig = unwrap!(MultisigDb::new(root.path(), Init::New));
+
+        let mut rng = rand::thread_rng();
+        let owners: Vec<_> = (0..3)
+            .map(|_| ClientFullId::new_ed25519(&mut rng))
+            .collect();
+        let outsider = ClientFullId::new_ed25519(&mut rng);
+        let owner_keys = owners
+            .iter()
+            .map(|owner| *owner.public_id().public_key())
+            .collect();
+        let policy = unwrap!(MultisigPolicy::new(owner_keys, 2));
+
+        let balance: XorName = rand::random();
+        unwrap!(multisig.set_policy(&balance, &policy));
+        assert!(multisig.is_multisig(&balance));
+
+        let now = utils::unix_time_secs();
+        let approve = |multisig: &mut MultisigDb, full_id: &ClientFullId, proposal| {
+            multisig.approve(proposal, *full_id.public_id().public_key(), now)
+        };
+
+        let transfer = proposal(balance, 10);
+        assert_eq!(
+            approve(&mut multisig, &outsider, transfer),
+            Err(NdError::AccessDenied)
+        );
+        assert_eq!(approve(&mut multisig, &owners[0], transfer), Ok(false));
+        // Approving twice doesn't count twice.
+        assert_eq!(approve(&mut multisig, &owners[0], transfer), Ok(false));
+        // A different transfer with the same transaction ID is rejected.
+        assert_eq!(
+            approve(&mut multisig, &owners[1], proposal(balance, 20)),
+            Err(NdError::InvalidOperation)
+        );
+        assert_eq!(approve(&mut multisig, &owners[1], transfer), Ok(true));
+        // The completed transfer can't be approved again.
+        assert_eq!(
+            approve(&mut multisig, &owners[2], transfer),
+            Err(NdError::InvalidOperation)
+        );
+        // Nor can the policy be replaced.
+        assert_eq!(
+            multisig.set_policy(&balance, &policy),
+            Err(NdError::InvalidOperation)
+        );
+    }
+
+    #[test]
+    fn pending_transfers_expire() {
+        let root = unwrap!(TempDir::new("multisig"));
+        let mut multisig = unwrap!(MultisigDb::new(root.path(), Init::New));
+
+        let mut rng = rand::thread_rng();
+        let owners: Vec<_> = (0..2)
+            .map(|_| ClientFullId::new_ed25519(&mut rng))
+            .collect();
+        let owner_keys = owners
+            .iter()
+            .map(|owner| *owner.public_id().public_key())
+            .collect();
+        let balance: XorName = rand::random();
+        unwrap!(multisig.set_policy(&balance, &unwrap!(MultisigPolicy::new(owner_keys, 2))));
+
+        let transfer = proposal(balance, 10);
+        let now = utils::unix_time_secs();
+        assert_eq!(
+            multisig.approve(transfer, *owners[0].public_id().public_key(), now),
+            Ok(false)
+        );
+
+        // The first approval has expired by the time the second one arrives.
+        assert_eq!(
+            multisig.approve(
+                transfer,
+                *owners[1].public_id().public_key(),
+                now + PENDING_TRANSFER_TIMEOUT.as_secs()
+            ),
+            Ok(false)
+        );
+    }
+
+    #[test]
+    fn transfers_survive_restart() {
+        let root = unwrap!(TempDir::new("multisig"));
+        let mut multisig = unwrap!(MultisigDb::new(root.path(), Init::New));
+
+        let mut rng = rand::thread_rng();
+        let owners: Vec<_> = (0..2)
+            .map(|_| ClientFullId::new_ed25519(&mut rng))
+            .collect();
+        let owner_keys = owners
+            .iter()
+            .map(|owner| *owner.public_id().public_key())
+            .collect();
+        let balance: XorName = rand::random();
+        unwrap!(multisig.set_policy(&balance, &unwrap!(MultisigPolicy::new(owner_keys, 2))));
+
+        let transfer = proposal(balance, 10);
+        let now = utils::unix_time_secs();
+        assert_eq!(
+            multisig.approve(transfer, *owners[0].public_id().public_key(), now),
+            Ok(false)
+        );
+
+        // The first approval still counts after a restart.
+        let mut multisig = unwrap!(MultisigDb::new(root.path(), Init::Load));
+        assert_eq!(
+            multisig.approve(transfer, *owners[1].public_id().public_key(), now),
+            Ok(true)
+        );
+        let message_id = MessageId::new();
+        multisig.insert_in_flight(message_id, balance);
+
+        // As do the completed transfer and the one in flight.
+        let mut multisig = unwrap!(MultisigDb::new(root.path(), Init::Load));
+        assert_eq!(
+            multisig.approve(transfer, *owners[0].public_id().public_key(), now),
+            Err(NdError::InvalidOperation)
+        );
+        assert_eq!(multisig.remove_in_flight(&message_id), Some(balance));
+        assert_eq!(multisig.remove_in_flight(&message_id), None);
+
+        // The completed transfer is forgotten once its approvals can no longer be replayed, even
+        // after a restart.
+        let mut multisig = unwrap!(MultisigDb::new(root.path(), Init::Load));
+        multisig.prune(now + COMPLETED_TRANSFER_RETENTION.as_secs());
+        assert!(multisig.expiries.is_empty());
+        assert!(multisig.transfers.get_all().is_empty());
+    }
+}
@@ -0,0 +1,138 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+/// Number of buckets above which idle ones are dropped.
+const PRUNE_THRESHOLD: usize = 1024;
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    // Returns the number of tokens the bucket would hold at `now`, given the refill `rate`.
+    fn tokens_at(&self, now: Instant, rate: f64) -> f64 {
+        let elapsed = now.duration_since(self.last_refill);
+        let elapsed_secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+        (self.tokens + elapsed_secs * rate).min(rate)
+    }
+}
+
+/// Token-bucket rate limiter with a separate bucket per key.
+///
+/// Each bucket refills at `rate` tokens per second up to a capacity of `rate`, i.e. bursts of up
+/// to one second's worth of requests are allowed.  A `rate` of zero disables the limiter.
+pub(super) struct RateLimiter<K> {
+    rate: u32,
+    buckets: HashMap<K, TokenBucket>,
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    pub fn new(rate: u32) -> Self {
+        Self {
+            rate,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Changes the rate, keeping the existing buckets.  Buckets above the new rate are capped the
+    /// next time they're used.
+    pub fn set_rate(&mut self, rate: u32) {
+        self.rate = rate;
+    }
+
+    /// Returns how long until the bucket for `key` holds a token, or `None` if it holds one now.
+    /// Doesn't take the token, so that a request can be checked against several limiters before
+    /// being charged to any of them.
+    pub fn wait_time(&self, key: &K, now: Instant) -> Option<Duration> {
+        if self.rate == 0 {
+            return None;
+        }
+        let rate = f64::from(self.rate);
+        let tokens = self
+            .buckets
+            .get(key)
+            .map_or(rate, |bucket| bucket.tokens_at(now, rate));
+        if tokens >= 1.0 {
+            None
+        } else {
+            let wait_secs = (1.0 - tokens) / rate;
+            Some(Duration::from_nanos((wait_secs * 1e9).ceil() as u64))
+        }
+    }
+
+    /// Takes a token from the bucket for `key`, which should have been checked with `wait_time`.
+    pub fn acquire(&mut self, key: K, now: Instant) {
+        if self.rate == 0 {
+            return;
+        }
+        if self.buckets.len() >= PRUNE_THRESHOLD {
+            self.prune(now);
+        }
+
+        let rate = f64::from(self.rate);
+        let bucket = self.buckets.entry(key).or_insert(TokenBucket {
+            tokens: rate,
+            last_refill: now,
+        });
+        bucket.tokens = (bucket.tokens_at(now, rate) - 1.0).max(0.0);
+        bucket.last_refill = now;
+    }
+
+    /// Drops the buckets which have been idle long enough to be full again, as they are
+    /// indistinguishable from fresh ones.
+    pub fn prune(&mut self, now: Instant) {
+        let rate = f64::from(self.rate);
+        self.buckets
+            .retain(|_, bucket| bucket.tokens_at(now, rate) < rate);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn limits_per_key() {
+        let mut limiter = RateLimiter::new(2);
+        let now = Instant::now();
+
+        for _ in 0..2 {
+            assert_eq!(limiter.wait_time(&0, now), None);
+            limiter.acquire(0, now);
+        }
+        assert_eq!(limiter.wait_time(&0, now), Some(Duration::from_millis(500)));
+
+        // Checking doesn't take a token, and other keys have their own bucket.
+        assert_eq!(limiter.wait_time(&1, now), None);
+        assert_eq!(limiter.wait_time(&1, now), None);
+
+        // Half a second refills one token.
+        let later = now + Duration::from_millis(500);
+        assert_eq!(limiter.wait_time(&0, later), None);
+        limiter.acquire(0, later);
+        assert!(limiter.wait_time(&0, later).is_some());
+    }
+
+    #[test]
+    fn zero_rate_disables_limiter() {
+        let mut limiter = RateLimiter::new(0);
+        let now = Instant::now();
+        assert!((0..1000).all(|_| {
+            limiter.acquire((), now);
+            limiter.wait_time(&(), now).is_none()
+        }));
+    }
+}
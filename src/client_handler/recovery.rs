@@ -0,0 +1,425 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{utils, vault::Init, Result, ToDbKey};
+use log::{trace, warn};
+use pickledb::PickleDb;
+use safe_nd::{Error as NdError, LoginPacket, PublicKey, Result as NdResult, XorName};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::Path,
+};
+
+const RECOVERY_CONFIGS_DB_NAME: &str = "recovery_configs.db";
+const PENDING_RECOVERIES_DB_NAME: &str = "pending_recoveries.db";
+/// How long an approval counts towards a replacement which hasn't reached the threshold, in
+/// seconds.
+const APPROVAL_TIMEOUT_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// Keys allowed to jointly replace a login packet, as designated by its owner.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct RecoveryConfig {
+    keys: BTreeSet<PublicKey>,
+    threshold: usize,
+    // Time between the threshold being reached and the replacement taking effect, during which
+    // the owner can veto it.
+    delay_secs: u64,
+}
+
+impl RecoveryConfig {
+    /// Returns `InvalidOperation` unless `1 <= threshold <= keys.len()`.
+    pub fn new(keys: BTreeSet<PublicKey>, threshold: usize, delay_secs: u64) -> NdResult<Self> {
+        let config = Self {
+            keys,
+            threshold,
+            delay_secs,
+        };
+        if !config.is_valid() {
+            return Err(NdError::InvalidOperation);
+        }
+        Ok(config)
+    }
+
+    // Configs also arrive deserialised from clients, bypassing `new`.
+    fn is_valid(&self) -> bool {
+        self.threshold > 0 && self.threshold <= self.keys.len()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Candidate {
+    // Replacement login packet, usually with a new `authorised_getter`.
+    new_packet: LoginPacket,
+    // Recovery keys approving the replacement, with the time of their approval in seconds since
+    // the Unix epoch.
+    approvals: BTreeMap<PublicKey, u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+enum PendingRecovery {
+    // Replacements approved by fewer keys than the threshold so far.  Each key counts towards one
+    // of them only.
+    Approving(Vec<Candidate>),
+    // Replacement which reached the threshold, and the time it takes effect in seconds since the
+    // Unix epoch.
+    Due {
+        new_packet: LoginPacket,
+        due_at: u64,
+    },
+}
+
+impl PendingRecovery {
+    // Time at which the recovery needs to be looked at again: when it takes effect, or when its
+    // oldest approval expires.
+    fn deadline(&self) -> u64 {
+        match self {
+            PendingRecovery::Approving(candidates) => candidates
+                .iter()
+                .flat_map(|candidate| candidate.approvals.values())
+                .min()
+                .map_or(0, |approved_at| {
+                    approved_at.saturating_add(APPROVAL_TIMEOUT_SECS)
+                }),
+            PendingRecovery::Due { due_at, .. } => *due_at,
+        }
+    }
+}
+
+// Drops the approvals which expired by `now`, and the candidates left without any.
+fn expire_approvals(candidates: &mut Vec<Candidate>, now: u64) {
+    for candidate in candidates.iter_mut() {
+        candidate
+            .approvals
+            .retain(|_, approved_at| approved_at.saturating_add(APPROVAL_TIMEOUT_SECS) > now);
+    }
+    candidates.retain(|candidate| !candidate.approvals.is_empty());
+}
+
+/// Recovery configurations of login packets, along with the recoveries in progress.
+///
+/// Both are persisted, as the delay is expected to be long enough to span restarts.  Each recovery
+/// key approves one replacement at a time, so a key approving a bogus replacement can't block the
+/// others from agreeing on a different one.  Approvals of a replacement which hasn't reached the
+/// threshold expire after `APPROVAL_TIMEOUT_SECS`.
+///
+/// The recoveries in progress are indexed by the time they next need looking at, so that
+/// `take_due` doesn't have to read the whole DB.
+pub(super) struct RecoveryDb {
+    configs: PickleDb,
+    pending: PickleDb,
+    deadlines: HashMap<String, u64>,
+    by_deadline: BTreeSet<(u64, String)>,
+}
+
+impl RecoveryDb {
+    pub fn new<R: AsRef<Path>>(root_dir: R, init_mode: Init) -> Result<Self> {
+        let pending = utils::new_db(&root_dir, PENDING_RECOVERIES_DB_NAME, init_mode)?;
+        // Records which can't be read are dropped by the next `take_due`.
+        let deadlines: HashMap<_, _> = pending
+            .get_all()
+            .into_iter()
+            .map(|db_key| {
+                let deadline = pending
+                    .get::<PendingRecovery>(&db_key)
+                    .map_or(0, |pending| pending.deadline());
+                (db_key, deadline)
+            })
+            .collect();
+        let by_deadline = deadlines
+            .iter()
+            .map(|(db_key, deadline)| (*deadline, db_key.clone()))
+            .collect();
+        Ok(Self {
+            configs: utils::new_db(&root_dir, RECOVERY_CONFIGS_DB_NAME, init_mode)?,
+            pending,
+            deadlines,
+            by_deadline,
+        })
+    }
+
+    /// Sets the recovery configuration of the login packet `name`, cancelling any recovery in
+    /// progress.  The caller is responsible for checking that the requester owns the packet.
+    pub fn set_config(&mut self, name: &XorName, config: &RecoveryConfig) -> NdResult<()> {
+        if !config.is_valid() {
+            return Err(NdError::InvalidOperation);
+        }
+        let db_key = name.to_db_key();
+        if let Err(error) = self.configs.set(&db_key, config) {
+            warn!("Failed to write RecoveryConfig to DB: {:?}", error);
+            return Err(NdError::from("Failed to set recovery configuration."));
+        }
+        if let Err(error) = self.set_pending(&db_key, None) {
+            warn!("Failed to delete PendingRecovery from DB: {:?}", error);
+        }
+        Ok(())
+    }
+
+    /// Adds the approval of recovery key `approver` to replacing the login packet with
+    /// `new_packet`.  The caller is responsible for checking that the approval was signed by
+    /// `approver`.
+    ///
+    /// Returns the time the replacement takes effect once the threshold is reached, or `None` while
+    /// more approvals are needed.  Until then, an approval replaces any earlier one by the same
+    /// key for a different replacement.  Once a replacement has reached the threshold, approvals
+    /// for a different one are rejected; the owner needs to veto it first.
+    pub fn approve(
+        &mut self,
+        new_packet: LoginPacket,
+        approver: PublicKey,
+        now: u64,
+    ) -> NdResult<Option<u64>> {
+        let db_key = new_packet.destination().to_db_key();
+        let config: RecoveryConfig = self.configs.get(&db_key).ok_or(NdError::AccessDenied)?;
+        if !config.keys.contains(&approver) {
+            return Err(NdError::AccessDenied);
+        }
+        let serialised_packet = utils::serialise(&new_packet);
+
+        let mut candidates = match self.pending.get::<PendingRecovery>(&db_key) {
+            Some(PendingRecovery::Due {
+                new_packet: due_packet,
+                due_at,
+            }) => {
+                if utils::serialise(&due_packet) != serialised_packet {
+                    return Err(NdError::InvalidOperation);
+                }
+                return Ok(Some(due_at));
+            }
+            Some(PendingRecovery::Approving(candidates)) => candidates,
+            None => Vec::new(),
+        };
+
+        for candidate in candidates.iter_mut() {
+            let _ = candidate.approvals.remove(&approver);
+        }
+        expire_approvals(&mut candidates, now);
+        let index = match candidates
+            .iter()
+            .position(|candidate| utils::serialise(&candidate.new_packet) == serialised_packet)
+        {
+            Some(index) => index,
+            None => {
+                candidates.push(Candidate {
+                    new_packet,
+                    approvals: BTreeMap::new(),
+                });
+                candidates.len() - 1
+            }
+        };
+        let _ = candidates[index].approvals.insert(approver, now);
+
+        let (pending, due_at) = if candidates[index].approvals.len() >= config.threshold {
+            let due_at = now.saturating_add(config.delay_secs);
+            let new_packet = candidates.swap_remove(index).new_packet;
+            (PendingRecovery::Due { new_packet, due_at }, Some(due_at))
+        } else {
+            (PendingRecovery::Approving(candidates), None)
+        };
+        if let Err(error) = self.set_pending(&db_key, Some(&pending)) {
+            warn!("Failed to write PendingRecovery to DB: {:?}", error);
+            return Err(NdError::from("Failed to record recovery approval."));
+        }
+        Ok(due_at)
+    }
+
+    /// Cancels the recovery in progress for the login packet `name`.  The caller is responsible for
+    /// checking that the requester owns the packet.
+    pub fn veto(&mut self, name: &XorName) -> NdResult<()> {
+        let db_key = name.to_db_key();
+        if !self.pending.exists(&db_key) {
+            return Err(NdError::InvalidOperation);
+        }
+        self.set_pending(&db_key, None).map_err(|error| {
+            warn!("Failed to delete PendingRecovery from DB: {:?}", error);
+            NdError::from("Failed to veto recovery.")
+        })
+    }
+
+    /// Removes and returns the replacement packets whose delay has elapsed by `now`, and drops the
+    /// approvals which expired by then.
+    pub fn take_due(&mut self, now: u64) -> Vec<LoginPacket> {
+        let mut due_packets = Vec::new();
+        while let Some((deadline, db_key)) = self.by_deadline.iter().next().cloned() {
+            if deadline > now {
+                break;
+            }
+            let (pending, due_packet) = match self.pending.get::<PendingRecovery>(&db_key) {
+                Some(PendingRecovery::Due { new_packet, .. }) => (None, Some(new_packet)),
+                Some(PendingRecovery::Approving(mut candidates)) => {
+                    expire_approvals(&mut candidates, now);
+                    if candidates.is_empty() {
+                        (None, None)
+                    } else {
+                        (Some(PendingRecovery::Approving(candidates)), None)
+                    }
+                }
+                None => (None, None),
+            };
+            if let Err(error) = self.set_pending(&db_key, pending.as_ref()) {
+                warn!("Failed to write PendingRecovery to DB: {:?}", error);
+                continue;
+            }
+            if let Some(new_packet) = due_packet {
+                trace!(
+                    "Recovery of login packet {} is due",
+                    new_packet.destination()
+                );
+                due_packets.push(new_packet);
+            }
+        }
+        due_packets
+    }
+
+    // Writes or removes the recovery in progress under `db_key`, keeping the deadline index in
+    // step.  The index entry is dropped even if the write fails, so that it isn't retried forever.
+    fn set_pending(
+        &mut self,
+        db_key: &str,
+        pending: Option<&PendingRecovery>,
+    ) -> Result<(), pickledb::error::Error> {
+        if let Some(deadline) = self.deadlines.remove(db_key) {
+            let _ = self.by_deadline.remove(&(deadline, db_key.to_string()));
+        }
+        match pending {
+            Some(pending) => {
+                self.pending.set(db_key, pending)?;
+                let deadline = pending.deadline();
+                let _ = self.deadlines.insert(db_key.to_string(), deadline);
+                let _ = self.by_deadline.insert((deadline, db_key.to_string()));
+            }
+            None => {
+                let _ = self.pending.rem(db_key)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use safe_nd::ClientFullId;
+    use tempdir::TempDir;
+    use unwrap::unwrap;
+
+    fn new_packet(rng: &mut rand::rngs::ThreadRng, name: XorName) -> LoginPacket {
+        let new_owner = ClientFullId::new_ed25519(rng);
+        let data = vec![1, 2, 3];
+        unwrap!(LoginPacket::new(
+            name,
+            *new_owner.public_id().public_key(),
+            data.clone(),
+            new_owner.sign(&data)
+        ))
+    }
+
+    #[test]
+    fn recovery_takes_effect_after_delay() {
+        let root = unwrap!(TempDir::new("recovery"));
+        let mut recovery = unwrap!(RecoveryDb::new(root.path(), Init::New));
+
+        let mut rng = rand::thread_rng();
+        let keys: Vec<_> = (0..3)
+            .map(|_| *ClientFullId::new_ed25519(&mut rng).public_id().public_key())
+            .collect();
+        let outsider = *ClientFullId::new_ed25519(&mut rng).public_id().public_key();
+        let packet = new_packet(&mut rng, rand::random());
+        let config = unwrap!(RecoveryConfig::new(keys.iter().cloned().collect(), 2, 100));
+        unwrap!(recovery.set_config(packet.destination(), &config));
+
+        assert_eq!(
+            recovery.approve(packet.clone(), outsider, 0),
+            Err(NdError::AccessDenied)
+        );
+        assert_eq!(recovery.approve(packet.clone(), keys[0], 0), Ok(None));
+        assert!(recovery.take_due(1000).is_empty());
+        assert_eq!(recovery.approve(packet.clone(), keys[1], 10), Ok(Some(110)));
+
+        assert!(recovery.take_due(109).is_empty());
+        let due = recovery.take_due(110);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].destination(), packet.destination());
+        assert!(recovery.take_due(110).is_empty());
+    }
+
+    #[test]
+    fn owner_can_veto_recovery() {
+        let root = unwrap!(TempDir::new("recovery"));
+        let mut recovery = unwrap!(RecoveryDb::new(root.path(), Init::New));
+
+        let mut rng = rand::thread_rng();
+        let keys: Vec<_> = (0..2)
+            .map(|_| *ClientFullId::new_ed25519(&mut rng).public_id().public_key())
+            .collect();
+        let packet = new_packet(&mut rng, rand::random());
+        let name = *packet.destination();
+        let config = unwrap!(RecoveryConfig::new(keys.iter().cloned().collect(), 2, 100));
+        unwrap!(recovery.set_config(&name, &config));
+
+        assert_eq!(recovery.veto(&name), Err(NdError::InvalidOperation));
+        assert_eq!(recovery.approve(packet.clone(), keys[0], 0), Ok(None));
+        assert_eq!(recovery.approve(packet, keys[1], 0), Ok(Some(100)));
+        unwrap!(recovery.veto(&name));
+        assert!(recovery.take_due(100).is_empty());
+    }
+
+    #[test]
+    fn recovery_keys_can_switch_replacement() {
+        let root = unwrap!(TempDir::new("recovery"));
+        let mut recovery = unwrap!(RecoveryDb::new(root.path(), Init::New));
+
+        let mut rng = rand::thread_rng();
+        let keys: Vec<_> = (0..3)
+            .map(|_| *ClientFullId::new_ed25519(&mut rng).public_id().public_key())
+            .collect();
+        let name: XorName = rand::random();
+        let packet = new_packet(&mut rng, name);
+        let bogus_packet = new_packet(&mut rng, name);
+        let config = unwrap!(RecoveryConfig::new(keys.iter().cloned().collect(), 2, 100));
+        unwrap!(recovery.set_config(&name, &config));
+
+        // A key approving a different replacement doesn't block the others.
+        assert_eq!(recovery.approve(bogus_packet.clone(), keys[0], 0), Ok(None));
+        assert_eq!(recovery.approve(packet.clone(), keys[1], 0), Ok(None));
+        // Nor does it count twice once it switches to the other one.
+        assert_eq!(recovery.approve(packet.clone(), keys[0], 0), Ok(Some(100)));
+        // Once the threshold is reached, the replacement can only be vetoed.
+        assert_eq!(
+            recovery.approve(bogus_packet, keys[2], 0),
+            Err(NdError::InvalidOperation)
+        );
+        assert_eq!(recovery.approve(packet, keys[2], 0), Ok(Some(100)));
+    }
+
+    #[test]
+    fn approvals_below_threshold_expire() {
+        let root = unwrap!(TempDir::new("recovery"));
+        let mut recovery = unwrap!(RecoveryDb::new(root.path(), Init::New));
+
+        let mut rng = rand::thread_rng();
+        let keys: Vec<_> = (0..2)
+            .map(|_| *ClientFullId::new_ed25519(&mut rng).public_id().public_key())
+            .collect();
+        let packet = new_packet(&mut rng, rand::random());
+        let config = unwrap!(RecoveryConfig::new(keys.iter().cloned().collect(), 2, 100));
+        unwrap!(recovery.set_config(packet.destination(), &config));
+
+        assert_eq!(recovery.approve(packet.clone(), keys[0], 0), Ok(None));
+        // The approval survives a restart, but not its timeout.
+        let mut recovery = unwrap!(RecoveryDb::new(root.path(), Init::Load));
+        assert!(recovery.take_due(APPROVAL_TIMEOUT_SECS).is_empty());
+        assert!(recovery.pending.get_all().is_empty());
+        assert!(recovery.by_deadline.is_empty());
+
+        assert_eq!(
+            recovery.approve(packet, keys[1], APPROVAL_TIMEOUT_SECS),
+            Ok(None)
+        );
+    }
+}
@@ -0,0 +1,247 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::messages::SessionToken;
+use bytes::Bytes;
+use log::trace;
+use safe_nd::{MessageId, PublicId};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// How long a resumable session survives after its connection drops.
+pub const SESSION_GRACE_PERIOD: Duration = Duration::from_secs(120);
+/// Maximum number of undelivered responses held for a single session.  The oldest is dropped to
+/// make room for a new one.
+pub const MAX_BUFFERED_RESPONSES: usize = 50;
+
+struct Session {
+    client_id: PublicId,
+    // The connection the session was started for.
+    peer_addr: SocketAddr,
+    // Set once the client asks for the session's token, as only then can it resume the session.
+    resumable: bool,
+    // Set when the connection of the session drops.  The session expires `SESSION_GRACE_PERIOD`
+    // after this.
+    suspended_at: Option<Instant>,
+    // Serialised responses which couldn't be delivered while the client was disconnected, in the
+    // order they were produced.
+    pending_responses: Vec<(MessageId, Bytes)>,
+}
+
+impl Session {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.suspended_at
+            .map(|suspended_at| now.duration_since(suspended_at) >= SESSION_GRACE_PERIOD)
+            .unwrap_or(false)
+    }
+}
+
+/// Sessions of the client connections, and of those which recently dropped.
+///
+/// Each connection gets its own session once it passes the challenge, as the same `PublicId` can
+/// be connected from several devices.  If the client has asked for the session's token, the
+/// session outlives a dropped connection: responses to it are buffered in the session, and are
+/// only handed over to a new connection of the same client which presents the token, so they
+/// reach the device which sent the requests.  Other sessions end with their connection.
+#[derive(Default)]
+pub(super) struct Sessions {
+    sessions: HashMap<SessionToken, Session>,
+}
+
+impl Sessions {
+    /// Starts a session for a new connection of `client_id` on `peer_addr`, returning its token.
+    pub fn start(
+        &mut self,
+        client_id: &PublicId,
+        peer_addr: SocketAddr,
+        now: Instant,
+    ) -> SessionToken {
+        self.prune(now);
+        let mut token = rand::random();
+        while self.sessions.contains_key(&token) {
+            token = rand::random();
+        }
+        let _ = self.sessions.insert(
+            token,
+            Session {
+                client_id: client_id.clone(),
+                peer_addr,
+                resumable: false,
+                suspended_at: None,
+                pending_responses: Vec::new(),
+            },
+        );
+        token
+    }
+
+    /// Lets the session be resumed once its connection drops.  Returns `false` if there's no such
+    /// session.
+    pub fn make_resumable(&mut self, token: SessionToken) -> bool {
+        match self.sessions.get_mut(&token) {
+            Some(session) => {
+                session.resumable = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Marks the session as suspended after its connection dropped, or ends it if it can't be
+    /// resumed.
+    pub fn suspend(&mut self, token: SessionToken, now: Instant) {
+        self.prune(now);
+        match self.sessions.get_mut(&token) {
+            Some(session) if session.resumable => session.suspended_at = Some(now),
+            Some(_) => {
+                let _ = self.sessions.remove(&token);
+            }
+            None => (),
+        }
+    }
+
+    /// Returns the suspended session whose connection on `peer_addr` dropped most recently.
+    pub fn suspended_on(&self, peer_addr: SocketAddr) -> Option<SessionToken> {
+        self.sessions
+            .iter()
+            .filter(|(_, session)| session.peer_addr == peer_addr)
+            .filter_map(|(token, session)| Some((*token, session.suspended_at?)))
+            .max_by_key(|(_, suspended_at)| *suspended_at)
+            .map(|(token, _)| token)
+    }
+
+    /// Ends the suspended session `token` of `client_id`, returning the responses buffered since
+    /// its connection dropped.  Returns `None` if there's no such session, e.g. because it expired.
+    pub fn resume(
+        &mut self,
+        token: SessionToken,
+        client_id: &PublicId,
+        now: Instant,
+    ) -> Option<Vec<(MessageId, Bytes)>> {
+        self.prune(now);
+        match self.sessions.get(&token) {
+            Some(session) if session.suspended_at.is_some() && session.client_id == *client_id => {}
+            _ => return None,
+        }
+        let session = self.sessions.remove(&token)?;
+        trace!(
+            "Resuming session of {} with {} buffered responses",
+            client_id,
+            session.pending_responses.len()
+        );
+        Some(session.pending_responses)
+    }
+
+    /// Buffers the serialised `response` for the suspended session `token`, so it can be
+    /// delivered when the session is resumed.  Returns `false` if the session isn't suspended.
+    pub fn buffer_response(
+        &mut self,
+        token: SessionToken,
+        message_id: MessageId,
+        response: Bytes,
+        now: Instant,
+    ) -> bool {
+        self.prune(now);
+        let session = match self.sessions.get_mut(&token) {
+            Some(session) if session.suspended_at.is_some() => session,
+            _ => return false,
+        };
+        session
+            .pending_responses
+            .retain(|(buffered_id, _)| *buffered_id != message_id);
+        if session.pending_responses.len() >= MAX_BUFFERED_RESPONSES {
+            let _ = session.pending_responses.remove(0);
+        }
+        session.pending_responses.push((message_id, response));
+        true
+    }
+
+    /// Removes all sessions whose grace period has elapsed.
+    pub fn prune(&mut self, now: Instant) {
+        self.sessions.retain(|_, session| !session.is_expired(now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use safe_nd::ClientFullId;
+
+    fn new_peer_addr() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], rand::random()))
+    }
+
+    fn new_client_id() -> PublicId {
+        let mut rng = rand::thread_rng();
+        PublicId::Client(ClientFullId::new_ed25519(&mut rng).public_id().clone())
+    }
+
+    #[test]
+    fn resume_hands_over_buffered_responses() {
+        let mut sessions = Sessions::default();
+        let client_id = new_client_id();
+        let now = Instant::now();
+
+        let peer_addr = new_peer_addr();
+        let token = sessions.start(&client_id, peer_addr, now);
+        assert!(sessions.make_resumable(token));
+
+        // Responses are only buffered while the session is suspended, and it can only be resumed
+        // then.
+        let message_id = MessageId::new();
+        let response = Bytes::from(vec![1, 2, 3]);
+        assert!(!sessions.buffer_response(token, message_id, response.clone(), now));
+        assert!(sessions.resume(token, &client_id, now).is_none());
+
+        sessions.suspend(token, now);
+        assert_eq!(sessions.suspended_on(peer_addr), Some(token));
+        assert!(sessions.buffer_response(token, message_id, response.clone(), now));
+
+        // Another client can't take over the session.
+        assert!(sessions.resume(token, &new_client_id(), now).is_none());
+        assert_eq!(
+            sessions.resume(token, &client_id, now),
+            Some(vec![(message_id, response)])
+        );
+        // A session can only be resumed once.
+        assert!(sessions.resume(token, &client_id, now).is_none());
+    }
+
+    #[test]
+    fn session_without_token_ends_with_connection() {
+        let mut sessions = Sessions::default();
+        let client_id = new_client_id();
+        let peer_addr = new_peer_addr();
+        let now = Instant::now();
+
+        let token = sessions.start(&client_id, peer_addr, now);
+        sessions.suspend(token, now);
+        assert!(sessions.suspended_on(peer_addr).is_none());
+        assert!(!sessions.buffer_response(token, MessageId::new(), Bytes::new(), now));
+        assert!(sessions.resume(token, &client_id, now).is_none());
+    }
+
+    #[test]
+    fn expired_session_is_not_resumed() {
+        let mut sessions = Sessions::default();
+        let client_id = new_client_id();
+        let now = Instant::now();
+
+        let token = sessions.start(&client_id, new_peer_addr(), now);
+        assert!(sessions.make_resumable(token));
+        sessions.suspend(token, now);
+
+        let later = now + SESSION_GRACE_PERIOD;
+        let message_id = MessageId::new();
+        let response = Bytes::from(vec![1, 2, 3]);
+        assert!(!sessions.buffer_response(token, message_id, response, later));
+        assert!(sessions.resume(token, &client_id, later).is_none());
+    }
+}
@@ -6,19 +6,22 @@
 // KIND, either express or implied. Please review the Licences for the specific language governing
 // permissions and limitations relating to use of the SAFE Network Software.
 
-use crate::{quic_p2p::Config as QuicP2pConfig, quic_p2p::NodeInfo, Result};
+use crate::{quic_p2p::Config as QuicP2pConfig, quic_p2p::NodeInfo, Error, Result};
 use directories::ProjectDirs;
 use log::{trace, Level};
 use serde::{Deserialize, Serialize};
 use std::{
     env,
+    ffi::OsString,
+    fmt::{self, Display, Formatter},
     fs::{self, File},
     io::{self, BufReader},
     net::{IpAddr, Ipv4Addr},
     path::PathBuf,
+    str::FromStr,
+    time::Duration,
 };
 use structopt::StructOpt;
-use unwrap::unwrap;
 
 const CONFIG_DIR_QUALIFIER: &str = "net";
 const CONFIG_DIR_ORGANISATION: &str = "MaidSafe";
@@ -27,22 +30,109 @@ const CONFIG_FILE: &str = "vault.config";
 const CONNECTION_INFO_FILE: &str = "vault_connection_info.config";
 const DEFAULT_ROOT_DIR_NAME: &str = "safe_vault";
 const DEFAULT_MAX_CAPACITY: u64 = 2 * 1024 * 1024 * 1024;
-const ARGS: [&str; 12] = [
-    "wallet-address",
-    "max-capacity",
-    "root-dir",
-    "verbose",
-    "hard-coded-contacts",
-    "port",
-    "ip",
-    "max-msg-size-allowed",
-    "idle-timeout-msec",
-    "keep-alive-interval-msec",
-    "our-complete-cert",
-    "our-type",
+const DEFAULT_MAX_CONNECTIONS_PER_CLIENT: usize = 5;
+const DEFAULT_CLIENT_RATE_LIMIT: u32 = 100;
+const DEFAULT_IP_RATE_LIMIT: u32 = 500;
+const DEFAULT_SNAPSHOT_INTERVAL_SECS: u64 = 60 * 60;
+const DEFAULT_SNAPSHOT_RETENTION: usize = 24;
+const DEFAULT_CHALLENGE_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_MAX_CLIENT_CANDIDATES: usize = 1000;
+const DEFAULT_MAX_CLIENT_CANDIDATES_PER_IP: usize = 10;
+const UNKNOWN_SETTING: &str = "unknown setting";
+const ENV_VAR_PREFIX: &str = "SAFE_VAULT_";
+const WALLET_ADDRESS_ARG: &str = "wallet-address";
+const MAX_CAPACITY_ARG: &str = "max-capacity";
+const ROOT_DIR_ARG: &str = "root-dir";
+const VERBOSE_ARG: &str = "verbose";
+const HARD_CODED_CONTACTS_ARG: &str = "hard-coded-contacts";
+const PORT_ARG: &str = "port";
+const IP_ARG: &str = "ip";
+const MAX_MSG_SIZE_ALLOWED_ARG: &str = "max-msg-size-allowed";
+const IDLE_TIMEOUT_MSEC_ARG: &str = "idle-timeout-msec";
+const KEEP_ALIVE_INTERVAL_MSEC_ARG: &str = "keep-alive-interval-msec";
+const OUR_COMPLETE_CERT_ARG: &str = "our-complete-cert";
+const OUR_TYPE_ARG: &str = "our-type";
+const MAX_CONNECTIONS_PER_CLIENT_ARG: &str = "max-connections-per-client";
+const CLIENT_RATE_LIMIT_ARG: &str = "client-rate-limit";
+const IP_RATE_LIMIT_ARG: &str = "ip-rate-limit";
+const SNAPSHOT_DIR_ARG: &str = "snapshot-dir";
+const SNAPSHOT_INTERVAL_SECS_ARG: &str = "snapshot-interval-secs";
+const SNAPSHOT_RETENTION_ARG: &str = "snapshot-retention";
+const CONTROL_PORT_ARG: &str = "control-port";
+const METRICS_PORT_ARG: &str = "metrics-port";
+const JSON_LOGS_ARG: &str = "json-logs";
+/// Only valid on the command line, not in the environment.
+const CHECK_CONFIG_ARG: &str = "check-config";
+const DISABLE_AUTO_UPDATE_ARG: &str = "disable-auto-update";
+const DAEMON_ARG: &str = "daemon";
+const LOG_DIR_ARG: &str = "log-dir";
+const CHALLENGE_TIMEOUT_SECS_ARG: &str = "challenge-timeout-secs";
+const MAX_CLIENT_CANDIDATES_ARG: &str = "max-client-candidates";
+const MAX_CLIENT_CANDIDATES_PER_IP_ARG: &str = "max-client-candidates-per-ip";
+const EXPORT_DIR_ARG: &str = "export-dir";
+const ARGS: [&str; 29] = [
+    WALLET_ADDRESS_ARG,
+    MAX_CAPACITY_ARG,
+    ROOT_DIR_ARG,
+    VERBOSE_ARG,
+    HARD_CODED_CONTACTS_ARG,
+    PORT_ARG,
+    IP_ARG,
+    MAX_MSG_SIZE_ALLOWED_ARG,
+    IDLE_TIMEOUT_MSEC_ARG,
+    KEEP_ALIVE_INTERVAL_MSEC_ARG,
+    OUR_COMPLETE_CERT_ARG,
+    OUR_TYPE_ARG,
+    MAX_CONNECTIONS_PER_CLIENT_ARG,
+    CLIENT_RATE_LIMIT_ARG,
+    IP_RATE_LIMIT_ARG,
+    SNAPSHOT_DIR_ARG,
+    SNAPSHOT_INTERVAL_SECS_ARG,
+    SNAPSHOT_RETENTION_ARG,
+    CONTROL_PORT_ARG,
+    METRICS_PORT_ARG,
+    JSON_LOGS_ARG,
+    CHECK_CONFIG_ARG,
+    DISABLE_AUTO_UPDATE_ARG,
+    DAEMON_ARG,
+    LOG_DIR_ARG,
+    CHALLENGE_TIMEOUT_SECS_ARG,
+    MAX_CLIENT_CANDIDATES_ARG,
+    MAX_CLIENT_CANDIDATES_PER_IP_ARG,
+    EXPORT_DIR_ARG,
 ];
 
+/// A setting which is invalid.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigError {
+    /// Name of the setting as a command line arg, or the config file path if it can't be parsed.
+    pub setting: String,
+    /// Why the setting is invalid.
+    pub reason: String,
+}
+
+impl ConfigError {
+    fn new<S: Into<String>, R: Into<String>>(setting: S, reason: R) -> Self {
+        Self {
+            setting: setting.into(),
+            reason: reason.into(),
+        }
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        write!(formatter, "{}: {}", self.setting, self.reason)
+    }
+}
+
 /// Vault configuration
+///
+/// Settings are read from the config file, then overridden by environment variables, then by
+/// command line args.  The environment variable for a setting is its command line arg in upper
+/// case, with `-` replaced by `_` and prefixed with `SAFE_VAULT_`, e.g. `SAFE_VAULT_ROOT_DIR` for
+/// `--root-dir`.  Flags take a value in their environment variables: the number of occurrences for
+/// `SAFE_VAULT_VERBOSE`, and `true` or `false` for the others, e.g. `SAFE_VAULT_JSON_LOGS`.
 #[derive(Default, Clone, Debug, Serialize, Deserialize, Eq, PartialEq, StructOpt)]
 #[structopt(rename_all = "kebab-case", bin_name = "safe_vault")]
 pub struct Config {
@@ -61,6 +151,73 @@ pub struct Config {
     /// `debug`, `-vvvv` to `trace`. This flag overrides RUST_LOG.
     #[structopt(short, long, parse(from_occurrences))]
     verbose: u64,
+    /// Maximum number of simultaneous connections allowed per client identity, e.g. when the same
+    /// account is used from several devices.
+    #[structopt(long)]
+    max_connections_per_client: Option<usize>,
+    /// Maximum number of requests per second accepted from a single client account, including its
+    /// apps.  Zero disables the limit.
+    #[structopt(long)]
+    client_rate_limit: Option<u32>,
+    /// Maximum number of requests per second accepted from a single IP address.  Zero disables the
+    /// limit.
+    #[structopt(long)]
+    ip_rate_limit: Option<u32>,
+    /// Directory for periodic snapshots of the root directory.  Snapshots are disabled if not set.
+    /// It must not be within the root directory.
+    #[structopt(long, parse(from_os_str))]
+    snapshot_dir: Option<PathBuf>,
+    /// Interval in seconds between snapshots.
+    #[structopt(long)]
+    snapshot_interval_secs: Option<u64>,
+    /// Number of snapshots to keep.  Older ones are deleted.  Snapshots share unchanged chunks via
+    /// hard links, unless the snapshot directory is on a different filesystem from the root
+    /// directory, in which case each one can take up to `max_capacity`.
+    #[structopt(long)]
+    snapshot_retention: Option<usize>,
+    /// Port on 127.0.0.1 on which to accept control commands, e.g. `status`.  Each command must be
+    /// preceded by the token the vault writes to `control.token` in the root directory, which only
+    /// the vault's user can read.  The control listener is disabled if not set.
+    #[structopt(long)]
+    control_port: Option<u16>,
+    /// Port on 127.0.0.1 on which to serve metrics over HTTP in the Prometheus text format.
+    /// Metrics aren't served if not set.
+    #[structopt(long)]
+    metrics_port: Option<u16>,
+    /// Log one JSON object per line, with fields identifying the vault and the client request
+    /// being handled, instead of free text.
+    #[structopt(long)]
+    json_logs: bool,
+    /// Validate the config file and command line args, print the resulting config and exit
+    /// without starting the vault.
+    #[structopt(long)]
+    #[serde(skip)]
+    check_config: bool,
+    /// Don't check for new releases at startup.
+    #[structopt(long)]
+    disable_auto_update: bool,
+    /// Run in the background, detached from the terminal, writing a locked pid file to the root
+    /// directory.  Unix only.  Requires `--log-dir`.
+    #[structopt(long)]
+    daemon: bool,
+    /// Directory for log files, which are rotated as they grow.  If not set, logs are written to
+    /// stderr.
+    #[structopt(long, parse(from_os_str))]
+    log_dir: Option<PathBuf>,
+    /// Time in seconds a new connection has to answer the vault's challenge before it's dropped.
+    #[structopt(long)]
+    challenge_timeout_secs: Option<u64>,
+    /// Maximum number of connections awaiting a challenge response.  The oldest is dropped to make
+    /// room for a new one.
+    #[structopt(long)]
+    max_client_candidates: Option<usize>,
+    /// Maximum number of connections awaiting a challenge response from a single IP address.
+    /// Further connections from that address are refused.
+    #[structopt(long)]
+    max_client_candidates_per_ip: Option<usize>,
+    /// Directory the `export` control command writes archives to.  Exports are disabled if not set.
+    #[structopt(long, parse(from_os_str))]
+    export_dir: Option<PathBuf>,
     #[structopt(flatten)]
     #[allow(missing_docs)]
     quic_p2p_config: QuicP2pConfig,
@@ -68,29 +225,222 @@ pub struct Config {
 
 impl Config {
     /// Returns a new `Config` instance.  Tries to read from the default vault config file location,
-    /// and overrides values with any equivalent command line args.
-    pub fn new() -> Self {
-        let mut config = Self::read_from_file().unwrap_or(Self {
-            wallet_address: None,
-            max_capacity: None,
-            root_dir: None,
-            verbose: 0,
-            quic_p2p_config: Default::default(),
-        });
+    /// and overrides values with any equivalent environment variables, then with any equivalent
+    /// command line args.  The defaults are used if there is no config file.
+    ///
+    /// Returns `Error::InvalidConfig` listing every invalid setting if the file can't be parsed, or
+    /// if any value is malformed or out of range.
+    pub fn new() -> Result<Self> {
+        Self::load(false)
+    }
 
-        let command_line_args = Config::clap().get_matches();
-        for arg in &ARGS {
-            let occurrences = command_line_args.occurrences_of(arg);
-            if occurrences != 0 {
-                if let Some(cla) = command_line_args.value_of(arg) {
-                    config.set_value(arg, cla);
-                } else {
-                    config.set_flag(arg, occurrences);
-                }
+    /// Re-reads the config file and overrides values with any equivalent environment variables and
+    /// command line args, as `new` does.  Unlike `new`, fails if there's no config file, so that
+    /// deleting it doesn't reset the running vault to the defaults.
+    pub fn reload() -> Result<Self> {
+        Self::load(true)
+    }
+
+    fn load(require_file: bool) -> Result<Self> {
+        let mut errors = Vec::new();
+        let mut config = match Self::read_from_file() {
+            Ok(Some(config)) => config,
+            Ok(None) if require_file => {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "No config file").into())
             }
+            Ok(None) => Self::default(),
+            Err(error) => {
+                errors.push(error);
+                Self::default()
+            }
+        };
+        errors.extend(config.apply_env_vars(env::vars_os()));
+        errors.extend(config.apply_command_line_args());
+        errors.extend(config.validate());
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(Error::InvalidConfig(errors))
         }
+    }
+
+    /// Checks the values which parse, but are out of range or conflict with each other.
+    fn validate(&self) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        let mut check = |valid: bool, arg: &str, reason: &str| {
+            if !valid {
+                errors.push(ConfigError::new(arg, reason));
+            }
+        };
 
-        config
+        check(
+            self.max_capacity != Some(0),
+            MAX_CAPACITY_ARG,
+            "must be greater than 0",
+        );
+        check(
+            self.max_connections_per_client != Some(0),
+            MAX_CONNECTIONS_PER_CLIENT_ARG,
+            "must be greater than 0",
+        );
+        check(
+            self.snapshot_dir
+                .as_ref()
+                .map_or(true, |dir| !dir.starts_with(self.root_dir())),
+            SNAPSHOT_DIR_ARG,
+            "must not be within the root directory",
+        );
+        check(
+            self.snapshot_interval_secs != Some(0),
+            SNAPSHOT_INTERVAL_SECS_ARG,
+            "must be greater than 0",
+        );
+        check(
+            self.snapshot_retention != Some(0),
+            SNAPSHOT_RETENTION_ARG,
+            "must be greater than 0",
+        );
+        check(
+            self.metrics_port.is_none() || self.metrics_port != self.control_port,
+            METRICS_PORT_ARG,
+            "must differ from the control port",
+        );
+        check(
+            !self.daemon || self.log_dir.is_some(),
+            LOG_DIR_ARG,
+            "must be set when running as a daemon",
+        );
+        check(
+            self.challenge_timeout_secs != Some(0),
+            CHALLENGE_TIMEOUT_SECS_ARG,
+            "must be greater than 0",
+        );
+        check(
+            self.max_client_candidates != Some(0),
+            MAX_CLIENT_CANDIDATES_ARG,
+            "must be greater than 0",
+        );
+        check(
+            self.max_client_candidates_per_ip != Some(0),
+            MAX_CLIENT_CANDIDATES_PER_IP_ARG,
+            "must be greater than 0",
+        );
+        check(
+            self.export_dir
+                .as_ref()
+                .map_or(true, |dir| !dir.starts_with(self.root_dir())),
+            EXPORT_DIR_ARG,
+            "must not be within the root directory",
+        );
+        errors
+    }
+
+    /// Path of the vault config file.
+    pub fn file_path() -> Result<PathBuf> {
+        Ok(dirs()?.config_dir().join(CONFIG_FILE))
+    }
+
+    /// Returns the names of the settings which differ in `new`, split into those the vault applies
+    /// while running and those which only take effect after a restart.
+    pub(crate) fn changes(&self, new: &Config) -> (Vec<&'static str>, Vec<&'static str>) {
+        let mut live = Vec::new();
+        let mut needs_restart = Vec::new();
+        let mut check = |changed: bool, arg: &'static str, is_live: bool| {
+            if changed && is_live {
+                live.push(arg);
+            } else if changed {
+                needs_restart.push(arg);
+            }
+        };
+
+        check(
+            self.max_capacity() != new.max_capacity(),
+            MAX_CAPACITY_ARG,
+            true,
+        );
+        // The binary only sets up logging for live changes when started with `--verbose`.
+        // Otherwise `RUST_LOG` applies until restarted.
+        check(
+            self.verbose != new.verbose,
+            VERBOSE_ARG,
+            self.verbose() != Level::Error,
+        );
+        check(
+            self.max_connections_per_client() != new.max_connections_per_client(),
+            MAX_CONNECTIONS_PER_CLIENT_ARG,
+            true,
+        );
+        check(
+            self.client_rate_limit() != new.client_rate_limit(),
+            CLIENT_RATE_LIMIT_ARG,
+            true,
+        );
+        check(
+            self.ip_rate_limit() != new.ip_rate_limit(),
+            IP_RATE_LIMIT_ARG,
+            true,
+        );
+        check(
+            self.challenge_timeout() != new.challenge_timeout(),
+            CHALLENGE_TIMEOUT_SECS_ARG,
+            true,
+        );
+        check(
+            self.max_client_candidates() != new.max_client_candidates(),
+            MAX_CLIENT_CANDIDATES_ARG,
+            true,
+        );
+        check(
+            self.max_client_candidates_per_ip() != new.max_client_candidates_per_ip(),
+            MAX_CLIENT_CANDIDATES_PER_IP_ARG,
+            true,
+        );
+        check(
+            self.snapshot_dir() != new.snapshot_dir(),
+            SNAPSHOT_DIR_ARG,
+            true,
+        );
+        check(self.export_dir() != new.export_dir(), EXPORT_DIR_ARG, true);
+        check(
+            self.snapshot_interval() != new.snapshot_interval(),
+            SNAPSHOT_INTERVAL_SECS_ARG,
+            true,
+        );
+        check(
+            self.snapshot_retention() != new.snapshot_retention(),
+            SNAPSHOT_RETENTION_ARG,
+            true,
+        );
+        check(
+            self.wallet_address() != new.wallet_address(),
+            WALLET_ADDRESS_ARG,
+            false,
+        );
+        check(self.root_dir() != new.root_dir(), ROOT_DIR_ARG, false);
+        check(
+            self.control_port() != new.control_port(),
+            CONTROL_PORT_ARG,
+            false,
+        );
+        check(
+            self.metrics_port() != new.metrics_port(),
+            METRICS_PORT_ARG,
+            false,
+        );
+        check(self.json_logs() != new.json_logs(), JSON_LOGS_ARG, false);
+        check(
+            self.disable_auto_update() != new.disable_auto_update(),
+            DISABLE_AUTO_UPDATE_ARG,
+            false,
+        );
+        check(self.daemon() != new.daemon(), DAEMON_ARG, false);
+        check(self.log_dir() != new.log_dir(), LOG_DIR_ARG, false);
+        check(
+            self.quic_p2p_config() != new.quic_p2p_config(),
+            "quic-p2p",
+            false,
+        );
+        (live, needs_restart)
     }
 
     /// The address to be credited when this vault farms SafeCoin.
@@ -128,6 +478,111 @@ impl Config {
         }
     }
 
+    /// Maximum number of simultaneous connections allowed for a single client identity.
+    pub fn max_connections_per_client(&self) -> usize {
+        self.max_connections_per_client
+            .unwrap_or(DEFAULT_MAX_CONNECTIONS_PER_CLIENT)
+    }
+
+    /// Set the maximum number of simultaneous connections allowed for a single client identity.
+    pub fn set_max_connections_per_client(&mut self, max_connections: usize) {
+        self.max_connections_per_client = Some(max_connections)
+    }
+
+    /// Maximum number of requests per second accepted from a single client account.
+    pub fn client_rate_limit(&self) -> u32 {
+        self.client_rate_limit.unwrap_or(DEFAULT_CLIENT_RATE_LIMIT)
+    }
+
+    /// Set the maximum number of requests per second accepted from a single client account.
+    pub fn set_client_rate_limit(&mut self, rate: u32) {
+        self.client_rate_limit = Some(rate)
+    }
+
+    /// Maximum number of requests per second accepted from a single IP address.
+    pub fn ip_rate_limit(&self) -> u32 {
+        self.ip_rate_limit.unwrap_or(DEFAULT_IP_RATE_LIMIT)
+    }
+
+    /// Time a new connection has to answer the vault's challenge.
+    pub fn challenge_timeout(&self) -> Duration {
+        Duration::from_secs(
+            self.challenge_timeout_secs
+                .unwrap_or(DEFAULT_CHALLENGE_TIMEOUT_SECS),
+        )
+    }
+
+    /// Maximum number of connections awaiting a challenge response.
+    pub fn max_client_candidates(&self) -> usize {
+        self.max_client_candidates
+            .unwrap_or(DEFAULT_MAX_CLIENT_CANDIDATES)
+    }
+
+    /// Maximum number of connections awaiting a challenge response from a single IP address.
+    pub fn max_client_candidates_per_ip(&self) -> usize {
+        self.max_client_candidates_per_ip
+            .unwrap_or(DEFAULT_MAX_CLIENT_CANDIDATES_PER_IP)
+    }
+
+    /// Directory for periodic snapshots of the root directory, if enabled.
+    pub fn snapshot_dir(&self) -> Option<PathBuf> {
+        self.snapshot_dir.clone()
+    }
+
+    /// Interval between snapshots.
+    pub fn snapshot_interval(&self) -> Duration {
+        Duration::from_secs(
+            self.snapshot_interval_secs
+                .unwrap_or(DEFAULT_SNAPSHOT_INTERVAL_SECS),
+        )
+    }
+
+    /// Number of snapshots to keep.
+    pub fn snapshot_retention(&self) -> usize {
+        self.snapshot_retention
+            .unwrap_or(DEFAULT_SNAPSHOT_RETENTION)
+    }
+
+    /// Port on 127.0.0.1 on which to accept control commands, if enabled.
+    pub fn control_port(&self) -> Option<u16> {
+        self.control_port
+    }
+
+    /// Port on 127.0.0.1 on which to serve metrics, if enabled.
+    pub fn metrics_port(&self) -> Option<u16> {
+        self.metrics_port
+    }
+
+    /// Whether to log JSON objects rather than free text.
+    pub fn json_logs(&self) -> bool {
+        self.json_logs
+    }
+
+    /// Whether to only validate and print the config rather than run the vault.
+    pub fn check_config(&self) -> bool {
+        self.check_config
+    }
+
+    /// Whether to skip checking for new releases.
+    pub fn disable_auto_update(&self) -> bool {
+        self.disable_auto_update
+    }
+
+    /// Whether to run in the background as a daemon.
+    pub fn daemon(&self) -> bool {
+        self.daemon
+    }
+
+    /// Directory for log files, if logging to files.
+    pub fn log_dir(&self) -> Option<PathBuf> {
+        self.log_dir.clone()
+    }
+
+    /// Directory to write exported archives to, if exports are enabled.
+    pub fn export_dir(&self) -> Option<PathBuf> {
+        self.export_dir.clone()
+    }
+
     /// Quic-P2P configuration options.
     pub fn quic_p2p_config(&self) -> &QuicP2pConfig {
         &self.quic_p2p_config
@@ -143,71 +598,223 @@ impl Config {
         self.quic_p2p_config.ip = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
     }
 
-    fn set_value(&mut self, arg: &str, value: &str) {
-        if arg == ARGS[0] {
-            self.wallet_address = Some(unwrap!(value.parse()));
-        } else if arg == ARGS[1] {
-            self.max_capacity = Some(unwrap!(value.parse()));
-        } else if arg == ARGS[2] {
-            self.root_dir = Some(unwrap!(value.parse()));
-        } else if arg == ARGS[3] {
-            self.verbose = unwrap!(value.parse());
-        } else if arg == ARGS[4] {
-            self.quic_p2p_config.hard_coded_contacts = unwrap!(serde_json::from_str(value));
-        } else if arg == ARGS[5] {
-            self.quic_p2p_config.port = Some(unwrap!(value.parse()));
-        } else if arg == ARGS[6] {
-            self.quic_p2p_config.ip = Some(unwrap!(value.parse()));
-        } else if arg == ARGS[11] {
-            self.quic_p2p_config.our_type = unwrap!(value.parse());
-        } else {
-            #[cfg(not(feature = "mock"))]
-            {
-                if arg == ARGS[7] {
-                    self.quic_p2p_config.max_msg_size_allowed = Some(unwrap!(value.parse()));
-                } else if arg == ARGS[8] {
-                    self.quic_p2p_config.idle_timeout_msec = Some(unwrap!(value.parse()));
-                } else if arg == ARGS[9] {
-                    self.quic_p2p_config.keep_alive_interval_msec = Some(unwrap!(value.parse()));
-                } else if arg == ARGS[10] {
-                    self.quic_p2p_config.our_complete_cert = Some(unwrap!(value.parse()));
+    /// Copies the named settings, as returned by `changes`, from `new`.
+    pub(crate) fn copy_settings(&mut self, new: &Config, args: &[&str]) {
+        for &arg in args {
+            if arg == MAX_CAPACITY_ARG {
+                self.max_capacity = new.max_capacity;
+            } else if arg == VERBOSE_ARG {
+                self.verbose = new.verbose;
+            } else if arg == MAX_CONNECTIONS_PER_CLIENT_ARG {
+                self.max_connections_per_client = new.max_connections_per_client;
+            } else if arg == CLIENT_RATE_LIMIT_ARG {
+                self.client_rate_limit = new.client_rate_limit;
+            } else if arg == IP_RATE_LIMIT_ARG {
+                self.ip_rate_limit = new.ip_rate_limit;
+            } else if arg == SNAPSHOT_DIR_ARG {
+                self.snapshot_dir = new.snapshot_dir.clone();
+            } else if arg == SNAPSHOT_INTERVAL_SECS_ARG {
+                self.snapshot_interval_secs = new.snapshot_interval_secs;
+            } else if arg == SNAPSHOT_RETENTION_ARG {
+                self.snapshot_retention = new.snapshot_retention;
+            } else if arg == CHALLENGE_TIMEOUT_SECS_ARG {
+                self.challenge_timeout_secs = new.challenge_timeout_secs;
+            } else if arg == MAX_CLIENT_CANDIDATES_ARG {
+                self.max_client_candidates = new.max_client_candidates;
+            } else if arg == MAX_CLIENT_CANDIDATES_PER_IP_ARG {
+                self.max_client_candidates_per_ip = new.max_client_candidates_per_ip;
+            } else if arg == EXPORT_DIR_ARG {
+                self.export_dir = new.export_dir.clone();
+            }
+        }
+    }
+
+    /// Applies the variables from `vars` which start with `ENV_VAR_PREFIX`.  Ones which don't name
+    /// a setting are reported as errors, to catch typos.
+    fn apply_env_vars<I>(&mut self, vars: I) -> Vec<ConfigError>
+    where
+        I: IntoIterator<Item = (OsString, OsString)>,
+    {
+        let mut errors = Vec::new();
+        for (name, value) in vars {
+            let name = match name.to_str() {
+                Some(name) if name.starts_with(ENV_VAR_PREFIX) => name,
+                _ => continue,
+            };
+            let arg = name[ENV_VAR_PREFIX.len()..]
+                .to_lowercase()
+                .replace('_', "-");
+            // Only checking the config from the environment would stop the vault ever starting.
+            let result = match (ARGS.iter().find(|known| **known == arg), value.to_str()) {
+                (Some(arg), _) if *arg == CHECK_CONFIG_ARG => {
+                    Err(ConfigError::new(*arg, UNKNOWN_SETTING))
+                }
+                (Some(arg), Some(value)) => self.set_value(arg, value),
+                (Some(arg), None) => Err(ConfigError::new(*arg, "value is not valid unicode")),
+                (None, _) => Err(ConfigError::new(arg, UNKNOWN_SETTING)),
+            };
+            if let Err(error) = result {
+                errors.push(ConfigError::new(name, error.reason));
+            }
+        }
+        errors
+    }
+
+    fn apply_command_line_args(&mut self) -> Vec<ConfigError> {
+        let command_line_args = Config::clap().get_matches();
+        let mut errors = Vec::new();
+        for arg in &ARGS {
+            let occurrences = command_line_args.occurrences_of(arg);
+            if occurrences != 0 {
+                let result = if let Some(cla) = command_line_args.value_of(arg) {
+                    self.set_value(arg, cla)
                 } else {
-                    println!("ERROR");
+                    self.set_flag(arg, occurrences)
+                };
+                if let Err(error) = result {
+                    errors.push(error);
                 }
             }
+        }
+        errors
+    }
+
+    fn set_value(&mut self, arg: &str, value: &str) -> Result<(), ConfigError> {
+        if arg == WALLET_ADDRESS_ARG {
+            self.wallet_address = Some(parse(arg, value)?);
+        } else if arg == MAX_CAPACITY_ARG {
+            self.max_capacity = Some(parse(arg, value)?);
+        } else if arg == ROOT_DIR_ARG {
+            self.root_dir = Some(parse(arg, value)?);
+        } else if arg == VERBOSE_ARG {
+            self.verbose = parse(arg, value)?;
+        } else if arg == HARD_CODED_CONTACTS_ARG {
+            self.quic_p2p_config.hard_coded_contacts = serde_json::from_str(value)
+                .map_err(|error| ConfigError::new(arg, error.to_string()))?;
+        } else if arg == PORT_ARG {
+            self.quic_p2p_config.port = Some(parse(arg, value)?);
+        } else if arg == IP_ARG {
+            self.quic_p2p_config.ip = Some(parse(arg, value)?);
+        } else if arg == OUR_TYPE_ARG {
+            self.quic_p2p_config.our_type = parse(arg, value)?;
+        } else if arg == MAX_CONNECTIONS_PER_CLIENT_ARG {
+            self.max_connections_per_client = Some(parse(arg, value)?);
+        } else if arg == CLIENT_RATE_LIMIT_ARG {
+            self.client_rate_limit = Some(parse(arg, value)?);
+        } else if arg == IP_RATE_LIMIT_ARG {
+            self.ip_rate_limit = Some(parse(arg, value)?);
+        } else if arg == SNAPSHOT_DIR_ARG {
+            self.snapshot_dir = Some(parse(arg, value)?);
+        } else if arg == SNAPSHOT_INTERVAL_SECS_ARG {
+            self.snapshot_interval_secs = Some(parse(arg, value)?);
+        } else if arg == SNAPSHOT_RETENTION_ARG {
+            self.snapshot_retention = Some(parse(arg, value)?);
+        } else if arg == CONTROL_PORT_ARG {
+            self.control_port = Some(parse(arg, value)?);
+        } else if arg == METRICS_PORT_ARG {
+            self.metrics_port = Some(parse(arg, value)?);
+        } else if arg == JSON_LOGS_ARG {
+            self.json_logs = parse(arg, value)?;
+        } else if arg == DISABLE_AUTO_UPDATE_ARG {
+            self.disable_auto_update = parse(arg, value)?;
+        } else if arg == DAEMON_ARG {
+            self.daemon = parse(arg, value)?;
+        } else if arg == LOG_DIR_ARG {
+            self.log_dir = Some(parse(arg, value)?);
+        } else if arg == CHALLENGE_TIMEOUT_SECS_ARG {
+            self.challenge_timeout_secs = Some(parse(arg, value)?);
+        } else if arg == MAX_CLIENT_CANDIDATES_ARG {
+            self.max_client_candidates = Some(parse(arg, value)?);
+        } else if arg == MAX_CLIENT_CANDIDATES_PER_IP_ARG {
+            self.max_client_candidates_per_ip = Some(parse(arg, value)?);
+        } else if arg == EXPORT_DIR_ARG {
+            self.export_dir = Some(parse(arg, value)?);
+        } else {
+            return self.set_quic_p2p_value(arg, value);
+        }
+        Ok(())
+    }
 
-            #[cfg(feature = "mock")]
-            println!("ERROR");
+    #[cfg(not(feature = "mock"))]
+    fn set_quic_p2p_value(&mut self, arg: &str, value: &str) -> Result<(), ConfigError> {
+        if arg == MAX_MSG_SIZE_ALLOWED_ARG {
+            self.quic_p2p_config.max_msg_size_allowed = Some(parse(arg, value)?);
+        } else if arg == IDLE_TIMEOUT_MSEC_ARG {
+            self.quic_p2p_config.idle_timeout_msec = Some(parse(arg, value)?);
+        } else if arg == KEEP_ALIVE_INTERVAL_MSEC_ARG {
+            self.quic_p2p_config.keep_alive_interval_msec = Some(parse(arg, value)?);
+        } else if arg == OUR_COMPLETE_CERT_ARG {
+            self.quic_p2p_config.our_complete_cert = Some(parse(arg, value)?);
+        } else {
+            return Err(ConfigError::new(arg, UNKNOWN_SETTING));
         }
+        Ok(())
     }
 
-    fn set_flag(&mut self, arg: &str, occurrences: u64) {
-        if arg == ARGS[3] {
+    #[cfg(feature = "mock")]
+    fn set_quic_p2p_value(&mut self, arg: &str, _value: &str) -> Result<(), ConfigError> {
+        Err(ConfigError::new(arg, UNKNOWN_SETTING))
+    }
+
+    fn set_flag(&mut self, arg: &str, occurrences: u64) -> Result<(), ConfigError> {
+        if arg == VERBOSE_ARG {
             self.verbose = occurrences;
+        } else if arg == JSON_LOGS_ARG {
+            self.json_logs = true;
+        } else if arg == CHECK_CONFIG_ARG {
+            self.check_config = true;
+        } else if arg == DISABLE_AUTO_UPDATE_ARG {
+            self.disable_auto_update = true;
+        } else if arg == DAEMON_ARG {
+            self.daemon = true;
         } else {
-            println!("ERROR");
+            return Err(ConfigError::new(arg, UNKNOWN_SETTING));
         }
+        Ok(())
     }
 
-    /// Reads the default vault config file.
-    fn read_from_file() -> Result<Config> {
-        let path = dirs()?.config_dir().join(CONFIG_FILE);
+    /// Reads the default vault config file, returning `None` if there isn't one.
+    fn read_from_file() -> Result<Option<Config>, ConfigError> {
+        let path = match Self::file_path() {
+            Ok(path) => path,
+            Err(error) => {
+                trace!("No config file location available: {}", error);
+                return Ok(None);
+            }
+        };
         let file = match File::open(&path) {
             Ok(file) => {
                 trace!("Reading settings from {}", path.display());
                 file
             }
-            Err(error) => {
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => {
                 trace!("No config file available at {}", path.display());
-                return Err(error.into());
+                return Ok(None);
+            }
+            Err(error) => {
+                return Err(ConfigError::new(
+                    path.display().to_string(),
+                    error.to_string(),
+                ))
             }
         };
         let reader = BufReader::new(file);
-        let config = serde_json::from_reader(reader)?;
-        Ok(config)
+        serde_json::from_reader(reader)
+            .map(Some)
+            .map_err(|error| ConfigError::new(path.display().to_string(), error.to_string()))
     }
 }
 
+fn parse<T>(arg: &str, value: &str) -> Result<T, ConfigError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value
+        .parse()
+        .map_err(|error| ConfigError::new(arg, format!("invalid value {:?}: {}", value, error)))
+}
+
 /// Writes a Vault config file **for use by tests and examples**.
 ///
 /// The file is written to the `current_bin_dir()` with the appropriate file name.
@@ -260,7 +867,7 @@ mod test {
     use serde_json;
     #[cfg(not(feature = "mock"))]
     use std::mem;
-    use std::{fs::File, io::Read, path::Path};
+    use std::{ffi::OsString, fs::File, io::Read, path::Path};
     #[cfg(not(feature = "mock"))]
     use structopt::StructOpt;
     use unwrap::unwrap;
@@ -269,9 +876,9 @@ mod test {
     #[test]
     fn smoke() {
         let expected_size = if cfg!(target_pointer_width = "64") {
-            240
+            440
         } else {
-            152
+            272
         };
         assert_eq!(
             expected_size,
@@ -299,6 +906,23 @@ mod test {
             ["keep-alive-interval-msec", "1"],
             ["our-complete-cert", cert_str.as_str()],
             ["our-type", "client"],
+            ["max-connections-per-client", "1"],
+            ["client-rate-limit", "1"],
+            ["ip-rate-limit", "1"],
+            ["snapshot-dir", "dir"],
+            ["snapshot-interval-secs", "1"],
+            ["snapshot-retention", "1"],
+            ["control-port", "1"],
+            ["metrics-port", "1"],
+            ["json-logs", "None"],
+            ["check-config", "None"],
+            ["disable-auto-update", "None"],
+            ["daemon", "None"],
+            ["log-dir", "dir"],
+            ["challenge-timeout-secs", "1"],
+            ["max-client-candidates", "1"],
+            ["max-client-candidates-per-ip", "1"],
+            ["export-dir", "dir"],
         ];
 
         for arg in &ARGS {
@@ -317,18 +941,105 @@ mod test {
                 max_capacity: None,
                 root_dir: None,
                 verbose: 0,
+                max_connections_per_client: None,
+                client_rate_limit: None,
+                ip_rate_limit: None,
+                snapshot_dir: None,
+                snapshot_interval_secs: None,
+                snapshot_retention: None,
+                control_port: None,
+                metrics_port: None,
+                json_logs: false,
+                check_config: false,
+                disable_auto_update: false,
+                daemon: false,
+                log_dir: None,
+                challenge_timeout_secs: None,
+                max_client_candidates: None,
+                max_client_candidates_per_ip: None,
+                export_dir: None,
                 quic_p2p_config: Default::default(),
             };
             let empty_config = config.clone();
             if let Some(val) = matches.value_of(arg) {
-                config.set_value(arg, val);
+                unwrap!(config.set_value(arg, val));
             } else {
-                config.set_flag(arg, occurrences);
+                unwrap!(config.set_flag(arg, occurrences));
             }
             assert!(empty_config != config, "Failed to set_value() for {}", arg);
         }
     }
 
+    #[test]
+    fn invalid_values_are_reported() {
+        let mut config = Config::default();
+        let error = config.set_value("port", "not a port").unwrap_err();
+        assert_eq!(error.setting, "port");
+        assert!(config.set_value("no-such-arg", "1").is_err());
+        assert!(config.set_flag("no-such-flag", 1).is_err());
+
+        unwrap!(config.set_value("max-capacity", "0"));
+        unwrap!(config.set_value("snapshot-retention", "0"));
+        let invalid: Vec<_> = config
+            .validate()
+            .into_iter()
+            .map(|error| error.setting)
+            .collect();
+        assert_eq!(invalid, vec!["max-capacity", "snapshot-retention"]);
+    }
+
+    #[test]
+    fn env_vars_override_settings() {
+        let vars = vec![
+            ("PATH", "/bin"),
+            ("SAFE_VAULT_MAX_CAPACITY", "10"),
+            ("SAFE_VAULT_VERBOSE", "2"),
+            ("SAFE_VAULT_JSON_LOGS", "true"),
+            ("SAFE_VAULT_PORT", "not a port"),
+            ("SAFE_VAULT_NO_SUCH_SETTING", "1"),
+            ("SAFE_VAULT_CHECK_CONFIG", "true"),
+        ];
+        let mut config = Config::default();
+        let errors = config.apply_env_vars(
+            vars.into_iter()
+                .map(|(name, value)| (OsString::from(name), OsString::from(value))),
+        );
+
+        assert_eq!(config.max_capacity(), 10);
+        assert_eq!(config.verbose, 2);
+        assert!(config.json_logs());
+        assert!(!config.check_config());
+        let invalid: Vec<_> = errors.into_iter().map(|error| error.setting).collect();
+        assert_eq!(
+            invalid,
+            vec![
+                "SAFE_VAULT_PORT",
+                "SAFE_VAULT_NO_SUCH_SETTING",
+                "SAFE_VAULT_CHECK_CONFIG"
+            ]
+        );
+    }
+
+    #[test]
+    fn changes_are_split_into_live_and_restart() {
+        let old = Config::default();
+        let mut new = old.clone();
+        assert_eq!(old.changes(&new), (vec![], vec![]));
+
+        new.max_capacity = Some(1);
+        new.verbose = 2;
+        new.control_port = Some(1);
+        // `old` was started without `--verbose`, so its log level can't change until restarted.
+        assert_eq!(
+            old.changes(&new),
+            (vec!["max-capacity"], vec!["verbose", "control-port"])
+        );
+
+        let mut verbose = old.clone();
+        verbose.verbose = 1;
+        assert_eq!(verbose.changes(&new).0, vec!["max-capacity", "verbose"]);
+    }
+
     #[ignore]
     #[test]
     fn parse_sample_config_file() {
@@ -0,0 +1,121 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+//! Replies to the `Command`s which query a running vault, and the token which the binary's control
+//! listener requires before passing a command on.
+
+use crate::Result;
+use safe_nd::PublicId;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+/// Name of the file in the root directory holding the control token.
+pub(crate) const TOKEN_FILENAME: &str = "control.token";
+
+/// Writes a new random token to `control.token` in `root_dir`, readable only by the current user
+/// on Unix, and returns it.  Any previous token is replaced.
+///
+/// The caller should hold the root directory's lock, i.e. have started the vault, so that a second
+/// vault failing to start doesn't replace the token of the running one.
+pub fn create_control_token(root_dir: &Path) -> Result<String> {
+    let token = hex::encode(rand::random::<[u8; 32]>());
+    let path = root_dir.join(TOKEN_FILENAME);
+    // Permissions only apply to newly created files, so never reuse an existing one.
+    if let Err(error) = fs::remove_file(&path) {
+        if error.kind() != io::ErrorKind::NotFound {
+            return Err(error.into());
+        }
+    }
+    let mut options = OpenOptions::new();
+    let _ = options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        let _ = options.mode(0o600);
+    }
+    let mut file = options.open(&path)?;
+    file.write_all(token.as_bytes())?;
+    file.sync_all()?;
+    Ok(token)
+}
+
+/// Role of the vault in its section.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum Role {
+    /// Handles clients and data.
+    Elder,
+    /// Holds chunks only.
+    Adult,
+}
+
+/// A client connected to the vault.
+#[derive(Clone, Debug, Serialize)]
+pub struct ClientStatus {
+    /// Address of the connection.
+    pub peer_addr: SocketAddr,
+    /// Identity the client authenticated as.
+    pub public_id: PublicId,
+}
+
+/// Snapshot of a running vault's state, in reply to `Command::Status`.
+#[derive(Clone, Debug, Serialize)]
+pub struct Status {
+    /// Role of the vault.
+    pub role: Role,
+    /// Clients which have answered our challenge.
+    pub connected_clients: Vec<ClientStatus>,
+    /// Connections which haven't answered our challenge yet.
+    pub client_candidates: usize,
+    /// Space used in bytes, per chunk store.
+    pub used_space: BTreeMap<String, u64>,
+    /// Client requests forwarded on and still awaiting a response.
+    pub pending_client_requests: usize,
+    /// ImmutableData operations awaiting responses from the chunk holders.
+    pub pending_idata_ops: usize,
+}
+
+/// How the vault stopped, returned by `Vault::run`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum ShutdownStatus {
+    /// Every operation in progress completed, and the state on disk was flushed.
+    Drained,
+    /// Operations were still in progress when the drain timed out or the network stopped.  The
+    /// state on disk was flushed.
+    Incomplete {
+        /// Client requests which didn't get a response.
+        pending_client_requests: usize,
+        /// ImmutableData operations which didn't conclude.
+        pending_idata_ops: usize,
+    },
+    /// The state on disk couldn't be flushed.
+    FlushFailed,
+}
+
+/// Outcome of `Command::ReloadConfig`.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ReloadReport {
+    /// Changed settings which were applied.
+    pub applied: Vec<&'static str>,
+    /// Changed settings which only take effect after a restart, e.g. the listening port.
+    pub needs_restart: Vec<&'static str>,
+}
+
+/// Outcome of `Command::Scrub`.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ScrubReport {
+    /// Number of chunk files checked.
+    pub checked: u64,
+    /// Chunk files which don't hold a valid chunk under the expected name.  They're left in place.
+    pub invalid: Vec<PathBuf>,
+}
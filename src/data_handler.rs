@@ -7,25 +7,29 @@
 // permissions and limitations relating to use of the SAFE Network Software.
 
 mod adata_handler;
+mod gc;
 mod idata_handler;
 mod idata_holder;
 mod idata_op;
 mod mdata_handler;
 
-use crate::{action::Action, rpc::Rpc, vault::Init, Config, Result};
+use crate::{action::Action, log_context, rpc::Rpc, vault::Init, Config, Result};
 use adata_handler::ADataHandler;
+use gc::{GarbageCollector, Orphan};
 use idata_handler::IDataHandler;
 use idata_holder::IDataHolder;
 use idata_op::{IDataOp, OpType};
-use log::{error, trace};
+use log::{error, info, trace};
 use mdata_handler::MDataHandler;
 
 use safe_nd::{IData, IDataAddress, MessageId, NodePublicId, PublicId, Request, Response, XorName};
 
 use std::{
     cell::Cell,
+    collections::BTreeSet,
     fmt::{self, Display, Formatter},
     rc::Rc,
+    time::Instant,
 };
 
 pub(crate) struct DataHandler {
@@ -34,6 +38,7 @@ pub(crate) struct DataHandler {
     idata_holder: IDataHolder,
     mdata_handler: MDataHandler,
     adata_handler: ADataHandler,
+    gc: GarbageCollector,
 }
 
 impl DataHandler {
@@ -53,10 +58,99 @@ impl DataHandler {
             idata_holder,
             mdata_handler,
             adata_handler,
+            gc: GarbageCollector::new(Instant::now()),
         })
     }
 
+    pub fn handle_timeout(&mut self, now: Instant) {
+        if self.gc.is_scan_due(now) {
+            self.collect_garbage(now);
+        }
+    }
+
+    /// Applies the settings from `config` which can change while running.
+    pub fn apply_config(&mut self, config: &Config) {
+        let max_capacity = config.max_capacity();
+        self.idata_holder.set_max_capacity(max_capacity);
+        self.mdata_handler.set_max_capacity(max_capacity);
+        self.adata_handler.set_max_capacity(max_capacity);
+    }
+
+    pub fn pending_idata_op_count(&self) -> usize {
+        self.idata_handler.idata_ops().len()
+    }
+
+    /// Describes the ImmutableData operations in progress and the orphans awaiting reclamation, for
+    /// diagnostics.
+    pub fn diagnostics(&self) -> String {
+        let idata_ops = self.idata_handler.idata_ops();
+        let mut diagnostics = format!("Pending ImmutableData operations: {}\n", idata_ops.len());
+        for (message_id, idata_op) in idata_ops {
+            diagnostics.push_str(&format!(
+                "  {:?} {:?} for {:?}\n",
+                message_id,
+                idata_op.op_type(),
+                idata_op.client()
+            ));
+        }
+        diagnostics.push_str(&format!(
+            "Orphans awaiting reclamation: {}\n",
+            self.gc.suspect_count()
+        ));
+        diagnostics
+    }
+
+    // Scans for immutable chunk metadata pointing at us while we don't hold the chunk, and for
+    // chunks we hold which have no metadata, reclaiming the ones which have stayed orphaned for the
+    // grace period.
+    //
+    // A chunk without local metadata is only an orphan if we're the sole metadata holder for its
+    // address; otherwise other elders may still have metadata for it, so it's only reported.
+    //
+    // Only immutable data is covered.  Mutable, append-only and login packet chunks have no
+    // metadata to check against, so they're only ever removed by an explicit delete.
+    fn collect_garbage(&mut self, now: Instant) {
+        let our_name = *self.id.name();
+        let mut found = BTreeSet::new();
+        let mut addresses_with_metadata = BTreeSet::new();
+        for (address, holders) in self.idata_handler.chunk_holders() {
+            if holders.contains(&our_name) && !self.idata_holder.has(&address) {
+                let _ = found.insert(Orphan::Metadata {
+                    address,
+                    holder: our_name,
+                });
+            }
+            let _ = addresses_with_metadata.insert(address);
+        }
+        for address in self.idata_holder.addresses() {
+            if addresses_with_metadata.contains(&address) {
+                continue;
+            }
+            if self.idata_handler.is_sole_metadata_holder(&address) {
+                let _ = found.insert(Orphan::Chunk(address));
+            } else {
+                info!(
+                    "{}: Holding chunk {:?} without local metadata, not reclaiming it as other \
+                     elders may hold its metadata",
+                    self, address
+                );
+            }
+        }
+        trace!("{}: GC scan found {} orphans", self, found.len());
+
+        for orphan in self.gc.handle_scan(found, now) {
+            info!("{}: Reclaiming orphan {:?}", self, orphan);
+            match orphan {
+                Orphan::Metadata { address, holder } => {
+                    self.idata_handler.remove_holder(&address, &holder)
+                }
+                Orphan::Chunk(address) => self.idata_holder.reclaim(&address),
+            }
+        }
+    }
+
     pub fn handle_vault_rpc(&mut self, src: XorName, rpc: Rpc) -> Option<Action> {
+        let _log_context = log_context::enter_rpc(&rpc);
         match rpc {
             Rpc::Request {
                 request,
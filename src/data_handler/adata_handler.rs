@@ -52,6 +52,10 @@ impl ADataHandler {
         Ok(Self { id, chunks })
     }
 
+    pub(super) fn set_max_capacity(&mut self, max_capacity: u64) {
+        self.chunks.set_max_capacity(max_capacity);
+    }
+
     pub(super) fn handle_put_adata_req(
         &mut self,
         requester: PublicId,
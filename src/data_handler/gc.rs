@@ -0,0 +1,111 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use log::warn;
+use safe_nd::{IDataAddress, XorName};
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::{Duration, Instant},
+};
+
+/// How often the stores are scanned for orphans.
+const GC_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// How long something has to stay orphaned before it's reclaimed.  This is much longer than any
+/// `IDataOp` should take, so chunks and metadata of in-progress Puts aren't mistaken for orphans.
+const GC_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// Something left behind by a failed or partial operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(super) enum Orphan {
+    /// Metadata registering `holder` for a chunk it doesn't hold.
+    Metadata {
+        address: IDataAddress,
+        holder: XorName,
+    },
+    /// A stored chunk without any metadata pointing at it.  Only reported as such when the vault is
+    /// the sole metadata holder for the chunk's address.
+    Chunk(IDataAddress),
+}
+
+/// Tracks orphans across scans, and decides when they can be reclaimed.
+///
+/// Orphans are only reclaimed if they've been seen in every scan since first being found, for at
+/// least `GC_GRACE_PERIOD`.  Tracking is in memory only, so a restart resets the grace period.
+pub(super) struct GarbageCollector {
+    next_scan: Instant,
+    // Orphans found by the last scan, mapped to when they were first found.
+    suspects: HashMap<Orphan, Instant>,
+}
+
+impl GarbageCollector {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            next_scan: now + GC_INTERVAL,
+            suspects: HashMap::new(),
+        }
+    }
+
+    /// Returns true if the stores are due a scan.
+    pub fn is_scan_due(&self, now: Instant) -> bool {
+        now >= self.next_scan
+    }
+
+    /// Returns the number of orphans awaiting the end of their grace period.
+    pub fn suspect_count(&self) -> usize {
+        self.suspects.len()
+    }
+
+    /// Records the orphans `found` by a scan at `now`, and returns the ones whose grace period has
+    /// elapsed.  Those are forgotten, so they should be reclaimed by the caller.
+    pub fn handle_scan(&mut self, found: BTreeSet<Orphan>, now: Instant) -> Vec<Orphan> {
+        self.next_scan = now + GC_INTERVAL;
+        self.suspects.retain(|orphan, _| found.contains(orphan));
+        for orphan in found {
+            let _ = self.suspects.entry(orphan).or_insert_with(|| {
+                warn!("Found orphan {:?}", orphan);
+                now
+            });
+        }
+
+        let expired: Vec<_> = self
+            .suspects
+            .iter()
+            .filter(|(_, found_at)| now.duration_since(**found_at) >= GC_GRACE_PERIOD)
+            .map(|(orphan, _)| *orphan)
+            .collect();
+        for orphan in &expired {
+            let _ = self.suspects.remove(orphan);
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orphans_are_reclaimed_after_grace_period() {
+        let now = Instant::now();
+        let mut gc = GarbageCollector::new(now);
+        assert!(!gc.is_scan_due(now));
+        assert!(gc.is_scan_due(now + GC_INTERVAL));
+
+        let orphan = Orphan::Chunk(IDataAddress::Pub(rand::random()));
+        let transient = Orphan::Chunk(IDataAddress::Pub(rand::random()));
+        let found = vec![orphan, transient].into_iter().collect();
+        assert!(gc.handle_scan(found, now).is_empty());
+
+        // `transient` is no longer orphaned, e.g. its Put completed, so it's forgotten.
+        let found = vec![orphan].into_iter().collect();
+        assert!(gc.handle_scan(found, now + GC_INTERVAL).is_empty());
+
+        let found = vec![orphan, transient].into_iter().collect();
+        assert_eq!(gc.handle_scan(found, now + GC_GRACE_PERIOD), vec![orphan]);
+    }
+}
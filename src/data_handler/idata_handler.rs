@@ -7,7 +7,7 @@
 // permissions and limitations relating to use of the SAFE Network Software.
 
 use super::{IDataOp, OpType};
-use crate::{action::Action, rpc::Rpc, utils, vault::Init, Config, Result, ToDbKey};
+use crate::{action::Action, from_db_key, rpc::Rpc, utils, vault::Init, Config, Result, ToDbKey};
 use log::{trace, warn};
 use pickledb::PickleDb;
 use safe_nd::{
@@ -363,6 +363,47 @@ impl IDataHandler {
         action
     }
 
+    /// Returns the addresses of all chunks with metadata, along with their registered holders.
+    pub(super) fn chunk_holders(&self) -> Vec<(IDataAddress, BTreeSet<XorName>)> {
+        self.metadata
+            .get_all()
+            .into_iter()
+            .filter_map(|db_key| {
+                let address = from_db_key(&db_key)?;
+                let metadata = self.metadata.get::<ChunkMetadata>(&db_key)?;
+                Some((address, metadata.holders))
+            })
+            .collect()
+    }
+
+    /// Returns true if we're the only elder holding metadata for chunks at `address`, in which case
+    /// a chunk without metadata here has none anywhere.
+    pub(super) fn is_sole_metadata_holder(&self, address: &IDataAddress) -> bool {
+        let our_name = self.id.name();
+        self.elders_sorted(address.name())
+            .all(|elder| elder == our_name)
+    }
+
+    /// Unregisters `holder` of the chunk at `address`, removing the metadata if no holders remain.
+    pub(super) fn remove_holder(&mut self, address: &IDataAddress, holder: &XorName) {
+        let db_key = address.to_db_key();
+        let mut metadata = match self.metadata.get::<ChunkMetadata>(&db_key) {
+            Some(metadata) => metadata,
+            None => return,
+        };
+        if !metadata.holders.remove(holder) {
+            return;
+        }
+        let result = if metadata.holders.is_empty() {
+            self.metadata.rem(&db_key).map(|_| ())
+        } else {
+            self.metadata.set(&db_key, &metadata)
+        };
+        if let Err(error) = result {
+            warn!("{}: Failed to update metadata in DB: {:?}", self, error);
+        }
+    }
+
     fn get_metadata_for(&self, address: IDataAddress) -> NdResult<ChunkMetadata> {
         match self.metadata.get::<ChunkMetadata>(&address.to_db_key()) {
             Some(metadata) => {
@@ -380,6 +421,10 @@ impl IDataHandler {
         }
     }
 
+    pub(super) fn idata_ops(&self) -> &BTreeMap<MessageId, IDataOp> {
+        &self.idata_ops
+    }
+
     pub(super) fn idata_op(&self, message_id: &MessageId) -> Option<&IDataOp> {
         self.idata_ops.get(message_id).or_else(|| {
             warn!(
@@ -42,6 +42,26 @@ impl IDataHolder {
         Ok(Self { id, chunks })
     }
 
+    pub(super) fn set_max_capacity(&mut self, max_capacity: u64) {
+        self.chunks.set_max_capacity(max_capacity);
+    }
+
+    /// Returns the addresses of all stored chunks.
+    pub(super) fn addresses(&self) -> Vec<IDataAddress> {
+        self.chunks.keys()
+    }
+
+    pub(super) fn has(&self, address: &IDataAddress) -> bool {
+        self.chunks.has(address)
+    }
+
+    /// Deletes the chunk at `address` without any permission checks, e.g. to reclaim an orphan.
+    pub(super) fn reclaim(&mut self, address: &IDataAddress) {
+        if let Err(error) = self.chunks.delete(address) {
+            error!("{}: Failed to delete chunk {:?}: {}", self, address, error);
+        }
+    }
+
     pub(super) fn store_idata(
         &mut self,
         kind: IData,
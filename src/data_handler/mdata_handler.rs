@@ -50,6 +50,10 @@ impl MDataHandler {
         Ok(Self { id, chunks })
     }
 
+    pub(super) fn set_max_capacity(&mut self, max_capacity: u64) {
+        self.chunks.set_max_capacity(max_capacity);
+    }
+
     /// Get `MData` from the chunk store and check permissions.
     /// Returns `Some(Result<..>)` if the flow should be continued, returns
     /// `None` if there was a logic error encountered and the flow should be
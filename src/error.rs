@@ -6,11 +6,11 @@
 // KIND, either express or implied. Please review the Licences for the specific language governing
 // permissions and limitations relating to use of the SAFE Network Software.
 
-use crate::{chunk_store, quic_p2p};
+use crate::{chunk_store, config_handler::ConfigError, quic_p2p};
 use quick_error::quick_error;
 use safe_nd::{self, Request, Response};
 use serde_json;
-use std::io;
+use std::{io, path::PathBuf};
 
 quick_error! {
     #[allow(clippy::large_enum_variant)]
@@ -83,6 +83,37 @@ quick_error! {
         NoSuchAccount {}
         /// Logic error.
         Logic {}
+        /// Archive is malformed, fails verification or can't be imported.
+        InvalidArchive(reason: String) {
+            display("Invalid archive: {}", reason)
+        }
+        /// An export was requested while exports are disabled, or to a path outside the export
+        /// directory.
+        InvalidExportPath(reason: String) {
+            display("Cannot export: {}", reason)
+        }
+        /// Settings from the config file or command line which are invalid, all of them rather than
+        /// just the first.
+        InvalidConfig(errors: Vec<ConfigError>) {
+            display("Invalid config: {}", errors
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("; "))
+        }
+        /// A release of the vault couldn't be fetched, verified or installed.
+        InvalidUpdate(reason: String) {
+            display("Update failed: {}", reason)
+        }
+        /// Another vault is using the root directory.
+        RootDirInUse(root_dir: PathBuf) {
+            display("Root directory {} is in use by another vault", root_dir.display())
+        }
+        /// The root directory was written by a newer vault, in a format we don't understand.
+        UnsupportedFormatVersion(found: u32, supported: u32) {
+            display("Vault directory format version {} is newer than the supported version {}. \
+                     Upgrade the vault or use a different root directory.", found, supported)
+        }
     }
 }
 
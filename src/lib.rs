@@ -58,19 +58,31 @@
 #![recursion_limit = "128"]
 
 mod action;
+mod admin;
 mod adult;
+mod archive;
 mod chunk_store;
 mod client_handler;
 mod coins_handler;
 mod config_handler;
+mod control;
 mod data_handler;
 mod error;
+mod log_context;
+mod log_file;
+mod manifest;
+mod messages;
+mod metrics;
+mod root_dir_lock;
 mod rpc;
+mod snapshot;
 mod to_db_key;
+mod updater;
 mod utils;
 mod vault;
+mod verbosity;
 
-pub(crate) use to_db_key::ToDbKey;
+pub(crate) use to_db_key::{from_db_key, ToDbKey};
 
 /// Utilities for testing.
 #[cfg(feature = "mock")]
@@ -83,9 +95,33 @@ pub use self::mock::quic_p2p;
 pub use quic_p2p;
 
 pub use crate::{
+    admin::{
+        chunk_store_usage, client_account, node_identity, recompute_used_space, verify_chunks,
+        AdminArgs, AdminCommand, ChunkStoreUsage, ClientAccount, NodeIdentity,
+    },
+    archive::import_archive,
     chunk_store::error::Error as ChunkStoreError,
-    client_handler::COST_OF_PUT,
-    config_handler::Config,
+    client_handler::{
+        AppRestrictions, AuditEntry, AuditEvent, MultisigPolicy, RecoveryConfig, TransferProposal,
+        COST_OF_PUT, MAX_BUFFERED_RESPONSES, SESSION_GRACE_PERIOD,
+    },
+    config_handler::{Config, ConfigError},
+    control::{
+        create_control_token, ClientStatus, ReloadReport, Role, ScrubReport, ShutdownStatus, Status,
+    },
     error::{Error, Result},
+    log_context::LogContext,
+    log_file::RotatingLogFile,
+    messages::{
+        SessionToken, VaultMessage, VaultNotification, VaultRequest, VaultResponse,
+        VAULT_MESSAGE_TAG,
+    },
+    root_dir_lock::PID_FILENAME,
+    snapshot::{list_snapshots, restore_snapshot},
+    updater::{
+        install_latest_release, pinned_signing_key, release_manifest, GithubReleases,
+        LocalReleases, Release, ReleaseSource, UpdateStatus,
+    },
     vault::{Command, Vault},
+    verbosity::{set_verbosity, verbosity_allows},
 };
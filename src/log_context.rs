@@ -0,0 +1,179 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+//! Structured fields describing the request being handled, for log formatters.
+//!
+//! The handlers set these on entry, so that every line logged while handling a client request,
+//! including its hops between handlers as `Rpc`s, carries the same `MessageId`.
+
+use crate::{rpc::Rpc, utils};
+use safe_nd::{MessageId, PublicId, Request, XorName};
+use serde::Serialize;
+use std::cell::RefCell;
+
+thread_local! {
+    static CURRENT: RefCell<LogContext> = RefCell::new(LogContext::default());
+}
+
+/// Fields describing what the vault is doing on this thread, for structured logging.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct LogContext {
+    /// Name of the vault.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node: Option<String>,
+    /// ID of the client request being handled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    /// Name of the client which sent the request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requester: Option<String>,
+    /// Type of the request, e.g. "PutIData".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_kind: Option<&'static str>,
+    /// "Success", or the type of error, once the response is known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<String>,
+}
+
+impl LogContext {
+    /// Returns the fields for this thread.
+    pub fn current() -> Self {
+        CURRENT.with(|current| current.borrow().clone())
+    }
+}
+
+/// Restores the previous request fields when dropped.
+#[must_use]
+pub(crate) struct ContextGuard(LogContext);
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        let previous = std::mem::replace(&mut self.0, LogContext::default());
+        CURRENT.with(|current| {
+            let mut current = current.borrow_mut();
+            let node = current.node.take();
+            *current = LogContext { node, ..previous };
+        });
+    }
+}
+
+/// Sets the name of the vault running on this thread.
+pub(crate) fn set_node(name: &XorName) {
+    CURRENT.with(|current| current.borrow_mut().node = Some(name.to_string()));
+}
+
+/// Sets the request fields until the returned guard is dropped.
+pub(crate) fn enter_request(
+    message_id: MessageId,
+    requester: &PublicId,
+    request: &Request,
+) -> ContextGuard {
+    enter(message_id, requester, Some(utils::request_kind(request)))
+}
+
+/// Sets the request fields from `rpc` until the returned guard is dropped.  The request type is
+/// only known for `Rpc::Request`, and the outcome only for `Rpc::Response` and `Rpc::Refund`.
+pub(crate) fn enter_rpc(rpc: &Rpc) -> ContextGuard {
+    match rpc {
+        Rpc::Request {
+            request,
+            requester,
+            message_id,
+        } => enter_request(*message_id, requester, request),
+        Rpc::Response {
+            response,
+            requester,
+            message_id,
+        } => {
+            let guard = enter(*message_id, requester, None);
+            set_outcome(utils::response_error(response).map(utils::error_kind));
+            guard
+        }
+        Rpc::Refund {
+            requester,
+            reason,
+            message_id,
+            ..
+        } => {
+            let guard = enter(*message_id, requester, None);
+            set_outcome(Some(utils::error_kind(reason)));
+            guard
+        }
+    }
+}
+
+/// Records the outcome of the current request: `None` for success, otherwise the error type.
+pub(crate) fn set_outcome(error_kind: Option<&str>) {
+    let outcome = error_kind.unwrap_or("Success").to_string();
+    CURRENT.with(|current| current.borrow_mut().outcome = Some(outcome));
+}
+
+fn enter(
+    message_id: MessageId,
+    requester: &PublicId,
+    request_kind: Option<&'static str>,
+) -> ContextGuard {
+    CURRENT.with(|current| {
+        let mut current = current.borrow_mut();
+        let new = LogContext {
+            node: current.node.clone(),
+            message_id: Some(format!("{:?}", message_id)),
+            requester: Some(requester.name().to_string()),
+            request_kind,
+            outcome: None,
+        };
+        ContextGuard(std::mem::replace(&mut *current, new))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use safe_nd::{ClientFullId, Coins, Error as NdError, IDataAddress};
+    use unwrap::unwrap;
+
+    #[test]
+    fn fields_are_restored_when_leaving_request() {
+        set_node(&rand::random());
+        let node = LogContext::current().node;
+        assert!(node.is_some());
+
+        let requester = PublicId::Client(
+            ClientFullId::new_ed25519(&mut rand::thread_rng())
+                .public_id()
+                .clone(),
+        );
+        let request = Request::GetIData(IDataAddress::Pub(rand::random()));
+        let outer_id = MessageId::new();
+        {
+            let _guard = enter_request(outer_id, &requester, &request);
+            {
+                let _guard = enter_rpc(&Rpc::Refund {
+                    requester: requester.clone(),
+                    amount: unwrap!(Coins::from_nano(1)),
+                    transaction_id: 0,
+                    reason: NdError::InsufficientBalance,
+                    message_id: MessageId::new(),
+                });
+                let current = LogContext::current();
+                assert_eq!(current.outcome, Some("InsufficientBalance".to_string()));
+                assert_eq!(current.request_kind, None);
+            }
+
+            let current = LogContext::current();
+            assert_eq!(current.node, node);
+            assert_eq!(current.message_id, Some(format!("{:?}", outer_id)));
+            assert_eq!(current.request_kind, Some("GetIData"));
+            assert_eq!(current.outcome, None);
+        }
+
+        let current = LogContext::current();
+        assert_eq!(current.node, node);
+        assert_eq!(current.message_id, None);
+    }
+}
@@ -0,0 +1,128 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+//! Log output for vaults running without a terminal, e.g. as a daemon.
+
+use crate::Result;
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// A log file which is rotated once it reaches a maximum size.
+///
+/// When rotated, `<name>` is renamed to `<name>.1`, `<name>.1` to `<name>.2` and so on, and the
+/// oldest file beyond the limit is deleted.
+pub struct RotatingLogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_old_files: usize,
+}
+
+impl RotatingLogFile {
+    /// Opens `dir/name` for appending, creating `dir` if needed.  The file is rotated before a
+    /// write which would take it over `max_size` bytes, keeping at most `max_old_files` rotated
+    /// files.
+    pub fn open<P: AsRef<Path>>(
+        dir: P,
+        name: &str,
+        max_size: u64,
+        max_old_files: usize,
+    ) -> Result<Self> {
+        fs::create_dir_all(dir.as_ref())?;
+        let path = dir.as_ref().join(name);
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_size,
+            max_old_files,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.max_old_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            // Renaming onto an existing file fails on Windows, so make room first.
+            remove_if_exists(&self.old_file_path(self.max_old_files))?;
+            for index in (1..self.max_old_files).rev() {
+                let path = self.old_file_path(index);
+                if path.exists() {
+                    fs::rename(path, self.old_file_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.old_file_path(1))?;
+        }
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn old_file_path(&self, index: usize) -> PathBuf {
+        let mut path = OsString::from(&self.path);
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+}
+
+impl Write for RotatingLogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size.saturating_add(buf.len() as u64) > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(ref error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+    use unwrap::unwrap;
+
+    #[test]
+    fn rotates_and_keeps_limited_old_files() {
+        let temp_dir = unwrap!(TempDir::new("log_file"));
+        let mut log_file = unwrap!(RotatingLogFile::open(temp_dir.path(), "vault.log", 10, 2));
+        for line in &["first\n", "second\n", "third\n", "fourth\n"] {
+            unwrap!(log_file.write_all(line.as_bytes()));
+        }
+        unwrap!(log_file.flush());
+
+        let read = |name: &str| unwrap!(fs::read_to_string(temp_dir.path().join(name)));
+        assert_eq!(read("vault.log"), "fourth\n");
+        assert_eq!(read("vault.log.1"), "third\n");
+        assert_eq!(read("vault.log.2"), "second\n");
+        assert!(!temp_dir.path().join("vault.log.3").exists());
+    }
+}
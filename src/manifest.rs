@@ -0,0 +1,140 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+//! Versioning of the on-disk format of the vault's root directory.
+//!
+//! The root directory holds a JSON manifest recording the format version of everything else in
+//! it: the state file, chunk files, DBs and their keys.  Whenever that format changes, e.g. because
+//! a `safe-nd` type changes its serialisation, `CURRENT_FORMAT_VERSION` must be bumped and a
+//! migration from the previous version appended to `MIGRATIONS`.
+
+use crate::{Error, Result};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+const MANIFEST_FILENAME: &str = "manifest.json";
+/// Format version of vault directories written by this version of the vault.
+pub(crate) const CURRENT_FORMAT_VERSION: u32 = 1;
+
+type Migration = fn(&Path) -> Result<()>;
+
+/// `MIGRATIONS[n]` upgrades a vault directory from format version `n` to `n + 1`.
+const MIGRATIONS: [Migration; CURRENT_FORMAT_VERSION as usize] = [migrate_v0_to_v1];
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    format_version: u32,
+    // Version of the vault which last wrote the directory, for diagnostics only.
+    vault_version: String,
+}
+
+/// Brings an existing vault directory up to `CURRENT_FORMAT_VERSION`, running the required
+/// migrations in order.
+///
+/// Returns `Error::UnsupportedFormatVersion` if the directory was written by a newer vault.  The
+/// manifest is updated after each migration, so an interrupted upgrade resumes where it stopped.
+pub(crate) fn migrate(root_dir: &Path) -> Result<()> {
+    let mut format_version = read_format_version(root_dir)?;
+    if format_version > CURRENT_FORMAT_VERSION {
+        return Err(Error::UnsupportedFormatVersion(
+            format_version,
+            CURRENT_FORMAT_VERSION,
+        ));
+    }
+
+    while format_version < CURRENT_FORMAT_VERSION {
+        info!(
+            "Migrating vault directory {} from format version {} to {}",
+            root_dir.display(),
+            format_version,
+            format_version + 1
+        );
+        MIGRATIONS[format_version as usize](root_dir)?;
+        format_version += 1;
+        write_format_version(root_dir, format_version)?;
+    }
+    Ok(())
+}
+
+/// Returns true if the directory has a manifest, whether or not it holds anything else yet.
+pub(crate) fn exists(root_dir: &Path) -> bool {
+    root_dir.join(MANIFEST_FILENAME).is_file()
+}
+
+/// Records that the vault directory is in the current format.
+pub(crate) fn write(root_dir: &Path) -> Result<()> {
+    write_format_version(root_dir, CURRENT_FORMAT_VERSION)
+}
+
+// Returns the format version of the directory.  Directories without a manifest predate it, and so
+// are version 0.
+fn read_format_version(root_dir: &Path) -> Result<u32> {
+    let path = root_dir.join(MANIFEST_FILENAME);
+    if !path.is_file() {
+        return Ok(0);
+    }
+    let manifest: Manifest = serde_json::from_slice(&fs::read(path)?)?;
+    Ok(manifest.format_version)
+}
+
+fn write_format_version(root_dir: &Path, format_version: u32) -> Result<()> {
+    let manifest = Manifest {
+        format_version,
+        vault_version: env!("CARGO_PKG_VERSION").to_string(),
+    };
+    fs::write(
+        root_dir.join(MANIFEST_FILENAME),
+        serde_json::to_vec_pretty(&manifest)?,
+    )?;
+    Ok(())
+}
+
+// Version 0 is the unversioned layout which predates the manifest.  Version 1 only adds the
+// manifest, so there's nothing to convert.
+fn migrate_v0_to_v1(_root_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+    use unwrap::unwrap;
+
+    #[test]
+    fn unversioned_directory_is_migrated() {
+        let root = unwrap!(TempDir::new("manifest"));
+        assert_eq!(unwrap!(read_format_version(root.path())), 0);
+
+        unwrap!(migrate(root.path()));
+        assert_eq!(
+            unwrap!(read_format_version(root.path())),
+            CURRENT_FORMAT_VERSION
+        );
+    }
+
+    #[test]
+    fn newer_directory_is_refused() {
+        let root = unwrap!(TempDir::new("manifest"));
+        assert!(!exists(root.path()));
+        unwrap!(write_format_version(
+            root.path(),
+            CURRENT_FORMAT_VERSION + 1
+        ));
+        assert!(exists(root.path()));
+
+        match migrate(root.path()) {
+            Err(Error::UnsupportedFormatVersion(found, supported)) => {
+                assert_eq!(found, CURRENT_FORMAT_VERSION + 1);
+                assert_eq!(supported, CURRENT_FORMAT_VERSION);
+            }
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,324 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+//! Messages between clients and the vault for the features `safe_nd::Message` can't carry yet.
+//!
+//! They're exchanged over the same connections as `safe_nd::Message`s, but are serialised with a
+//! leading `VAULT_MESSAGE_TAG` so that either side can tell the two apart.  The tag is never the
+//! start of a serialised `safe_nd::Message`, as that's the index of its variant.
+
+use crate::{
+    client_handler::{
+        AppRestrictions, AuditEntry, MultisigPolicy, RecoveryConfig, TransferProposal,
+    },
+    utils, Error, Result,
+};
+use safe_nd::{
+    Error as NdError, LoginPacket, MessageId, PublicKey, Result as NdResult, Signature,
+    Transaction, XorName,
+};
+use serde::{Deserialize, Serialize};
+
+/// Bytes at the start of every serialised `VaultMessage`.
+pub const VAULT_MESSAGE_TAG: [u8; 4] = *b"SVLT";
+
+/// Error kind, e.g. in metrics, of a request of either protocol rejected for exceeding the rate
+/// limits.
+pub(crate) const RATE_LIMIT_EXCEEDED: &str = "RateLimitExceeded";
+
+/// Token identifying a client session, returned by `VaultRequest::StartSession`.
+pub type SessionToken = u64;
+
+/// A message in the vault's own protocol.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum VaultMessage {
+    /// Request from a client, signed by the client's or app's own key over the serialised
+    /// `(request, message_id, timestamp)`.
+    Request {
+        /// The request.
+        request: VaultRequest,
+        /// ID of the request, unique per signing key.
+        message_id: MessageId,
+        /// Time the request was made, in seconds since the Unix epoch.  Requests too old for the
+        /// vault to still remember their ID are rejected, so they can't be replayed.
+        timestamp: u64,
+        /// Signature of the request, its ID and its timestamp.
+        signature: Signature,
+    },
+    /// Response from the vault to the request with the same `MessageId`.
+    Response {
+        /// The response.
+        response: VaultResponse,
+        /// ID of the request being answered.
+        message_id: MessageId,
+    },
+    /// Notification from the vault.
+    Notification(VaultNotification),
+}
+
+impl VaultMessage {
+    /// Serialises the message, prefixed with `VAULT_MESSAGE_TAG`.
+    pub fn serialise(&self) -> Vec<u8> {
+        let mut bytes = VAULT_MESSAGE_TAG.to_vec();
+        bytes.extend(utils::serialise(self));
+        bytes
+    }
+
+    /// Returns `true` if `bytes` hold a `VaultMessage` rather than a `safe_nd::Message`.
+    pub fn is_vault_message(bytes: &[u8]) -> bool {
+        bytes.starts_with(&VAULT_MESSAGE_TAG)
+    }
+
+    /// Deserialises a message serialised by `serialise`.
+    pub fn deserialise(bytes: &[u8]) -> Result<Self> {
+        if !Self::is_vault_message(bytes) {
+            return Err(Error::InvalidMessage);
+        }
+        Ok(bincode::deserialize(&bytes[VAULT_MESSAGE_TAG.len()..])?)
+    }
+}
+
+/// Requests handled by the client's vault.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VaultRequest {
+    /// Gets the token of the session of this connection and keeps the session for a grace period
+    /// if the connection drops, so that it can be resumed with `ResumeSession`.  Without this, the
+    /// session ends with its connection, and responses to requests sent on it go to any other
+    /// connection of the same client instead.
+    StartSession,
+    /// Takes over the session with the given token, whose connection has dropped.  Responses to
+    /// the requests sent on that connection are delivered on this one instead, starting with those
+    /// produced while disconnected.  Only valid for the same `PublicId`, and within a grace period
+    /// of the drop.
+    ResumeSession(SessionToken),
+    /// Sets the restrictions of the client's authorised app `key`, replacing any previous ones.
+    /// Only valid from the client itself, not from its apps.
+    SetAppRestrictions {
+        /// Key of the app.
+        key: PublicKey,
+        /// The new restrictions.
+        restrictions: AppRestrictions,
+    },
+    /// Gets the client's audit log of auth key changes and app mutations, oldest first.  Only
+    /// valid from the client itself, not from its apps.
+    GetAuditLog,
+    /// Makes transfers out of the client's balance require the approval of `threshold` of the
+    /// policy's owners, via `ApproveMultisigTransfer`.  The policy can't be changed or removed once
+    /// set.  Only valid from the client itself, not from its apps.
+    SetMultisigPolicy(MultisigPolicy),
+    /// Adds the client's approval to a transfer out of a multisig balance it's an owner of.  The
+    /// transfer is executed once enough owners have approved the same proposal within an hour of
+    /// the first approval.  Only valid from the client itself, not from its apps.
+    ApproveMultisigTransfer(TransferProposal),
+    /// Replaces the login packet at its destination, like `safe_nd::Request::UpdateLoginPacket`,
+    /// but only if its current version is still `expected_version`.  Otherwise the update is
+    /// rejected with `InvalidSuccessor` holding the current version, so that concurrent updates
+    /// from different devices can't clobber each other.
+    UpdateLoginPacket {
+        /// The new login packet.
+        login_packet: LoginPacket,
+        /// Version of the login packet the update is based on.
+        expected_version: u64,
+    },
+    /// Gets the current version of the login packet at the given name, along with the superseded
+    /// versions which can still be rolled back to.
+    ListLoginPacketVersions(XorName),
+    /// Makes a superseded version of a login packet the current one again.  The restored packet
+    /// is stored as a new version, so the rollback can itself be undone.
+    RollbackLoginPacket {
+        /// Name of the login packet.
+        name: XorName,
+        /// The superseded version to restore.
+        version: u64,
+    },
+    /// Sets the keys allowed to jointly replace the client's login packet at `name`, cancelling
+    /// any recovery in progress.
+    SetRecoveryConfig {
+        /// Name of the login packet.
+        name: XorName,
+        /// The recovery keys, threshold and delay.
+        config: RecoveryConfig,
+    },
+    /// Adds the client's approval, as one of the recovery keys of the login packet at the new
+    /// packet's destination, to replacing it with the new packet.  Once enough keys have approved
+    /// it, the current owner is sent `VaultNotification::RecoveryApproved` and the replacement
+    /// takes effect after the configured delay unless vetoed.  Until then, the approval replaces
+    /// the key's approval of any other replacement, and expires after a week.
+    ApproveRecovery(LoginPacket),
+    /// Cancels the recovery in progress for the client's login packet at the given name.
+    VetoRecovery(XorName),
+}
+
+impl VaultRequest {
+    /// Returns the response carrying `error`.
+    pub fn error_response(&self, error: NdError) -> VaultResponse {
+        match self {
+            VaultRequest::StartSession => VaultResponse::SessionStarted(Err(error)),
+            VaultRequest::ResumeSession(_)
+            | VaultRequest::SetAppRestrictions { .. }
+            | VaultRequest::SetMultisigPolicy(_)
+            | VaultRequest::SetRecoveryConfig { .. }
+            | VaultRequest::VetoRecovery(_) => VaultResponse::Mutation(Err(error)),
+            VaultRequest::ApproveRecovery(_) => VaultResponse::RecoveryApproval(Err(error)),
+            VaultRequest::GetAuditLog => VaultResponse::GetAuditLog(Err(error)),
+            VaultRequest::ApproveMultisigTransfer(_) => VaultResponse::TransferApproval(Err(error)),
+            VaultRequest::UpdateLoginPacket { .. } | VaultRequest::RollbackLoginPacket { .. } => {
+                VaultResponse::LoginPacketVersion(Err(error))
+            }
+            VaultRequest::ListLoginPacketVersions(_) => {
+                VaultResponse::LoginPacketVersions(Err(error))
+            }
+        }
+    }
+
+    // Name of the request's variant, e.g. for metrics without the payload.
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            VaultRequest::StartSession => "StartSession",
+            VaultRequest::ResumeSession(_) => "ResumeSession",
+            VaultRequest::SetAppRestrictions { .. } => "SetAppRestrictions",
+            VaultRequest::GetAuditLog => "GetAuditLog",
+            VaultRequest::SetMultisigPolicy(_) => "SetMultisigPolicy",
+            VaultRequest::ApproveMultisigTransfer(_) => "ApproveMultisigTransfer",
+            VaultRequest::UpdateLoginPacket { .. } => "UpdateLoginPacket",
+            VaultRequest::ListLoginPacketVersions(_) => "ListLoginPacketVersions",
+            VaultRequest::RollbackLoginPacket { .. } => "RollbackLoginPacket",
+            VaultRequest::SetRecoveryConfig { .. } => "SetRecoveryConfig",
+            VaultRequest::ApproveRecovery(_) => "ApproveRecovery",
+            VaultRequest::VetoRecovery(_) => "VetoRecovery",
+        }
+    }
+
+    // Whether the request may only be sent by a client, not by its apps.
+    pub(crate) fn requires_owner(&self) -> bool {
+        match self {
+            VaultRequest::StartSession
+            | VaultRequest::ResumeSession(_)
+            | VaultRequest::UpdateLoginPacket { .. }
+            | VaultRequest::ListLoginPacketVersions(_)
+            | VaultRequest::RollbackLoginPacket { .. }
+            | VaultRequest::SetRecoveryConfig { .. }
+            | VaultRequest::ApproveRecovery(_)
+            | VaultRequest::VetoRecovery(_) => false,
+            VaultRequest::SetAppRestrictions { .. }
+            | VaultRequest::GetAuditLog
+            | VaultRequest::SetMultisigPolicy(_)
+            | VaultRequest::ApproveMultisigTransfer(_) => true,
+        }
+    }
+
+    // Whether the request changes any state, in which case its `MessageId` is remembered across
+    // restarts to stop it being replayed.
+    pub(crate) fn is_mutation(&self) -> bool {
+        match self {
+            VaultRequest::StartSession
+            | VaultRequest::ResumeSession(_)
+            | VaultRequest::GetAuditLog
+            | VaultRequest::ListLoginPacketVersions(_) => false,
+            VaultRequest::SetAppRestrictions { .. }
+            | VaultRequest::SetMultisigPolicy(_)
+            | VaultRequest::ApproveMultisigTransfer(_)
+            | VaultRequest::UpdateLoginPacket { .. }
+            | VaultRequest::RollbackLoginPacket { .. }
+            | VaultRequest::SetRecoveryConfig { .. }
+            | VaultRequest::ApproveRecovery(_)
+            | VaultRequest::VetoRecovery(_) => true,
+        }
+    }
+}
+
+/// Responses to `VaultRequest`s.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VaultResponse {
+    /// Outcome of a request which returns no data.
+    Mutation(NdResult<()>),
+    /// Token of the session of the connection the request was sent on.
+    SessionStarted(NdResult<SessionToken>),
+    /// The client's audit log.
+    GetAuditLog(NdResult<Vec<AuditEntry>>),
+    /// Outcome of an approval of a multisig transfer: `None` while the transfer awaits more
+    /// approvals, or the executed transaction once this approval completed it.
+    TransferApproval(NdResult<Option<Transaction>>),
+    /// The new version of an updated or rolled back login packet.
+    LoginPacketVersion(NdResult<u64>),
+    /// The current version of a login packet, along with its superseded versions still held,
+    /// oldest first.
+    LoginPacketVersions(NdResult<(u64, Vec<u64>)>),
+    /// Outcome of an approval of a login packet recovery: `None` while more approvals are needed,
+    /// or the time the replacement takes effect, in seconds since the Unix epoch.
+    RecoveryApproval(NdResult<Option<u64>>),
+    /// The request was rejected without being handled because the client's account or IP address
+    /// exceeded its request rate.  `safe_nd` requests are rejected with an error response of their
+    /// own type instead.
+    RateLimitExceeded {
+        /// Time in milliseconds after which a retry will be accepted.
+        retry_after_ms: u64,
+    },
+}
+
+impl VaultResponse {
+    // Name of the variant of the error carried by the response, if any.  A rate limit rejection
+    // counts as an error too.
+    pub(crate) fn error_kind(&self) -> Option<&'static str> {
+        let error = match self {
+            VaultResponse::Mutation(result) => result.as_ref().err(),
+            VaultResponse::SessionStarted(result) => result.as_ref().err(),
+            VaultResponse::GetAuditLog(result) => result.as_ref().err(),
+            VaultResponse::TransferApproval(result) => result.as_ref().err(),
+            VaultResponse::LoginPacketVersion(result) => result.as_ref().err(),
+            VaultResponse::LoginPacketVersions(result) => result.as_ref().err(),
+            VaultResponse::RecoveryApproval(result) => result.as_ref().err(),
+            VaultResponse::RateLimitExceeded { .. } => return Some(RATE_LIMIT_EXCEEDED),
+        };
+        error.map(utils::error_kind)
+    }
+}
+
+/// Notifications sent by the vault without being asked.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VaultNotification {
+    /// A recovery of the login packet at `name`, which is owned by the notified connection's key,
+    /// has been approved and replaces the packet at `due_at` unless vetoed with
+    /// `VaultRequest::VetoRecovery` first.
+    RecoveryApproved {
+        /// Name of the login packet.
+        name: XorName,
+        /// Time the replacement takes effect, in seconds since the Unix epoch.
+        due_at: u64,
+    },
+    /// The vault is shutting down and is about to drop this connection.  Requests which haven't
+    /// been answered yet won't be.
+    ShuttingDown,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use safe_nd::{Message, Request};
+    use unwrap::unwrap;
+
+    #[test]
+    fn vault_messages_are_distinguishable() {
+        let message = VaultMessage::Notification(VaultNotification::ShuttingDown);
+        let bytes = message.serialise();
+        assert!(VaultMessage::is_vault_message(&bytes));
+        match unwrap!(VaultMessage::deserialise(&bytes)) {
+            VaultMessage::Notification(VaultNotification::ShuttingDown) => (),
+            message => panic!("Unexpected {:?}", message),
+        }
+
+        let nd_message = utils::serialise(&Message::Request {
+            request: Request::GetBalance,
+            message_id: MessageId::new(),
+            signature: None,
+        });
+        assert!(!VaultMessage::is_vault_message(&nd_message));
+        assert!(VaultMessage::deserialise(&nd_message).is_err());
+        assert!(bincode::deserialize::<Message>(&bytes).is_err());
+    }
+}
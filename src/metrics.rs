@@ -0,0 +1,404 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+//! Counters, gauges and histograms describing a running vault, served in the Prometheus text
+//! exposition format.
+//!
+//! The vault's event loop records into `Metrics`, and a separate thread serves them over HTTP.
+
+use crate::{control::Status, Result};
+use log::{info, warn};
+use safe_nd::{Coins, MessageId};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    io::{BufRead, BufReader, Read, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+const LATENCY_BUCKET_COUNT: usize = 9;
+/// Upper bounds in seconds of the request latency histogram buckets.
+const LATENCY_BUCKETS: [f64; LATENCY_BUCKET_COUNT] =
+    [0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0];
+/// Requests without a response for this long are no longer tracked for latency.
+const MAX_TRACKED_REQUEST_AGE: Duration = Duration::from_secs(5 * 60);
+/// Time a scraper has to send its request and read the response, as connections are served one at
+/// a time.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Limit on the size of the request line and headers read from a scraper.
+const HTTP_MAX_REQUEST_LEN: u64 = 16 * 1024;
+
+/// Handle to the metrics of a vault.  Clones share the same metrics.
+#[derive(Clone, Default)]
+pub(crate) struct Metrics(Arc<Mutex<Registry>>);
+
+#[derive(Default)]
+struct Registry {
+    requests: BTreeMap<&'static str, u64>,
+    request_latency: BTreeMap<&'static str, Histogram>,
+    // Requests awaiting a response, for latency.
+    pending: HashMap<MessageId, (&'static str, Instant)>,
+    successful_responses: u64,
+    errors: BTreeMap<&'static str, u64>,
+    coins_transferred_nanos: u64,
+    status: Option<Status>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    // Per bucket, not cumulative.
+    bucket_counts: [u64; LATENCY_BUCKET_COUNT],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(index) = LATENCY_BUCKETS.iter().position(|bound| value <= *bound) {
+            self.bucket_counts[index] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+impl Metrics {
+    /// Records a request of the given kind, of either protocol, received from a client.
+    pub fn request_received(&self, kind: &'static str, message_id: MessageId, now: Instant) {
+        let mut registry = self.lock();
+        *registry.requests.entry(kind).or_insert(0) += 1;
+        let _ = registry.pending.insert(message_id, (kind, now));
+    }
+
+    /// Records a response, of either protocol, sent to a client, along with the latency of its
+    /// request.  `error_kind` is `None` for a successful response.
+    pub fn response_sent(
+        &self,
+        message_id: MessageId,
+        error_kind: Option<&'static str>,
+        now: Instant,
+    ) {
+        let mut registry = self.lock();
+        match error_kind {
+            Some(kind) => *registry.errors.entry(kind).or_insert(0) += 1,
+            None => registry.successful_responses += 1,
+        }
+        if let Some((kind, received_at)) = registry.pending.remove(&message_id) {
+            let latency = now.duration_since(received_at);
+            let latency_secs = latency.as_secs() as f64 + f64::from(latency.subsec_nanos()) * 1e-9;
+            registry
+                .request_latency
+                .entry(kind)
+                .or_default()
+                .observe(latency_secs);
+        }
+    }
+
+    /// Records coins credited to a balance by a transfer.
+    pub fn coins_transferred(&self, amount: Coins) {
+        let mut registry = self.lock();
+        registry.coins_transferred_nanos = registry
+            .coins_transferred_nanos
+            .saturating_add(amount.as_nano());
+    }
+
+    /// Replaces the gauges with the values in `status`, and stops tracking the latency of requests
+    /// which are never going to get a response.
+    pub fn update(&self, status: Status, now: Instant) {
+        let mut registry = self.lock();
+        registry.status = Some(status);
+        registry.pending.retain(|_, (_, received_at)| {
+            now.duration_since(*received_at) < MAX_TRACKED_REQUEST_AGE
+        });
+    }
+
+    /// Returns the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let registry = self.lock();
+        let mut output = String::new();
+
+        write_header(
+            &mut output,
+            "safe_vault_requests_total",
+            "counter",
+            "Client requests received, by request type.",
+        );
+        for (&kind, count) in &registry.requests {
+            writeln_metric(
+                &mut output,
+                "safe_vault_requests_total",
+                &[("request", kind)],
+                *count,
+            );
+        }
+
+        write_header(
+            &mut output,
+            "safe_vault_request_duration_seconds",
+            "histogram",
+            "Time from receiving a client request to sending its response, by request type.",
+        );
+        for (&kind, histogram) in &registry.request_latency {
+            let mut cumulative_count = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.bucket_counts.iter()) {
+                cumulative_count += count;
+                writeln_metric(
+                    &mut output,
+                    "safe_vault_request_duration_seconds_bucket",
+                    &[("request", kind), ("le", &bound.to_string())],
+                    cumulative_count,
+                );
+            }
+            writeln_metric(
+                &mut output,
+                "safe_vault_request_duration_seconds_bucket",
+                &[("request", kind), ("le", "+Inf")],
+                histogram.count,
+            );
+            writeln_metric(
+                &mut output,
+                "safe_vault_request_duration_seconds_sum",
+                &[("request", kind)],
+                histogram.sum,
+            );
+            writeln_metric(
+                &mut output,
+                "safe_vault_request_duration_seconds_count",
+                &[("request", kind)],
+                histogram.count,
+            );
+        }
+
+        write_header(
+            &mut output,
+            "safe_vault_responses_total",
+            "counter",
+            "Responses sent to clients, by outcome.",
+        );
+        writeln_metric(
+            &mut output,
+            "safe_vault_responses_total",
+            &[("result", "success")],
+            registry.successful_responses,
+        );
+        writeln_metric(
+            &mut output,
+            "safe_vault_responses_total",
+            &[("result", "error")],
+            registry.errors.values().sum::<u64>(),
+        );
+
+        write_header(
+            &mut output,
+            "safe_vault_errors_total",
+            "counter",
+            "Error responses sent to clients, by error type.",
+        );
+        for (kind, count) in &registry.errors {
+            writeln_metric(
+                &mut output,
+                "safe_vault_errors_total",
+                &[("error", kind)],
+                *count,
+            );
+        }
+
+        write_header(
+            &mut output,
+            "safe_vault_coins_transferred_nanos_total",
+            "counter",
+            "Coins credited to balances by transfers, in nano coins.",
+        );
+        writeln_metric(
+            &mut output,
+            "safe_vault_coins_transferred_nanos_total",
+            &[],
+            registry.coins_transferred_nanos,
+        );
+
+        if let Some(status) = &registry.status {
+            write_status(&mut output, status);
+        }
+        output
+    }
+
+    /// Serves the metrics over HTTP on 127.0.0.1:`port` from a new thread, answering every request
+    /// with the rendered metrics.
+    pub fn serve(&self, port: u16) -> Result<()> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        info!("Serving metrics on http://127.0.0.1:{}/metrics", port);
+        let metrics = self.clone();
+        let _ = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if let Err(error) = stream.and_then(|stream| metrics.handle_http(stream)) {
+                    warn!("Failed to serve metrics: {}", error);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn handle_http(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+        stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+        // Skip the request line and headers.  Any request gets the metrics.
+        let mut reader = BufReader::new((&stream).take(HTTP_MAX_REQUEST_LEN));
+        let mut line = String::new();
+        while reader.read_line(&mut line)? > 0 && !line.trim_end().is_empty() {
+            line.clear();
+        }
+
+        let body = self.render();
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: text/plain; version=0.0.4\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+        stream.flush()
+    }
+
+    fn lock(&self) -> MutexGuard<Registry> {
+        // A panic while holding the lock can't leave the registry inconsistent enough to matter.
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn write_status(output: &mut String, status: &Status) {
+    write_header(
+        output,
+        "safe_vault_connected_clients",
+        "gauge",
+        "Clients which have answered our challenge.",
+    );
+    writeln_metric(
+        output,
+        "safe_vault_connected_clients",
+        &[],
+        status.connected_clients.len(),
+    );
+
+    write_header(
+        output,
+        "safe_vault_client_candidates",
+        "gauge",
+        "Connections which haven't answered our challenge yet.",
+    );
+    writeln_metric(
+        output,
+        "safe_vault_client_candidates",
+        &[],
+        status.client_candidates,
+    );
+
+    write_header(
+        output,
+        "safe_vault_used_space_bytes",
+        "gauge",
+        "Space used, by chunk store.",
+    );
+    for (store, used_space) in &status.used_space {
+        writeln_metric(
+            output,
+            "safe_vault_used_space_bytes",
+            &[("store", store.as_str())],
+            *used_space,
+        );
+    }
+
+    write_header(
+        output,
+        "safe_vault_pending_idata_ops",
+        "gauge",
+        "ImmutableData operations awaiting responses from the chunk holders.",
+    );
+    writeln_metric(
+        output,
+        "safe_vault_pending_idata_ops",
+        &[],
+        status.pending_idata_ops,
+    );
+}
+
+fn write_header(output: &mut String, name: &str, metric_type: &str, help: &str) {
+    let _ = writeln!(output, "# HELP {} {}", name, help);
+    let _ = writeln!(output, "# TYPE {} {}", name, metric_type);
+}
+
+fn writeln_metric<T: std::fmt::Display>(
+    output: &mut String,
+    name: &str,
+    labels: &[(&str, &str)],
+    value: T,
+) {
+    let _ = write!(output, "{}", name);
+    if !labels.is_empty() {
+        let labels: Vec<_> = labels
+            .iter()
+            .map(|(label, value)| format!("{}=\"{}\"", label, value))
+            .collect();
+        let _ = write!(output, "{{{}}}", labels.join(","));
+    }
+    let _ = writeln!(output, " {}", value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{messages::RATE_LIMIT_EXCEEDED, utils, VaultRequest, VaultResponse};
+    use safe_nd::{Error as NdError, IDataAddress, Request};
+
+    #[test]
+    fn render_counts_requests_and_errors() {
+        let metrics = Metrics::default();
+        let now = Instant::now();
+        let request = Request::GetIData(IDataAddress::Pub(rand::random()));
+        let kind = utils::request_kind(&request);
+
+        let ok_id = MessageId::new();
+        metrics.request_received(kind, ok_id, now);
+        metrics.response_sent(ok_id, None, now + Duration::from_millis(2));
+
+        let error_id = MessageId::new();
+        metrics.request_received(kind, error_id, now);
+        let error_kind = utils::error_kind(&NdError::InvalidSuccessor(3));
+        metrics.response_sent(error_id, Some(error_kind), now);
+
+        // A `safe_nd` request over the rate limits gets an error response of its own type, but is
+        // counted as rate limited, as is a `VaultRequest`.
+        let rate_limited_id = MessageId::new();
+        metrics.request_received(kind, rate_limited_id, now);
+        metrics.response_sent(rate_limited_id, Some(RATE_LIMIT_EXCEEDED), now);
+
+        let vault_request_id = MessageId::new();
+        metrics.request_received(VaultRequest::GetAuditLog.kind(), vault_request_id, now);
+        let response = VaultResponse::RateLimitExceeded { retry_after_ms: 1 };
+        metrics.response_sent(vault_request_id, response.error_kind(), now);
+
+        let output = metrics.render();
+        assert!(output.contains("safe_vault_requests_total{request=\"GetIData\"} 3\n"));
+        assert!(output.contains(
+            "safe_vault_request_duration_seconds_bucket{request=\"GetIData\",le=\"0.001\"} 2\n"
+        ));
+        assert!(output.contains(
+            "safe_vault_request_duration_seconds_bucket{request=\"GetIData\",le=\"0.005\"} 3\n"
+        ));
+        assert!(output.contains("safe_vault_responses_total{result=\"success\"} 1\n"));
+        assert!(output.contains("safe_vault_errors_total{error=\"InvalidSuccessor\"} 1\n"));
+        assert!(output.contains("safe_vault_requests_total{request=\"GetAuditLog\"} 1\n"));
+        assert!(output.contains("safe_vault_errors_total{error=\"RateLimitExceeded\"} 2\n"));
+    }
+}
@@ -0,0 +1,45 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+use crate::{Error, Result};
+use fs2::FileExt;
+use std::{
+    fs::{self, File, OpenOptions},
+    path::Path,
+};
+
+/// Name of the lock file in the root directory.
+pub(crate) const LOCK_FILENAME: &str = "lock";
+/// Name of the pid file a vault running as a daemon keeps locked in its root directory.
+pub const PID_FILENAME: &str = "safe_vault.pid";
+
+/// Exclusive use of a vault's root directory, for as long as this is alive.
+///
+/// The lock is advisory, taken on a file in the root directory, and released by the OS when the
+/// file is closed, so a crashed vault doesn't leave it held.
+pub(crate) struct RootDirLock(File);
+
+impl RootDirLock {
+    /// Locks `root_dir`, creating it if needed.  Returns `Error::RootDirInUse` if another vault, in
+    /// this process or another, already holds the lock.
+    pub fn acquire(root_dir: &Path) -> Result<Self> {
+        fs::create_dir_all(root_dir)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(root_dir.join(LOCK_FILENAME))?;
+        if let Err(error) = file.try_lock_exclusive() {
+            return if error.raw_os_error() == fs2::lock_contended_error().raw_os_error() {
+                Err(Error::RootDirInUse(root_dir.to_path_buf()))
+            } else {
+                Err(error.into())
+            };
+        }
+        Ok(RootDirLock(file))
+    }
+}
@@ -0,0 +1,302 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+//! Periodic snapshots of a running vault's root directory.
+//!
+//! Each snapshot is a copy of the root directory named after the Unix time it was taken at, so a
+//! vault can be restored to a chosen point in time with `restore_snapshot`.
+//!
+//! Chunk files are never modified in place, so snapshots hard-link them rather than copying them.
+//! Taking a snapshot then only costs a walk of the root directory and copies of the DBs and other
+//! small files, and the retained snapshots share every chunk which hasn't changed between them.  If
+//! the snapshot directory is on a different filesystem, the chunks have to be copied instead, and
+//! each retained snapshot can then take up to `max_capacity` of disk space.
+//!
+//! The chunks are linked on the event loop along with the DBs being copied, as a chunk replaced in
+//! between wouldn't match the DBs of the snapshot.  How long that took is logged, with a warning if
+//! it held up the vault noticeably.  Deleting the snapshots beyond the retention limit doesn't
+//! affect the vault's state, so it's done on a separate thread.
+
+use crate::{archive, chunk_store, Config, Error, Result};
+use log::{info, warn};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+const SNAPSHOT_PREFIX: &str = "snapshot-";
+const PARTIAL_EXTENSION: &str = "partial";
+// Expired snapshots are renamed with this extension before being deleted in the background, so
+// that they're no longer listed.
+const DELETING_EXTENSION: &str = "deleting";
+/// Time spent taking a snapshot beyond which a warning is logged.
+const SLOW_SNAPSHOT_WARNING: Duration = Duration::from_secs(1);
+
+/// Takes snapshots of the root directory at the configured interval, keeping only the most recent
+/// ones.
+pub(crate) struct Snapshots {
+    dir: PathBuf,
+    interval: Duration,
+    retention: usize,
+    max_capacity: u64,
+    next_snapshot: Instant,
+}
+
+impl Snapshots {
+    /// Returns `None` if snapshots aren't configured, or if the configured directory is within the
+    /// root directory, where each snapshot would end up in the next one.
+    pub fn new(config: &Config, now: Instant) -> Option<Self> {
+        let dir = config.snapshot_dir()?;
+        if dir.starts_with(config.root_dir()) {
+            warn!(
+                "Snapshots disabled: snapshot directory {} is within the root directory.",
+                dir.display()
+            );
+            return None;
+        }
+        let interval = config.snapshot_interval();
+        Some(Self {
+            dir,
+            interval,
+            retention: config.snapshot_retention(),
+            max_capacity: config.max_capacity(),
+            next_snapshot: now + interval,
+        })
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        now >= self.next_snapshot
+    }
+
+    /// Snapshots `root_dir` for `timestamp`, in seconds since the Unix epoch, then starts deleting
+    /// the snapshots beyond the retention limit in the background.  The caller must ensure nothing modifies `root_dir`
+    /// meanwhile.
+    pub fn take(&mut self, root_dir: &Path, now: Instant, timestamp: u64) -> Result<PathBuf> {
+        let started = Instant::now();
+        self.next_snapshot = now + self.interval;
+        let path = snapshot_path(&self.dir, timestamp);
+        let partial_path = path.with_extension(PARTIAL_EXTENSION);
+        if partial_path.exists() {
+            fs::remove_dir_all(&partial_path)?;
+        }
+
+        let mut copied_chunk_count = 0;
+        for relative_path in archive::list_data_files(root_dir)? {
+            let source = root_dir.join(&relative_path);
+            let target = partial_path.join(&relative_path);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            if chunk_store::is_chunk_file(&relative_path) {
+                if fs::hard_link(&source, &target).is_ok() {
+                    continue;
+                }
+                copied_chunk_count += 1;
+            }
+            let _ = fs::copy(&source, &target)?;
+        }
+        if copied_chunk_count > 0 {
+            warn!(
+                "Copied {} chunks into snapshot {} as they couldn't be hard-linked.  Keeping {} \
+                 snapshots can take up to {} bytes.",
+                copied_chunk_count,
+                path.display(),
+                self.retention,
+                self.max_capacity.saturating_mul(self.retention as u64)
+            );
+        }
+        fs::rename(&partial_path, &path)?;
+        let elapsed = started.elapsed();
+        if elapsed >= SLOW_SNAPSHOT_WARNING {
+            warn!(
+                "Took snapshot {} in {:?}, during which the vault was blocked",
+                path.display(),
+                elapsed
+            );
+        } else {
+            info!("Took snapshot {} in {:?}", path.display(), elapsed);
+        }
+
+        let timestamps = list_snapshots(&self.dir)?;
+        let expired = timestamps.len().saturating_sub(self.retention);
+        let mut deleting_paths = Vec::new();
+        for timestamp in &timestamps[..expired] {
+            let expired_path = snapshot_path(&self.dir, *timestamp);
+            let deleting_path = expired_path.with_extension(DELETING_EXTENSION);
+            match fs::rename(&expired_path, &deleting_path) {
+                Ok(()) => deleting_paths.push(deleting_path),
+                Err(error) => warn!(
+                    "Failed to delete snapshot {}: {}",
+                    expired_path.display(),
+                    error
+                ),
+            }
+        }
+        if !deleting_paths.is_empty() {
+            let _ = thread::spawn(move || {
+                for deleting_path in deleting_paths {
+                    match fs::remove_dir_all(&deleting_path) {
+                        Ok(()) => info!("Deleted snapshot {}", deleting_path.display()),
+                        Err(error) => warn!(
+                            "Failed to delete snapshot {}: {}",
+                            deleting_path.display(),
+                            error
+                        ),
+                    }
+                }
+            });
+        }
+        Ok(path)
+    }
+}
+
+/// Returns the times, in seconds since the Unix epoch, of the snapshots in `snapshot_dir`, oldest
+/// first.
+pub fn list_snapshots<P: AsRef<Path>>(snapshot_dir: P) -> Result<Vec<u64>> {
+    let mut timestamps = fs::read_dir(snapshot_dir)?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            if !path.is_dir() {
+                return None;
+            }
+            path.file_name()?
+                .to_str()?
+                .trim_start_matches(SNAPSHOT_PREFIX)
+                .parse()
+                .ok()
+        })
+        .collect::<Vec<u64>>();
+    timestamps.sort();
+    Ok(timestamps)
+}
+
+/// Restores the snapshot taken at `timestamp` into `root_dir`, which must not exist yet.  The vault
+/// must not be running while its root directory is replaced.
+///
+/// As with an archive import, every chunk is checked against its file name and, where it's derived
+/// from the contents, its address.  Nothing is written to `root_dir` unless the whole snapshot is
+/// valid.
+pub fn restore_snapshot<P: AsRef<Path>, Q: AsRef<Path>>(
+    snapshot_dir: P,
+    timestamp: u64,
+    root_dir: Q,
+) -> Result<()> {
+    let root_dir = root_dir.as_ref();
+    if root_dir.exists() {
+        return Err(Error::InvalidArchive(format!(
+            "{} already exists",
+            root_dir.display()
+        )));
+    }
+    let path = snapshot_path(snapshot_dir.as_ref(), timestamp);
+    if !path.is_dir() {
+        return Err(Error::InvalidArchive(format!(
+            "no snapshot at {}",
+            path.display()
+        )));
+    }
+
+    // Files are copied rather than linked, so that the restored vault can't modify the snapshot.
+    let staging_dir = root_dir.with_extension(PARTIAL_EXTENSION);
+    if staging_dir.exists() {
+        fs::remove_dir_all(&staging_dir)?;
+    }
+    let result = archive::list_data_files(&path)?
+        .into_iter()
+        .try_for_each(|relative_path| {
+            let contents = fs::read(path.join(&relative_path))?;
+            chunk_store::verify_file(&relative_path, &contents).map_err(|error| {
+                Error::InvalidArchive(format!("{}: {}", relative_path.display(), error))
+            })?;
+            let target = staging_dir.join(&relative_path);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(target, contents)?;
+            Ok(())
+        });
+    if let Err(error) = result {
+        let _ = fs::remove_dir_all(&staging_dir);
+        return Err(error);
+    }
+
+    fs::rename(&staging_dir, root_dir)?;
+    info!(
+        "Restored snapshot {} to {}",
+        path.display(),
+        root_dir.display()
+    );
+    Ok(())
+}
+
+fn snapshot_path(snapshot_dir: &Path, timestamp: u64) -> PathBuf {
+    snapshot_dir.join(format!("{}{}", SNAPSHOT_PREFIX, timestamp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+    use unwrap::unwrap;
+
+    #[test]
+    fn old_snapshots_are_deleted_and_any_kept_can_be_restored() {
+        let temp_dir = unwrap!(TempDir::new("snapshot"));
+        let root_dir = temp_dir.path().join("root");
+        unwrap!(fs::create_dir_all(&root_dir));
+        let snapshot_dir = temp_dir.path().join("snapshots");
+        let now = Instant::now();
+        let mut snapshots = Snapshots {
+            dir: snapshot_dir.clone(),
+            interval: Duration::from_secs(60),
+            retention: 2,
+            max_capacity: 0,
+            next_snapshot: now,
+        };
+
+        for timestamp in 1..=3 {
+            unwrap!(fs::write(root_dir.join("state"), &[timestamp as u8]));
+            let _ = unwrap!(snapshots.take(&root_dir, now, timestamp));
+        }
+        assert!(!snapshots.is_due(now));
+        assert_eq!(unwrap!(list_snapshots(&snapshot_dir)), vec![2, 3]);
+
+        let restored_dir = temp_dir.path().join("restored");
+        assert!(restore_snapshot(&snapshot_dir, 1, &restored_dir).is_err());
+        unwrap!(restore_snapshot(&snapshot_dir, 2, &restored_dir));
+        assert_eq!(unwrap!(fs::read(restored_dir.join("state"))), vec![2]);
+    }
+
+    #[test]
+    fn snapshots_keep_replaced_chunks() {
+        let temp_dir = unwrap!(TempDir::new("snapshot"));
+        let root_dir = temp_dir.path().join("root");
+        let chunk_path = Path::new("chunks").join("immutable").join("chunk");
+        unwrap!(fs::create_dir_all(unwrap!(root_dir
+            .join(&chunk_path)
+            .parent())));
+        unwrap!(fs::write(root_dir.join(&chunk_path), &[1]));
+        let snapshot_dir = temp_dir.path().join("snapshots");
+        let now = Instant::now();
+        let mut snapshots = Snapshots {
+            dir: snapshot_dir,
+            interval: Duration::from_secs(60),
+            retention: 1,
+            max_capacity: 0,
+            next_snapshot: now,
+        };
+
+        let path = unwrap!(snapshots.take(&root_dir, now, 1));
+        // Replace the chunk the way `ChunkStore::put` does.
+        unwrap!(fs::remove_file(root_dir.join(&chunk_path)));
+        unwrap!(fs::write(root_dir.join(&chunk_path), &[2]));
+        assert_eq!(unwrap!(fs::read(path.join(&chunk_path))), vec![1]);
+    }
+}
@@ -6,12 +6,12 @@
 // KIND, either express or implied. Please review the Licences for the specific language governing
 // permissions and limitations relating to use of the SAFE Network Software.
 
-use crate::utils;
+use crate::{chunk_store::LoginPacketVersionId, utils};
 use base64;
 use safe_nd::{
     ADataAddress, ClientPublicId, IDataAddress, MDataAddress, NodePublicId, PublicKey, XorName,
 };
-use serde::Serialize;
+use serde::{de::DeserializeOwned, Serialize};
 
 pub(crate) trait ToDbKey: Serialize {
     /// The encoded string representation of an identifier, used as a key in the context of a
@@ -22,9 +22,16 @@ pub(crate) trait ToDbKey: Serialize {
     }
 }
 
+/// Decodes an identifier from its `to_db_key()` representation.
+pub(crate) fn from_db_key<T: DeserializeOwned>(key: &str) -> Option<T> {
+    let decoded = base64::decode(key).ok()?;
+    bincode::deserialize(&decoded).ok()
+}
+
 impl ToDbKey for ADataAddress {}
 impl ToDbKey for ClientPublicId {}
 impl ToDbKey for IDataAddress {}
+impl ToDbKey for LoginPacketVersionId {}
 impl ToDbKey for MDataAddress {}
 impl ToDbKey for NodePublicId {}
 impl ToDbKey for PublicKey {}
@@ -0,0 +1,368 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+//! Checking for, verifying and installing new releases of the vault binary.
+//!
+//! Each release binary is published along with a `.sig` file holding the release signing key's
+//! signature of the release's manifest: its version, its target and the SHA3-256 hash of the
+//! binary.  The key's public half is pinned into the vault binary at build time, and a binary is
+//! only installed if the signature verifies against it for the version and target being installed,
+//! so that an older or another target's signed binary can't be passed off as the release.
+
+use crate::{utils, Error, Result};
+use log::info;
+use safe_nd::{PublicKey, Signature};
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+/// Hex encoded, bincode serialised `PublicKey` which releases must be signed with, set at build
+/// time.  Not under the `SAFE_VAULT_` prefix, as the config would reject it as an unknown setting.
+const PINNED_SIGNING_KEY: Option<&str> = option_env!("VAULT_RELEASE_SIGNING_KEY");
+const SIGNATURE_EXTENSION: &str = "sig";
+const LOCAL_RELEASE_LIST: &str = "releases.json";
+
+/// A release of the vault binary for one target.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct Release {
+    /// Version of the release, e.g. "0.19.1".
+    pub version: String,
+    /// Target triple the binary is built for.
+    pub target: String,
+    /// Name of the file holding the binary, possibly in an archive.  Its signature is in the file
+    /// of the same name with ".sig" appended.
+    pub asset_name: String,
+}
+
+// What the release signing key signs for each release.
+#[derive(Serialize)]
+struct ReleaseManifest<'a> {
+    version: &'a str,
+    target: &'a str,
+    sha3_256: [u8; 32],
+}
+
+/// Returns the serialised manifest of the release of `binary` as `version` for `target`, which is
+/// what the release's `.sig` file holds the signature of.
+pub fn release_manifest(version: &str, target: &str, binary: &[u8]) -> Vec<u8> {
+    utils::serialise(&ReleaseManifest {
+        version,
+        target,
+        sha3_256: tiny_keccak::sha3_256(binary),
+    })
+}
+
+/// Outcome of `install_latest_release`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UpdateStatus {
+    /// There's no newer release.
+    UpToDate,
+    /// The binary was replaced by the given version, which takes effect after a restart.
+    Updated(String),
+}
+
+/// Where to find releases of the vault binary.
+pub trait ReleaseSource {
+    /// Returns the newest release for `target`, if there is one.
+    fn latest_release(&self, target: &str) -> Result<Option<Release>>;
+
+    /// Fetches the binary of `release` along with its serialised signature.
+    fn fetch(&self, release: &Release) -> Result<(Vec<u8>, Vec<u8>)>;
+}
+
+/// Releases published on GitHub, with each binary in a `.tar.gz` or `.zip` archive.
+pub struct GithubReleases {
+    repo_owner: String,
+    repo_name: String,
+}
+
+impl GithubReleases {
+    /// Releases of the given repository.
+    pub fn new<S: Into<String>>(repo_owner: S, repo_name: S) -> Self {
+        Self {
+            repo_owner: repo_owner.into(),
+            repo_name: repo_name.into(),
+        }
+    }
+
+    fn download(&self, release: &Release, asset_name: &str, path: &Path) -> Result<()> {
+        let url = format!(
+            "https://github.com/{}/{}/releases/download/{}/{}",
+            self.repo_owner, self.repo_name, release.version, asset_name
+        );
+        let mut file = File::create(path)?;
+        self_update::Download::from_url(&url)
+            .show_progress(false)
+            .download_to(&mut file)
+            .map_err(|error| Error::InvalidUpdate(format!("downloading {}: {}", url, error)))
+    }
+
+    fn fetch_into(&self, release: &Release, dir: &Path) -> Result<(Vec<u8>, Vec<u8>)> {
+        let archive_path = dir.join(&release.asset_name);
+        let signature_name = signature_file_name(&release.asset_name);
+        let signature_path = dir.join(&signature_name);
+        self.download(release, &release.asset_name, &archive_path)?;
+        self.download(release, &signature_name, &signature_path)?;
+
+        let archive_kind = if release.asset_name.ends_with(".zip") {
+            self_update::ArchiveKind::Zip
+        } else {
+            self_update::ArchiveKind::Tar(Some(self_update::Compression::Gz))
+        };
+        let bin_name = bin_name(&release.target);
+        self_update::Extract::from_source(&archive_path)
+            .archive(archive_kind)
+            .extract_file(dir, &bin_name)
+            .map_err(|error| Error::InvalidUpdate(format!("extracting {}: {}", bin_name, error)))?;
+
+        Ok((fs::read(dir.join(bin_name))?, fs::read(signature_path)?))
+    }
+}
+
+impl ReleaseSource for GithubReleases {
+    fn latest_release(&self, target: &str) -> Result<Option<Release>> {
+        let releases = self_update::backends::github::ReleaseList::configure()
+            .repo_owner(&self.repo_owner)
+            .repo_name(&self.repo_name)
+            .with_target(target)
+            .build()
+            .and_then(|release_list| release_list.fetch())
+            .map_err(|error| Error::InvalidUpdate(format!("listing releases: {}", error)))?;
+        // Releases are listed newest first.
+        Ok(releases.iter().find_map(|release| {
+            let asset = release.asset_for(target)?;
+            Some(Release {
+                version: release.version.clone(),
+                target: target.to_string(),
+                asset_name: asset.name,
+            })
+        }))
+    }
+
+    fn fetch(&self, release: &Release) -> Result<(Vec<u8>, Vec<u8>)> {
+        let dir = env::temp_dir().join(format!("safe_vault_update_{}", release.version));
+        fs::create_dir_all(&dir)?;
+        let result = self.fetch_into(release, &dir);
+        let _ = fs::remove_dir_all(&dir);
+        result
+    }
+}
+
+/// Releases in a local directory, e.g. a mirror for vaults without internet access.  The directory
+/// holds a `releases.json` file listing the `Release`s, alongside their binaries and signatures.
+pub struct LocalReleases {
+    dir: PathBuf,
+}
+
+impl LocalReleases {
+    /// Releases in `dir`.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+impl ReleaseSource for LocalReleases {
+    fn latest_release(&self, target: &str) -> Result<Option<Release>> {
+        let releases: Vec<Release> =
+            serde_json::from_slice(&fs::read(self.dir.join(LOCAL_RELEASE_LIST))?)?;
+        let mut latest: Option<Release> = None;
+        for release in releases
+            .into_iter()
+            .filter(|release| release.target == target)
+        {
+            let is_newer = match &latest {
+                Some(latest) => is_newer(&latest.version, &release.version)?,
+                None => true,
+            };
+            if is_newer {
+                latest = Some(release);
+            }
+        }
+        Ok(latest)
+    }
+
+    fn fetch(&self, release: &Release) -> Result<(Vec<u8>, Vec<u8>)> {
+        let binary = fs::read(self.dir.join(&release.asset_name))?;
+        let signature = fs::read(self.dir.join(signature_file_name(&release.asset_name)))?;
+        Ok((binary, signature))
+    }
+}
+
+/// Returns the release signing key pinned at build time by setting the `VAULT_RELEASE_SIGNING_KEY`
+/// environment variable, if any.  Without one, releases can't be verified, so updating is disabled.
+pub fn pinned_signing_key() -> Result<Option<PublicKey>> {
+    let encoded = match PINNED_SIGNING_KEY {
+        Some(encoded) => encoded,
+        None => return Ok(None),
+    };
+    let bytes = hex::decode(encoded.trim())
+        .map_err(|error| Error::InvalidUpdate(format!("pinned signing key: {}", error)))?;
+    Ok(Some(bincode::deserialize(&bytes)?))
+}
+
+/// Replaces the binary at `install_path` with the newest release from `source` for `target`, if
+/// it's newer than `current_version` and its manifest is signed by `signing_key`.  The binary is left
+/// untouched if anything fails.
+pub fn install_latest_release<S: ReleaseSource>(
+    source: &S,
+    signing_key: &PublicKey,
+    current_version: &str,
+    target: &str,
+    install_path: &Path,
+) -> Result<UpdateStatus> {
+    let release = match source.latest_release(target)? {
+        Some(release) => release,
+        None => return Ok(UpdateStatus::UpToDate),
+    };
+    if !is_newer(current_version, &release.version)? {
+        return Ok(UpdateStatus::UpToDate);
+    }
+
+    info!("Fetching release {} for {}", release.version, target);
+    let (binary, signature) = source.fetch(&release)?;
+    let signature: Signature = bincode::deserialize(&signature)?;
+    let manifest = release_manifest(&release.version, &release.target, &binary);
+    signing_key.verify(&signature, &manifest).map_err(|error| {
+        Error::InvalidUpdate(format!(
+            "release {} has an invalid signature: {}",
+            release.version, error
+        ))
+    })?;
+
+    install(&binary, install_path)?;
+    info!(
+        "Installed release {} at {}",
+        release.version,
+        install_path.display()
+    );
+    Ok(UpdateStatus::Updated(release.version))
+}
+
+fn is_newer(current_version: &str, version: &str) -> Result<bool> {
+    self_update::version::bump_is_greater(current_version, version)
+        .map_err(|error| Error::InvalidUpdate(format!("comparing versions: {}", error)))
+}
+
+// Moves the running binary aside rather than overwriting it, as Windows only allows the former.
+fn install(binary: &[u8], path: &Path) -> Result<()> {
+    let new_path = path.with_extension("new");
+    let old_path = path.with_extension("old");
+    fs::write(&new_path, binary)?;
+    set_executable(&new_path)?;
+
+    let _ = fs::remove_file(&old_path);
+    fs::rename(path, &old_path)?;
+    if let Err(error) = fs::rename(&new_path, path) {
+        let _ = fs::rename(&old_path, path);
+        return Err(error.into());
+    }
+    // Fails on Windows while the old binary is running.  It's then removed by the next update.
+    let _ = fs::remove_file(&old_path);
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755)).map_err(From::from)
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+fn signature_file_name(asset_name: &str) -> String {
+    format!("{}.{}", asset_name, SIGNATURE_EXTENSION)
+}
+
+fn bin_name(target: &str) -> &'static str {
+    if target.contains("pc-windows") {
+        "safe_vault.exe"
+    } else {
+        "safe_vault"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use safe_nd::ClientFullId;
+    use serde_json::json;
+    use tempdir::TempDir;
+    use unwrap::unwrap;
+
+    const TARGET: &str = "x86_64-unknown-linux-gnu";
+
+    #[test]
+    fn only_newer_releases_with_valid_signatures_are_installed() {
+        let temp_dir = unwrap!(TempDir::new("updater"));
+        let release_dir = temp_dir.path().join("releases");
+        unwrap!(fs::create_dir_all(&release_dir));
+        let install_path = temp_dir.path().join("safe_vault");
+        unwrap!(fs::write(&install_path, b"0.1.0"));
+
+        let releases = json!([
+            { "version": "0.1.1", "target": TARGET, "asset_name": "safe_vault-0.1.1" },
+            { "version": "0.2.0", "target": TARGET, "asset_name": "safe_vault-0.2.0" },
+            { "version": "0.3.0", "target": "other", "asset_name": "safe_vault-0.3.0" },
+        ]);
+        unwrap!(fs::write(
+            release_dir.join(LOCAL_RELEASE_LIST),
+            releases.to_string()
+        ));
+        let signing_id = ClientFullId::new_ed25519(&mut rand::thread_rng());
+        let signing_key = signing_id.public_id().public_key().clone();
+        let other_id = ClientFullId::new_ed25519(&mut rand::thread_rng());
+        let write_release = |id: &ClientFullId, version: &str| {
+            let binary = b"0.2.0".to_vec();
+            let manifest = release_manifest(version, TARGET, &binary);
+            let signature = unwrap!(bincode::serialize(&id.sign(&manifest)));
+            unwrap!(fs::write(release_dir.join("safe_vault-0.2.0"), &binary));
+            unwrap!(fs::write(
+                release_dir.join("safe_vault-0.2.0.sig"),
+                &signature
+            ));
+        };
+        let source = LocalReleases::new(&release_dir);
+
+        write_release(&other_id, "0.2.0");
+        let result = install_latest_release(&source, &signing_key, "0.1.0", TARGET, &install_path);
+        assert!(result.is_err());
+        assert_eq!(unwrap!(fs::read(&install_path)), b"0.1.0");
+
+        // Signed by the right key, but for a different version.
+        write_release(&signing_id, "0.1.1");
+        let result = install_latest_release(&source, &signing_key, "0.1.0", TARGET, &install_path);
+        assert!(result.is_err());
+        assert_eq!(unwrap!(fs::read(&install_path)), b"0.1.0");
+
+        write_release(&signing_id, "0.2.0");
+        let status = unwrap!(install_latest_release(
+            &source,
+            &signing_key,
+            "0.2.0",
+            TARGET,
+            &install_path
+        ));
+        assert_eq!(status, UpdateStatus::UpToDate);
+
+        let status = unwrap!(install_latest_release(
+            &source,
+            &signing_key,
+            "0.1.0",
+            TARGET,
+            &install_path
+        ));
+        assert_eq!(status, UpdateStatus::Updated("0.2.0".to_string()));
+        assert_eq!(unwrap!(fs::read(&install_path)), b"0.2.0");
+    }
+}
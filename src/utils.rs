@@ -11,38 +11,101 @@ use bincode;
 use log::{error, trace};
 use pickledb::{PickleDb, PickleDbDumpPolicy};
 use rand::{distributions::Standard, thread_rng, Rng};
-use safe_nd::{ClientPublicId, IDataAddress, PublicId, PublicKey, Request, XorName};
+use safe_nd::{
+    ClientPublicId, Error as NdError, IDataAddress, PublicId, PublicKey, Request, Response, XorName,
+};
 use serde::Serialize;
-use std::{borrow::Cow, fs, path::Path};
+use std::{
+    borrow::Cow,
+    fs::{self, OpenOptions},
+    io,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
 use unwrap::unwrap;
 
+/// Extension of every DB name passed to `new_db`, so that `sync_dbs` can find them.
+const DB_EXTENSION: &str = "db";
+
 pub(crate) fn new_db<D: AsRef<Path>, N: AsRef<Path>>(
     db_dir: D,
     db_name: N,
     init_mode: Init,
+) -> Result<PickleDb> {
+    new_db_with_dump_policy(db_dir, db_name, init_mode, PickleDbDumpPolicy::AutoDump)
+}
+
+/// As `new_db`, but with the given dump policy, e.g. for a DB which is written in batches.
+pub(crate) fn new_db_with_dump_policy<D: AsRef<Path>, N: AsRef<Path>>(
+    db_dir: D,
+    db_name: N,
+    init_mode: Init,
+    dump_policy: PickleDbDumpPolicy,
 ) -> Result<PickleDb> {
     let db_path = db_dir.as_ref().join(db_name);
     if init_mode == Init::New {
         trace!("Creating database at {}", db_path.display());
         fs::create_dir_all(db_dir)?;
-        let mut db = PickleDb::new_bin(db_path, PickleDbDumpPolicy::AutoDump);
+        let mut db = PickleDb::new_bin(db_path, dump_policy);
         // Write then delete a value to ensure DB file is actually written to disk.
         db.set("", &"")?;
         let _ = db.rem("")?;
+        db.dump()?;
         return Ok(db);
     }
     trace!("Loading database at {}", db_path.display());
-    let result = PickleDb::load_bin(db_path.clone(), PickleDbDumpPolicy::AutoDump);
+    let result = PickleDb::load_bin(db_path.clone(), dump_policy);
     if let Err(ref error) = &result {
         error!("Failed to load {}: {}", db_path.display(), error);
     }
     Ok(result?)
 }
 
+/// Loads an existing DB which is never written back, e.g. for inspecting a stopped vault.
+pub(crate) fn load_db_read_only<D: AsRef<Path>, N: AsRef<Path>>(
+    db_dir: D,
+    db_name: N,
+) -> Result<PickleDb> {
+    let db_path = db_dir.as_ref().join(db_name);
+    trace!("Loading database read-only at {}", db_path.display());
+    // Never dumped, as nothing ever requests it.
+    Ok(PickleDb::load_bin(
+        db_path,
+        PickleDbDumpPolicy::DumpUponRequest,
+    )?)
+}
+
+/// Flushes the DBs created by `new_db` in `db_dir` to disk.
+pub(crate) fn sync_dbs(db_dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(db_dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_file() && path.extension() == Some(DB_EXTENSION.as_ref()) {
+            sync_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Flushes the file at `path` to disk.
+pub(crate) fn sync_file(path: &Path) -> io::Result<()> {
+    // Opened for writing, as flushing a read-only handle fails on Windows.
+    OpenOptions::new().write(true).open(path)?.sync_all()
+}
+
 pub(crate) fn random_vec(size: usize) -> Vec<u8> {
     thread_rng().sample_iter(&Standard).take(size).collect()
 }
 
+/// Returns the current time in seconds since the Unix epoch, for timestamps which need to survive a
+/// restart.
+pub(crate) fn unix_time_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
 pub(crate) fn serialise<T: Serialize>(data: &T) -> Vec<u8> {
     unwrap!(bincode::serialize(data))
 }
@@ -211,3 +274,125 @@ pub(crate) fn authorisation_kind(request: &Request) -> AuthorisationKind {
         GetBalance => AuthorisationKind::GetBalance,
     }
 }
+
+/// Returns the name of the request's variant, e.g. for logging or auditing without the payload.
+pub(crate) fn request_kind(request: &Request) -> &'static str {
+    use Request::*;
+
+    match request {
+        PutIData(_) => "PutIData",
+        GetIData(_) => "GetIData",
+        DeleteUnpubIData(_) => "DeleteUnpubIData",
+        PutMData(_) => "PutMData",
+        GetMData(_) => "GetMData",
+        GetMDataValue { .. } => "GetMDataValue",
+        DeleteMData(_) => "DeleteMData",
+        GetMDataShell(_) => "GetMDataShell",
+        GetMDataVersion(_) => "GetMDataVersion",
+        ListMDataEntries(_) => "ListMDataEntries",
+        ListMDataKeys(_) => "ListMDataKeys",
+        ListMDataValues(_) => "ListMDataValues",
+        SetMDataUserPermissions { .. } => "SetMDataUserPermissions",
+        DelMDataUserPermissions { .. } => "DelMDataUserPermissions",
+        ListMDataPermissions(_) => "ListMDataPermissions",
+        ListMDataUserPermissions { .. } => "ListMDataUserPermissions",
+        MutateMDataEntries { .. } => "MutateMDataEntries",
+        PutAData(_) => "PutAData",
+        GetAData(_) => "GetAData",
+        GetADataShell { .. } => "GetADataShell",
+        GetADataValue { .. } => "GetADataValue",
+        DeleteAData(_) => "DeleteAData",
+        GetADataRange { .. } => "GetADataRange",
+        GetADataIndices(_) => "GetADataIndices",
+        GetADataLastEntry(_) => "GetADataLastEntry",
+        GetADataPermissions { .. } => "GetADataPermissions",
+        GetPubADataUserPermissions { .. } => "GetPubADataUserPermissions",
+        GetUnpubADataUserPermissions { .. } => "GetUnpubADataUserPermissions",
+        GetADataOwners { .. } => "GetADataOwners",
+        AddPubADataPermissions { .. } => "AddPubADataPermissions",
+        AddUnpubADataPermissions { .. } => "AddUnpubADataPermissions",
+        SetADataOwner { .. } => "SetADataOwner",
+        AppendSeq { .. } => "AppendSeq",
+        AppendUnseq(_) => "AppendUnseq",
+        TransferCoins { .. } => "TransferCoins",
+        GetBalance => "GetBalance",
+        CreateBalance { .. } => "CreateBalance",
+        CreateLoginPacket(_) => "CreateLoginPacket",
+        CreateLoginPacketFor { .. } => "CreateLoginPacketFor",
+        UpdateLoginPacket(_) => "UpdateLoginPacket",
+        GetLoginPacket(_) => "GetLoginPacket",
+        ListAuthKeysAndVersion => "ListAuthKeysAndVersion",
+        InsAuthKey { .. } => "InsAuthKey",
+        DelAuthKey { .. } => "DelAuthKey",
+    }
+}
+
+/// Returns the name of the error's variant, e.g. "InvalidSuccessor" for `InvalidSuccessor(3)`, so
+/// that errors carrying different values are counted together.
+pub(crate) fn error_kind(error: &NdError) -> &'static str {
+    use NdError::*;
+
+    match error {
+        AccessDenied => "AccessDenied",
+        NoSuchLoginPacket => "NoSuchLoginPacket",
+        LoginPacketExists => "LoginPacketExists",
+        NoSuchData => "NoSuchData",
+        DataExists => "DataExists",
+        NoSuchEntry => "NoSuchEntry",
+        TooManyEntries => "TooManyEntries",
+        InvalidEntryActions(_) => "InvalidEntryActions",
+        NoSuchKey => "NoSuchKey",
+        DuplicateEntryKeys => "DuplicateEntryKeys",
+        InvalidOwners => "InvalidOwners",
+        InvalidSuccessor(_) => "InvalidSuccessor",
+        InvalidOwnersSuccessor(_) => "InvalidOwnersSuccessor",
+        InvalidPermissionsSuccessor(_) => "InvalidPermissionsSuccessor",
+        InvalidPermissions => "InvalidPermissions",
+        InvalidOperation => "InvalidOperation",
+        SigningKeyTypeMismatch => "SigningKeyTypeMismatch",
+        InvalidSignature => "InvalidSignature",
+        DuplicateMessageId => "DuplicateMessageId",
+        NetworkOther(_) => "NetworkOther",
+        LossOfPrecision => "LossOfPrecision",
+        ExcessiveValue => "ExcessiveValue",
+        FailedToParse(_) => "FailedToParse",
+        TransactionIdExists => "TransactionIdExists",
+        InsufficientBalance => "InsufficientBalance",
+        NoSuchBalance => "NoSuchBalance",
+        BalanceExists => "BalanceExists",
+        ExceededSize => "ExceededSize",
+    }
+}
+
+/// Returns the error carried by the response, if any.
+pub(crate) fn response_error(response: &Response) -> Option<&NdError> {
+    use Response::*;
+
+    match response {
+        GetIData(result) => result.as_ref().err(),
+        GetMData(result) => result.as_ref().err(),
+        GetMDataShell(result) => result.as_ref().err(),
+        GetMDataVersion(result) => result.as_ref().err(),
+        ListMDataEntries(result) => result.as_ref().err(),
+        ListMDataKeys(result) => result.as_ref().err(),
+        ListMDataValues(result) => result.as_ref().err(),
+        ListMDataUserPermissions(result) => result.as_ref().err(),
+        ListMDataPermissions(result) => result.as_ref().err(),
+        GetMDataValue(result) => result.as_ref().err(),
+        GetAData(result) => result.as_ref().err(),
+        GetADataShell(result) => result.as_ref().err(),
+        GetADataOwners(result) => result.as_ref().err(),
+        GetADataRange(result) => result.as_ref().err(),
+        GetADataIndices(result) => result.as_ref().err(),
+        GetADataLastEntry(result) => result.as_ref().err(),
+        GetADataPermissions(result) => result.as_ref().err(),
+        GetPubADataUserPermissions(result) => result.as_ref().err(),
+        GetUnpubADataUserPermissions(result) => result.as_ref().err(),
+        GetADataValue(result) => result.as_ref().err(),
+        GetBalance(result) => result.as_ref().err(),
+        Transaction(result) => result.as_ref().err(),
+        ListAuthKeysAndVersion(result) => result.as_ref().err(),
+        GetLoginPacket(result) => result.as_ref().err(),
+        Mutation(result) => result.as_ref().err(),
+    }
+}
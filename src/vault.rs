@@ -9,26 +9,38 @@
 use crate::{
     action::Action,
     adult::Adult,
+    archive, chunk_store,
     client_handler::ClientHandler,
     coins_handler::CoinsHandler,
+    control::{ReloadReport, Role, ScrubReport, ShutdownStatus, Status},
     data_handler::DataHandler,
+    log_context, manifest,
+    metrics::Metrics,
     quic_p2p::{Event, NodeInfo},
+    root_dir_lock::RootDirLock,
     rpc::Rpc,
-    utils, Config, Error, Result,
+    snapshot::Snapshots,
+    utils, verbosity, Config, Error, Result,
 };
 use bincode;
-use crossbeam_channel::{select, Receiver};
+use crossbeam_channel::{self, select, Receiver, Sender};
 use log::{error, info, trace};
 use safe_nd::{NodeFullId, Request, XorName};
 use std::{
     cell::Cell,
     fmt::{self, Display, Formatter},
     fs,
-    path::PathBuf,
+    net::SocketAddr,
+    path::{Component, Path, PathBuf},
     rc::Rc,
+    time::{Duration, Instant},
 };
 
 const STATE_FILENAME: &str = "state";
+/// Interval between periodic housekeeping runs, e.g. expiring unanswered client challenges.
+const TIMER_INTERVAL: Duration = Duration::from_secs(5);
+/// Time operations in progress at shutdown have to complete.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[allow(clippy::large_enum_variant)]
 enum State {
@@ -51,36 +63,72 @@ pub(crate) enum Init {
 
 /// Command that the user can send to a running vault to control its execution.
 pub enum Command {
-    /// Shutdown the vault
+    /// Shutdown the vault, once the operations in progress complete.  A second `Shutdown` stops
+    /// the vault without waiting any longer.
     Shutdown,
+    /// Write an archive of the vault's root directory, under the given file name in the configured
+    /// `export-dir`, for restoring with `import_archive`.  Fails with `Error::InvalidExportPath` if
+    /// exports are disabled or the name isn't a plain file name.  The result is sent back on the
+    /// given channel.
+    Export(PathBuf, Sender<Result<()>>),
+    /// Report the vault's role, clients, used space and operations in progress.
+    Status(Sender<Status>),
+    /// Drop the connection to the client on the given address.  Replies `false` if there's no such
+    /// client.
+    DisconnectClient(SocketAddr, Sender<bool>),
+    /// Apply the settings from the given config which can change while running, e.g. the maximum
+    /// capacity and the rate limits.  The reply lists the changed settings which need a restart.
+    ReloadConfig(Box<Config>, Sender<ReloadReport>),
+    /// Check every stored chunk against its name and contents, reporting the invalid ones.
+    Scrub(Sender<Result<ScrubReport>>),
+    /// Describe the vault's internal state in detail, for debugging.
+    DumpDiagnostics(Sender<String>),
 }
 
 /// Main vault struct.
 pub struct Vault {
     id: NodeFullId,
+    // The settings in effect, i.e. as started with apart from those reloaded since.
+    config: Config,
     root_dir: PathBuf,
+    // Held for the vault's lifetime, so no other vault uses the same root directory.
+    _root_dir_lock: RootDirLock,
     state: State,
+    snapshots: Option<Snapshots>,
+    metrics: Metrics,
+    // Set once shutting down, to the time by which the operations in progress should complete.
+    drain_deadline: Option<Instant>,
     event_receiver: Receiver<Event>,
     command_receiver: Receiver<Command>,
 }
 
 impl Vault {
-    /// Construct a new vault instance.
+    /// Construct a new vault instance.  Fails with `Error::RootDirInUse` if another vault is using
+    /// the configured root directory.
     pub fn new(config: Config, command_receiver: Receiver<Command>) -> Result<Self> {
+        let root_dir_lock = RootDirLock::acquire(&config.root_dir())?;
         let mut init_mode = Init::Load;
-        let (is_elder, id) = Self::read_state(&config)?.unwrap_or_else(|| {
+        // A manifest without a state file still needs checking, e.g. if a newer vault created the
+        // directory but stopped before writing its state.
+        if config.root_dir().join(STATE_FILENAME).is_file() || manifest::exists(config.root_dir()) {
+            manifest::migrate(config.root_dir())?;
+        }
+        let (is_elder, id) = Self::read_state(&config.root_dir())?.unwrap_or_else(|| {
             let mut rng = rand::thread_rng();
             let id = NodeFullId::new(&mut rng);
             init_mode = Init::New;
             (true, id)
         });
+        log_context::set_node(id.public_id().name());
 
+        let metrics = Metrics::default();
         let (state, event_receiver) = if is_elder {
             let total_used_space = Rc::new(Cell::new(0));
             let (client_handler, event_receiver) = ClientHandler::new(
                 id.public_id().clone(),
                 &config,
                 &total_used_space,
+                metrics.clone(),
                 init_mode,
             )?;
             let data_handler = DataHandler::new(
@@ -108,15 +156,24 @@ impl Vault {
             )?;
             unimplemented!();
         };
+        if let Some(port) = config.metrics_port() {
+            metrics.serve(port)?;
+        }
 
         let vault = Self {
             id,
             root_dir: config.root_dir().to_path_buf(),
+            _root_dir_lock: root_dir_lock,
             state,
+            snapshots: Snapshots::new(&config, Instant::now()),
+            config,
+            metrics,
+            drain_deadline: None,
             event_receiver,
             command_receiver,
         };
         vault.dump_state()?;
+        manifest::write(&vault.root_dir)?;
         Ok(vault)
     }
 
@@ -131,10 +188,13 @@ impl Vault {
         }
     }
 
-    /// Runs the main event loop. Blocks until the vault is terminated.
+    /// Runs the main event loop. Blocks until the vault is terminated, returning whether the
+    /// operations in progress were drained.
     // FIXME: remove when https://github.com/crossbeam-rs/crossbeam/issues/404 is resolved
     #[allow(clippy::zero_ptr, clippy::drop_copy)]
-    pub fn run(&mut self) {
+    pub fn run(&mut self) -> ShutdownStatus {
+        log_context::set_node(self.id.public_id().name());
+        let timer = crossbeam_channel::tick(TIMER_INTERVAL);
         loop {
             select! {
                 recv(self.event_receiver) -> event => {
@@ -145,18 +205,23 @@ impl Vault {
                     }
                 }
                 recv(self.command_receiver) -> command => {
-                    if let Ok(Command::Shutdown) = command {
-                        trace!("{}: Shutdown command received", self);
-                        break
+                    if let Ok(command) = command {
+                        self.handle_command(command)
                     }
                 }
+                recv(timer) -> _ => self.handle_timeout(Instant::now()),
+            }
+            if self.is_drain_finished(Instant::now()) {
+                break;
             }
         }
+        self.finish_shutdown()
     }
 
     /// Processes any outstanding network events and returns. Does not block.
     /// Returns whether at least one event was processed.
     pub fn poll(&mut self) -> bool {
+        log_context::set_node(self.id.public_id().name());
         let mut processed = false;
 
         while let Ok(event) = self.event_receiver.try_recv() {
@@ -167,6 +232,245 @@ impl Vault {
         processed
     }
 
+    /// Runs the periodic housekeeping as if the timer fired at `now`, so that tests can expire
+    /// state without waiting.
+    #[cfg(feature = "mock")]
+    pub fn handle_timeout_at(&mut self, now: Instant) {
+        self.handle_timeout(now)
+    }
+
+    fn handle_timeout(&mut self, now: Instant) {
+        if let Some(client_handler) = self.client_handler_mut() {
+            client_handler.handle_timeout(now);
+        }
+        if let Some(data_handler) = self.data_handler_mut() {
+            data_handler.handle_timeout(now);
+        }
+        self.take_snapshot_if_due(now);
+        self.metrics.update(self.status(), now);
+    }
+
+    // As with `export`, the directory is consistent while we're handling this.
+    fn take_snapshot_if_due(&mut self, now: Instant) {
+        let snapshots = match self.snapshots.as_mut() {
+            Some(snapshots) if snapshots.is_due(now) => snapshots,
+            _ => return,
+        };
+        if let Err(error) = snapshots.take(&self.root_dir, now, utils::unix_time_secs()) {
+            error!(
+                "{}: Failed to take snapshot: {}",
+                self.id.public_id(),
+                error
+            );
+        }
+    }
+
+    // Replies are best-effort: the sender may have given up waiting.
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Shutdown => {
+                trace!("{}: Shutdown command received", self);
+                self.start_drain(Instant::now());
+            }
+            Command::Export(file_name, reply_sender) => {
+                if let Some(client_handler) = self.client_handler_mut() {
+                    client_handler.flush();
+                }
+                let _ = reply_sender.send(self.export(&file_name));
+            }
+            Command::Status(reply_sender) => {
+                let _ = reply_sender.send(self.status());
+            }
+            Command::DisconnectClient(peer_addr, reply_sender) => {
+                let disconnected = self.client_handler_mut().map_or(false, |client_handler| {
+                    client_handler.disconnect_client(peer_addr)
+                });
+                let _ = reply_sender.send(disconnected);
+            }
+            Command::ReloadConfig(config, reply_sender) => {
+                let _ = reply_sender.send(self.reload_config(&config));
+            }
+            Command::Scrub(reply_sender) => {
+                let result = chunk_store::scrub(&self.root_dir)
+                    .map(|(checked, invalid)| ScrubReport { checked, invalid })
+                    .map_err(Error::from);
+                if let Ok(ref report) = result {
+                    info!(
+                        "{}: Scrub checked {} chunks, {} invalid",
+                        self,
+                        report.checked,
+                        report.invalid.len()
+                    );
+                }
+                let _ = reply_sender.send(result);
+            }
+            Command::DumpDiagnostics(reply_sender) => {
+                let _ = reply_sender.send(self.diagnostics());
+            }
+        }
+    }
+
+    fn start_drain(&mut self, now: Instant) {
+        if self.drain_deadline.is_some() {
+            info!("{}: Shutting down without waiting for the drain", self);
+            self.drain_deadline = Some(now);
+            return;
+        }
+        info!(
+            "{}: Shutting down, waiting up to {:?} for operations in progress",
+            self, DRAIN_TIMEOUT
+        );
+        self.drain_deadline = Some(now + DRAIN_TIMEOUT);
+        if let Some(client_handler) = self.client_handler_mut() {
+            client_handler.start_draining();
+        }
+    }
+
+    fn is_drain_finished(&self, now: Instant) -> bool {
+        match self.drain_deadline {
+            Some(deadline) => now >= deadline || self.pending_ops() == (0, 0),
+            None => false,
+        }
+    }
+
+    // Returns the numbers of client requests awaiting a response and of ImmutableData operations
+    // awaiting the chunk holders.
+    fn pending_ops(&self) -> (usize, usize) {
+        match &self.state {
+            State::Elder {
+                client_handler,
+                data_handler,
+                ..
+            } => (
+                client_handler.pending_request_count(),
+                data_handler.pending_idata_op_count(),
+            ),
+            State::Adult(_) => (0, 0),
+        }
+    }
+
+    fn finish_shutdown(&mut self) -> ShutdownStatus {
+        let (pending_client_requests, pending_idata_ops) = self.pending_ops();
+        if let Some(client_handler) = self.client_handler_mut() {
+            client_handler.disconnect_all_clients();
+            client_handler.flush();
+        }
+
+        let flushed = utils::sync_dbs(&self.root_dir)
+            .and_then(|()| chunk_store::sync_used_space(&self.root_dir).map_err(Error::from));
+        let status = if let Err(error) = flushed {
+            error!("{}: Failed to flush state to disk: {}", self, error);
+            ShutdownStatus::FlushFailed
+        } else if pending_client_requests == 0 && pending_idata_ops == 0 {
+            ShutdownStatus::Drained
+        } else {
+            ShutdownStatus::Incomplete {
+                pending_client_requests,
+                pending_idata_ops,
+            }
+        };
+        info!("{}: Stopped: {:?}", self, status);
+        status
+    }
+
+    fn status(&self) -> Status {
+        let used_space =
+            chunk_store::used_space_per_store(&self.root_dir).unwrap_or_else(|error| {
+                error!("{}: Failed to read used space: {}", self, error);
+                Default::default()
+            });
+        match &self.state {
+            State::Elder {
+                client_handler,
+                data_handler,
+                ..
+            } => Status {
+                role: Role::Elder,
+                connected_clients: client_handler.connected_clients(),
+                client_candidates: client_handler.client_candidate_count(),
+                used_space,
+                pending_client_requests: client_handler.pending_request_count(),
+                pending_idata_ops: data_handler.pending_idata_op_count(),
+            },
+            State::Adult(_) => Status {
+                role: Role::Adult,
+                connected_clients: Vec::new(),
+                client_candidates: 0,
+                used_space,
+                pending_client_requests: 0,
+                pending_idata_ops: 0,
+            },
+        }
+    }
+
+    fn diagnostics(&self) -> String {
+        let mut diagnostics = format!("{}\n{:#?}\n", self, self.status());
+        if let State::Elder {
+            client_handler,
+            data_handler,
+            ..
+        } = &self.state
+        {
+            diagnostics.push_str(&client_handler.diagnostics(Instant::now()));
+            diagnostics.push_str(&data_handler.diagnostics());
+        }
+        diagnostics
+    }
+
+    fn reload_config(&mut self, new_config: &Config) -> ReloadReport {
+        let (applied, needs_restart) = self.config.changes(new_config);
+        let previous = self.config.clone();
+        self.config.copy_settings(new_config, &applied);
+        let config = self.config.clone();
+
+        if let Some(client_handler) = self.client_handler_mut() {
+            client_handler.apply_config(&config);
+        }
+        if let Some(data_handler) = self.data_handler_mut() {
+            data_handler.apply_config(&config);
+        }
+        if config.verbose() != previous.verbose() {
+            verbosity::set_verbosity(config.verbose());
+        }
+        if config.snapshot_dir() != previous.snapshot_dir()
+            || config.snapshot_interval() != previous.snapshot_interval()
+            || config.snapshot_retention() != previous.snapshot_retention()
+        {
+            self.snapshots = Snapshots::new(&config, Instant::now());
+        }
+
+        info!(
+            "{}: Reloaded config, applied {:?}, needing a restart {:?}",
+            self, applied, needs_restart
+        );
+        ReloadReport {
+            applied,
+            needs_restart,
+        }
+    }
+
+    // The event loop is single-threaded and the DBs are dumped on every change, so the directory is
+    // consistent while we're handling this.
+    fn export(&self, file_name: &Path) -> Result<()> {
+        let export_dir = self.config.export_dir().ok_or_else(|| {
+            Error::InvalidExportPath("exports are disabled, as export-dir is not set".to_string())
+        })?;
+        let mut components = file_name.components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => (),
+            _ => {
+                return Err(Error::InvalidExportPath(format!(
+                    "{} is not a plain file name",
+                    file_name.display()
+                )))
+            }
+        }
+        let archive_path = export_dir.join(file_name);
+        info!("{}: Exporting to {}", self, archive_path.display());
+        fs::create_dir_all(&export_dir)?;
+        archive::export(&self.root_dir, &archive_path).map(|_| ())
+    }
+
     fn step(&mut self, event: Event) {
         let mut maybe_action = self.handle_quic_p2p_event(event);
         while let Some(action) = maybe_action {
@@ -187,7 +491,8 @@ impl Vault {
             Event::SentUserMessage { peer_addr, .. } => {
                 trace!("{}: Succesfully sent message to: {}", self, peer_addr);
             }
-            Event::UnsentUserMessage { peer_addr, .. } => {
+            Event::UnsentUserMessage { peer_addr, msg, .. } => {
+                client_handler.handle_unsent_message(peer_addr, msg);
                 info!("{}: Not sent message to: {}", self, peer_addr);
             }
             Event::BootstrapFailure | Event::BootstrappedTo { .. } | Event::Finish => {
@@ -199,6 +504,7 @@ impl Vault {
 
     fn handle_action(&mut self, action: Action) -> Option<Action> {
         use Action::*;
+        let _log_context = log_context::enter_rpc(action.rpc());
         match action {
             ForwardClientRequest(rpc) => self.forward_client_request(rpc),
             ProxyClientRequest(rpc) => self.proxy_client_request(rpc),
@@ -420,8 +726,8 @@ impl Vault {
     }
 
     /// Returns Some((is_elder, ID)) or None if file doesn't exist.
-    fn read_state(config: &Config) -> Result<Option<(bool, NodeFullId)>> {
-        let path = config.root_dir().join(STATE_FILENAME);
+    pub(crate) fn read_state(root_dir: &Path) -> Result<Option<(bool, NodeFullId)>> {
+        let path = root_dir.join(STATE_FILENAME);
         if !path.is_file() {
             return Ok(None);
         }
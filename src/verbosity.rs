@@ -0,0 +1,63 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under The General Public License (GPL), version 3.
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied. Please review the Licences for the specific language governing
+// permissions and limitations relating to use of the SAFE Network Software.
+
+//! The log level set with `--verbose`, which applies to this crate's records only and, unlike
+//! `RUST_LOG`, can change while the vault runs.
+
+use log::{Level, Metadata};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Target of this crate's log records, and the prefix of its modules' targets.
+const CRATE_TARGET: &str = "safe_vault";
+/// Stored in `LEVEL` while no verbosity is set.
+const UNSET: usize = 0;
+
+static LEVEL: AtomicUsize = AtomicUsize::new(UNSET);
+
+/// Sets the level up to which this crate's records are logged, overriding `RUST_LOG` for them.
+pub fn set_verbosity(level: Level) {
+    LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+/// Returns whether a record with the given metadata is within the verbosity, or `None` if the
+/// verbosity doesn't apply to it: it's from another crate, or no verbosity was set.
+pub fn verbosity_allows(metadata: &Metadata) -> Option<bool> {
+    let level = LEVEL.load(Ordering::Relaxed);
+    let target = metadata.target();
+    let is_ours = target == CRATE_TARGET
+        || (target.starts_with(CRATE_TARGET) && target[CRATE_TARGET.len()..].starts_with("::"));
+    if level == UNSET || !is_ours {
+        return None;
+    }
+    Some(metadata.level() as usize <= level)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verbosity_applies_to_this_crate_only() {
+        let metadata = |level, target| Metadata::builder().level(level).target(target).build();
+        set_verbosity(Level::Info);
+
+        assert_eq!(
+            verbosity_allows(&metadata(Level::Info, "safe_vault::vault")),
+            Some(true)
+        );
+        assert_eq!(
+            verbosity_allows(&metadata(Level::Debug, "safe_vault")),
+            Some(false)
+        );
+        assert_eq!(
+            verbosity_allows(&metadata(Level::Debug, "safe_vault_extra")),
+            None
+        );
+        assert_eq!(verbosity_allows(&metadata(Level::Trace, "quic_p2p")), None);
+    }
+}
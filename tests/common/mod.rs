@@ -24,7 +24,7 @@ use safe_nd::{
 use safe_vault::{
     mock::Network,
     quic_p2p::{self, Builder, Event, NodeInfo, OurType, Peer, QuicP2p},
-    Config, Vault,
+    Config, SessionToken, Vault, VaultMessage, VaultRequest, VaultResponse,
 };
 use serde::Serialize;
 use std::{
@@ -34,6 +34,7 @@ use std::{
     net::SocketAddr,
     ops::{Deref, DerefMut},
     slice,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use tempdir::TempDir;
 use unwrap::unwrap;
@@ -52,6 +53,11 @@ pub struct Environment {
 
 impl Environment {
     pub fn new() -> Self {
+        Self::with_config(Config::default())
+    }
+
+    // Creates the environment with its vault using `config`, apart from the root directory.
+    pub fn with_config(config: Config) -> Self {
         let do_format = move |formatter: &mut Formatter, record: &Record<'_>| {
             let now = formatter.timestamp();
             writeln!(
@@ -76,7 +82,7 @@ impl Environment {
         Self {
             rng,
             network: Network::new(network_rng),
-            vault: TestVault::new(),
+            vault: TestVault::new(config),
         }
     }
 
@@ -99,6 +105,32 @@ impl Environment {
         client
     }
 
+    pub fn new_disconnected_client(&mut self) -> TestClient {
+        TestClient::new_disconnected(&mut self.rng)
+    }
+
+    // Returns two connected clients with the same identity, e.g. one account used on two devices.
+    pub fn new_connected_client_pair(&mut self) -> (TestClient, TestClient) {
+        let mut clients = self.new_disconnected_clients_with_same_id(2);
+        for client in &mut clients {
+            self.establish_connection(client);
+        }
+        let second = unwrap!(clients.pop());
+        let first = unwrap!(clients.pop());
+        (first, second)
+    }
+
+    // Returns `count` disconnected clients with the same identity.
+    pub fn new_disconnected_clients_with_same_id(&mut self, count: usize) -> Vec<TestClient> {
+        // The clients derive their keys from identical RNG states, so they share a `ClientFullId`.
+        let rng = self.rng.clone();
+        let mut clients = vec![TestClient::new_disconnected(&mut self.rng)];
+        for _ in 1..count {
+            clients.push(TestClient::new_disconnected(&mut rng.clone()));
+        }
+        clients
+    }
+
     pub fn new_connected_app(&mut self, owner: ClientPublicId) -> TestApp {
         let mut app = TestApp::new_disconnected(&mut self.rng, owner);
         self.establish_connection(&mut app);
@@ -109,6 +141,8 @@ impl Environment {
         TestApp::new_disconnected(&mut self.rng, owner)
     }
 
+    // Connects the client and passes the challenge.  As for a client which only speaks the
+    // `safe_nd` protocol, the vault sends nothing further.
     pub fn establish_connection<T: TestClientTrait>(&mut self, client: &mut T) {
         let conn_info = self.vault.connection_info();
         client.quic_p2p().connect_to(conn_info.clone());
@@ -117,6 +151,54 @@ impl Environment {
         client.expect_connected_to(&conn_info);
         client.handle_challenge_from(&conn_info);
         self.poll();
+
+        client.expect_no_new_message();
+    }
+
+    // Asks for the token of the session of the client's connection.
+    pub fn start_session<T: TestClientTrait>(&mut self, client: &mut T) -> SessionToken {
+        let message_id = client.send_vault_request(VaultRequest::StartSession);
+        self.poll();
+        match client.expect_vault_response(message_id) {
+            VaultResponse::SessionStarted(Ok(token)) => token,
+            response => unexpected!(response),
+        }
+    }
+
+    // Runs the vault's periodic housekeeping as if `elapsed` had passed.
+    pub fn handle_timeout_after(&mut self, elapsed: Duration) {
+        self.vault.inner.handle_timeout_at(Instant::now() + elapsed);
+        self.poll();
+    }
+
+    // Connects the client and passes the challenge, expecting the vault to drop the connection
+    // afterwards.
+    pub fn expect_connection_refused<T: TestClientTrait>(&mut self, client: &mut T) {
+        let conn_info = self.vault.connection_info();
+        client.quic_p2p().connect_to(conn_info.clone());
+        self.poll();
+
+        client.expect_connected_to(&conn_info);
+        client.handle_challenge_from(&conn_info);
+        self.poll();
+
+        client.expect_connection_failure_from(&conn_info);
+        client.expect_no_new_message();
+    }
+
+    // Drops the client's connection to the vault.
+    pub fn disconnect<T: TestClientTrait>(&mut self, client: &mut T) {
+        let peer_addr = client.connected_vault().peer_addr;
+        client.quic_p2p().disconnect_from(peer_addr);
+        self.poll();
+    }
+
+    /// Tries to create another vault using the same root directory as the running one.
+    pub fn new_vault_in_same_root_dir(&self) -> safe_vault::Result<Vault> {
+        let mut config = Config::default();
+        config.set_root_dir(self.vault.root_dir.path());
+        let (_, command_rx) = crossbeam_channel::bounded(0);
+        Vault::new(config, command_rx)
     }
 }
 
@@ -126,24 +208,19 @@ trait AsMutSlice<T> {
 
 struct TestVault {
     inner: Vault,
-    _root_dir: TempDir,
+    root_dir: TempDir,
 }
 
 impl TestVault {
-    fn new() -> Self {
+    fn new(mut config: Config) -> Self {
         let root_dir = unwrap!(TempDir::new("safe_vault"));
-
-        let mut config = Config::default();
         config.set_root_dir(root_dir.path());
 
         let (_, command_rx) = crossbeam_channel::bounded(0);
 
         let inner = unwrap!(Vault::new(config, command_rx));
 
-        Self {
-            inner,
-            _root_dir: root_dir,
-        }
+        Self { inner, root_dir }
     }
 
     fn connection_info(&mut self) -> NodeInfo {
@@ -218,6 +295,15 @@ pub trait TestClientTrait {
         }
     }
 
+    fn expect_connection_failure_from(&self, conn_info: &NodeInfo) {
+        match self.rx().try_recv() {
+            Ok(Event::ConnectionFailure { peer_addr, .. }) => {
+                assert_eq!(peer_addr, conn_info.peer_addr)
+            }
+            x => unexpected!(x),
+        }
+    }
+
     fn handle_challenge_from(&mut self, conn_info: &NodeInfo) {
         let (sender, bytes) = self.expect_new_message();
         assert_eq!(sender, conn_info.peer_addr);
@@ -293,6 +379,83 @@ pub trait TestClientTrait {
         }
     }
 
+    fn send_vault_request(&mut self, request: VaultRequest) -> MessageId {
+        self.send_vault_request_at(request, unix_time_secs())
+    }
+
+    // Sends `request` as if it had been made at `timestamp`, in seconds since the Unix epoch.
+    fn send_vault_request_at(&mut self, request: VaultRequest, timestamp: u64) -> MessageId {
+        let message_id = MessageId::new();
+        let to_sign = unwrap!(bincode::serialize(&(&request, &message_id, &timestamp)));
+        let signature = self.full_id().sign(&to_sign);
+
+        let msg = VaultMessage::Request {
+            request,
+            message_id,
+            timestamp,
+            signature,
+        };
+        let node_info = self.connected_vault();
+        self.quic_p2p()
+            .send(Peer::Node { node_info }, Bytes::from(msg.serialise()), 0);
+
+        message_id
+    }
+
+    fn expect_vault_message(&mut self) -> VaultMessage {
+        let bytes = self.expect_new_message().1;
+        unwrap!(VaultMessage::deserialise(&bytes))
+    }
+
+    fn expect_vault_response(&mut self, expected_message_id: MessageId) -> VaultResponse {
+        match self.expect_vault_message() {
+            VaultMessage::Response {
+                response,
+                message_id,
+            } => {
+                assert_eq!(
+                    message_id, expected_message_id,
+                    "Received VaultResponse with unexpected MessageId."
+                );
+                response
+            }
+            message => unexpected!(message),
+        }
+    }
+
+    // Expects the successful response to `ResumeSession` and `count` responses replayed from the
+    // session, in any order.  Returns the replayed responses.
+    fn expect_resumed_session(
+        &mut self,
+        resume_id: MessageId,
+        count: usize,
+    ) -> Vec<(MessageId, Response)> {
+        let mut resumed = false;
+        let mut replayed = Vec::new();
+        for _ in 0..=count {
+            let bytes = self.expect_new_message().1;
+            if VaultMessage::is_vault_message(&bytes) {
+                match unwrap!(VaultMessage::deserialise(&bytes)) {
+                    VaultMessage::Response {
+                        response: VaultResponse::Mutation(Ok(())),
+                        message_id,
+                    } if message_id == resume_id && !resumed => resumed = true,
+                    message => unexpected!(message),
+                }
+            } else {
+                match unwrap!(bincode::deserialize(&bytes)) {
+                    Message::Response {
+                        response,
+                        message_id,
+                    } => replayed.push((message_id, response)),
+                    message => unexpected!(message),
+                }
+            }
+        }
+        self.expect_no_new_message();
+        replayed
+    }
+
     fn expect_notification(&mut self) -> Notification {
         let bytes = self.expect_new_message().1;
         let message: Message = unwrap!(bincode::deserialize(&bytes));
@@ -461,6 +624,19 @@ pub fn perform_mutation<T: TestClientTrait>(
     get_from_response::<_, ()>(env, client, request);
 }
 
+pub fn perform_vault_mutation<T: TestClientTrait>(
+    env: &mut Environment,
+    client: &mut T,
+    request: VaultRequest,
+) {
+    let message_id = client.send_vault_request(request);
+    env.poll();
+    assert_eq!(
+        client.expect_vault_response(message_id),
+        VaultResponse::Mutation(Ok(()))
+    );
+}
+
 pub fn send_request_expect_ok<T, D>(
     env: &mut Environment,
     client: &mut T,
@@ -558,6 +734,10 @@ pub fn transfer_coins(
     assert_eq!(actual, expected);
 }
 
+pub fn unix_time_secs() -> u64 {
+    unwrap!(SystemTime::now().duration_since(UNIX_EPOCH)).as_secs()
+}
+
 pub fn gen_public_key(rng: &mut TestRng) -> PublicKey {
     *ClientFullId::new_ed25519(rng).public_id().public_key()
 }
@@ -62,13 +62,17 @@ use safe_nd::{
     ADataUnpubPermissions, ADataUser, AppPermissions, AppendOnlyData, ClientFullId, Coins,
     EntryError, Error as NdError, IData, IDataAddress, LoginPacket, MData, MDataAction,
     MDataAddress, MDataEntries, MDataKind, MDataPermissionSet, MDataSeqEntryActions, MDataSeqValue,
-    MDataUnseqEntryActions, MDataValue, MDataValues, Message, MessageId, PubImmutableData,
-    PubSeqAppendOnlyData, PubUnseqAppendOnlyData, PublicKey, Request, Response, Result as NdResult,
-    SeqAppendOnly, SeqMutableData, Transaction, UnpubImmutableData, UnpubSeqAppendOnlyData,
-    UnpubUnseqAppendOnlyData, UnseqAppendOnly, UnseqMutableData, XorName,
+    MDataUnseqEntryActions, MDataValue, MDataValues, Message, MessageId, Notification,
+    PubImmutableData, PubSeqAppendOnlyData, PubUnseqAppendOnlyData, PublicKey, Request, Response,
+    Result as NdResult, SeqAppendOnly, SeqMutableData, Transaction, UnpubImmutableData,
+    UnpubSeqAppendOnlyData, UnpubUnseqAppendOnlyData, UnseqAppendOnly, UnseqMutableData, XorName,
 };
-use safe_vault::COST_OF_PUT;
-use std::collections::{BTreeMap, BTreeSet};
+use safe_vault::{
+    AppRestrictions, AuditEvent, Config, Error as VaultError, MultisigPolicy, RecoveryConfig,
+    TransferProposal, VaultMessage, VaultNotification, VaultRequest, VaultResponse, COST_OF_PUT,
+    MAX_BUFFERED_RESPONSES, SESSION_GRACE_PERIOD,
+};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use unwrap::unwrap;
 
 #[test]
@@ -78,6 +82,15 @@ fn client_connects() {
     let _app = env.new_connected_app(client.public_id().clone());
 }
 
+#[test]
+fn root_dir_is_locked_while_vault_runs() {
+    let env = Environment::new();
+    match env.new_vault_in_same_root_dir() {
+        Err(VaultError::RootDirInUse(_)) => (),
+        result => unexpected!(result.map(|_| ())),
+    }
+}
+
 #[test]
 fn invalid_signature() {
     let mut env = Environment::new();
@@ -117,6 +130,279 @@ fn invalid_signature() {
     }
 }
 
+#[test]
+fn multiple_connections_per_client() {
+    let mut env = Environment::new();
+    let (mut device_a, mut device_b) = env.new_connected_client_pair();
+
+    // Notifications are sent to every connection of the client, but the response only to the
+    // connection which sent the request.
+    let amount = unwrap!(Coins::from_nano(10));
+    let transaction_id = 1;
+    let message_id = device_a.send_request(Request::CreateBalance {
+        new_balance_owner: *device_a.public_id().public_key(),
+        amount,
+        transaction_id,
+    });
+    env.poll();
+
+    let expected = Transaction {
+        id: transaction_id,
+        amount,
+    };
+    assert_eq!(device_a.expect_notification(), Notification(expected));
+    assert_eq!(device_b.expect_notification(), Notification(expected));
+    match device_a.expect_response(message_id) {
+        Response::Transaction(Ok(transaction)) => assert_eq!(transaction, expected),
+        x => unexpected!(x),
+    }
+    device_b.expect_no_new_message();
+
+    common::send_request_expect_ok(&mut env, &mut device_b, Request::GetBalance, amount);
+    device_a.expect_no_new_message();
+}
+
+#[test]
+fn max_connections_per_client() {
+    let mut config = Config::default();
+    config.set_max_connections_per_client(2);
+    let mut env = Environment::with_config(config);
+    let mut devices = env.new_disconnected_clients_with_same_id(3);
+    for device in &mut devices[..2] {
+        env.establish_connection(device);
+    }
+
+    // The connection over the limit is dropped once it has passed the challenge.
+    env.expect_connection_refused(&mut devices[2]);
+
+    // The existing connections still get their responses.
+    for device in &mut devices[..2] {
+        common::send_request_expect_err(
+            &mut env,
+            device,
+            Request::GetBalance,
+            NdError::NoSuchBalance,
+        );
+    }
+
+    // Once one of them drops, there's room for another.
+    env.disconnect(&mut devices[0]);
+    env.establish_connection(&mut devices[2]);
+    common::send_request_expect_err(
+        &mut env,
+        &mut devices[2],
+        Request::GetBalance,
+        NdError::NoSuchBalance,
+    );
+}
+
+#[test]
+fn resume_session() {
+    let mut env = Environment::new();
+    let mut other_client = env.new_connected_client();
+    let mut devices = env.new_disconnected_clients_with_same_id(2);
+    let mut restarted = unwrap!(devices.pop());
+    let mut client = unwrap!(devices.pop());
+    env.establish_connection(&mut client);
+    common::create_balance(&mut env, &mut client, None, 10);
+    let token = env.start_session(&mut client);
+
+    // The connection drops while the request is in flight, so the response can't be delivered.
+    let message_id = client.send_request(Request::GetBalance);
+    drop(client);
+    env.poll();
+
+    env.establish_connection(&mut restarted);
+
+    // Only the client which owned the session can resume it.
+    let other_id = other_client.send_vault_request(VaultRequest::ResumeSession(token));
+    env.poll();
+    assert_eq!(
+        other_client.expect_vault_response(other_id),
+        VaultResponse::Mutation(Err(NdError::AccessDenied))
+    );
+
+    // Resuming the session delivers the buffered response.
+    let resume_id = restarted.send_vault_request(VaultRequest::ResumeSession(token));
+    env.poll();
+    let replayed = restarted.expect_resumed_session(resume_id, 1);
+    assert_eq!(
+        replayed,
+        vec![(
+            message_id,
+            Response::GetBalance(Ok(unwrap!(Coins::from_nano(10))))
+        )]
+    );
+
+    // A session can only be resumed once.
+    let message_id = restarted.send_vault_request(VaultRequest::ResumeSession(token));
+    env.poll();
+    assert_eq!(
+        restarted.expect_vault_response(message_id),
+        VaultResponse::Mutation(Err(NdError::AccessDenied))
+    );
+}
+
+#[test]
+fn resume_session_with_too_many_buffered_responses() {
+    let mut env = Environment::new();
+    let mut devices = env.new_disconnected_clients_with_same_id(2);
+    let mut restarted = unwrap!(devices.pop());
+    let mut client = unwrap!(devices.pop());
+    env.establish_connection(&mut client);
+    let token = env.start_session(&mut client);
+
+    let message_ids: Vec<_> = (0..=MAX_BUFFERED_RESPONSES)
+        .map(|_| client.send_request(Request::GetBalance))
+        .collect();
+    drop(client);
+    env.poll();
+
+    // Only the most recent responses are kept.
+    env.establish_connection(&mut restarted);
+    let resume_id = restarted.send_vault_request(VaultRequest::ResumeSession(token));
+    env.poll();
+    let replayed = restarted.expect_resumed_session(resume_id, MAX_BUFFERED_RESPONSES);
+    let replayed_ids: HashSet<_> = replayed
+        .into_iter()
+        .map(|(message_id, response)| {
+            assert_eq!(response, Response::GetBalance(Err(NdError::NoSuchBalance)));
+            message_id
+        })
+        .collect();
+    assert_eq!(replayed_ids.len(), MAX_BUFFERED_RESPONSES);
+    assert!(replayed_ids
+        .iter()
+        .all(|message_id| message_ids.contains(message_id)));
+}
+
+#[test]
+fn resume_expired_session() {
+    let mut env = Environment::new();
+    let mut devices = env.new_disconnected_clients_with_same_id(2);
+    let mut restarted = unwrap!(devices.pop());
+    let mut client = unwrap!(devices.pop());
+    env.establish_connection(&mut client);
+    let token = env.start_session(&mut client);
+
+    let _ = client.send_request(Request::GetBalance);
+    drop(client);
+    env.poll();
+
+    // The session and its buffered response are gone once the grace period has passed.
+    env.handle_timeout_after(SESSION_GRACE_PERIOD);
+    env.establish_connection(&mut restarted);
+    let message_id = restarted.send_vault_request(VaultRequest::ResumeSession(token));
+    env.poll();
+    assert_eq!(
+        restarted.expect_vault_response(message_id),
+        VaultResponse::Mutation(Err(NdError::AccessDenied))
+    );
+    restarted.expect_no_new_message();
+}
+
+#[test]
+fn rate_limited_requests() {
+    let mut config = Config::default();
+    config.set_client_rate_limit(1);
+    let mut env = Environment::with_config(config);
+    let mut client = env.new_connected_client();
+
+    common::send_request_expect_err(
+        &mut env,
+        &mut client,
+        Request::GetBalance,
+        NdError::NoSuchBalance,
+    );
+
+    // A `safe_nd` request over the limit gets an error response the client can decode.
+    let address = IDataAddress::Pub(env.rng().gen());
+    let message_id = client.send_request(Request::GetIData(address));
+    env.poll();
+    match client.expect_response(message_id) {
+        Response::GetIData(Err(NdError::NetworkOther(_))) => (),
+        x => unexpected!(x),
+    }
+
+    // A `VaultRequest` over the limit is told when to retry.
+    let message_id = client.send_vault_request(VaultRequest::GetAuditLog);
+    env.poll();
+    match client.expect_vault_response(message_id) {
+        VaultResponse::RateLimitExceeded { retry_after_ms } => assert!(retry_after_ms > 0),
+        x => unexpected!(x),
+    }
+}
+
+#[test]
+fn replayed_request() {
+    let mut env = Environment::new();
+    let mut client_a = env.new_connected_client();
+    let mut client_b = env.new_connected_client();
+
+    common::create_balance(&mut env, &mut client_a, None, 10);
+    common::create_balance(&mut env, &mut client_a, Some(&mut client_b), 1);
+
+    let request = Request::TransferCoins {
+        destination: *client_b.public_id().name(),
+        amount: unwrap!(Coins::from_nano(2)),
+        transaction_id: 3,
+    };
+    let message_id = MessageId::new();
+    let signature = client_a.sign(unwrap!(bincode::serialize(&(&request, &message_id))));
+    let message = Message::Request {
+        request,
+        message_id,
+        signature: Some(signature),
+    };
+
+    client_a.send(&message);
+    env.poll();
+    let _ = client_b.expect_notification();
+    match client_a.expect_response(message_id) {
+        Response::Transaction(Ok(_)) => (),
+        x => unexpected!(x),
+    }
+
+    // Sending the same signed message again must not transfer the coins a second time.
+    client_a.send(&message);
+    env.poll();
+    match client_a.expect_response(message_id) {
+        Response::Transaction(Err(NdError::DuplicateMessageId)) => (),
+        x => unexpected!(x),
+    }
+    client_b.expect_no_new_message();
+
+    let balance_a = unwrap!(Coins::from_nano(6));
+    let balance_b = unwrap!(Coins::from_nano(3));
+    common::send_request_expect_ok(&mut env, &mut client_a, Request::GetBalance, balance_a);
+    common::send_request_expect_ok(&mut env, &mut client_b, Request::GetBalance, balance_b);
+}
+
+#[test]
+fn stale_vault_request() {
+    let mut env = Environment::new();
+    let mut client = env.new_connected_client();
+    let now = common::unix_time_secs();
+
+    // Requests made too long ago, or too far in the future, are rejected, as the vault may no
+    // longer remember their IDs.
+    for timestamp in &[now - 60 * 60, now + 60 * 60] {
+        let message_id = client.send_vault_request_at(VaultRequest::GetAuditLog, *timestamp);
+        env.poll();
+        match client.expect_vault_response(message_id) {
+            VaultResponse::GetAuditLog(Err(NdError::NetworkOther(_))) => (),
+            x => unexpected!(x),
+        }
+    }
+
+    let message_id = client.send_vault_request_at(VaultRequest::GetAuditLog, now);
+    env.poll();
+    match client.expect_vault_response(message_id) {
+        VaultResponse::GetAuditLog(Ok(_)) => (),
+        x => unexpected!(x),
+    }
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 //
 // Login packets
@@ -319,6 +605,207 @@ fn update_login_packet() {
     }
 }
 
+#[test]
+fn login_packet_versions() {
+    let mut env = Environment::new();
+    let mut client = env.new_connected_client();
+
+    common::create_balance(&mut env, &mut client, None, *COST_OF_PUT);
+
+    let login_packet_locator: XorName = env.rng().gen();
+    let client_public_key = *client.public_id().public_key();
+    let new_login_packet = |client: &common::TestClient, data: Vec<u8>| {
+        let signature = client.sign(&data);
+        unwrap!(LoginPacket::new(
+            login_packet_locator,
+            client_public_key,
+            data,
+            signature,
+        ))
+    };
+
+    let login_packet = new_login_packet(&client, vec![0; 32]);
+    common::perform_mutation(
+        &mut env,
+        &mut client,
+        Request::CreateLoginPacket(login_packet),
+    );
+
+    // An update based on the current version succeeds.
+    let login_packet = new_login_packet(&client, vec![1; 32]);
+    let message_id = client.send_vault_request(VaultRequest::UpdateLoginPacket {
+        login_packet,
+        expected_version: 0,
+    });
+    env.poll();
+    assert_eq!(
+        client.expect_vault_response(message_id),
+        VaultResponse::LoginPacketVersion(Ok(1))
+    );
+
+    // One based on a stale version is rejected.
+    let login_packet = new_login_packet(&client, vec![2; 32]);
+    let message_id = client.send_vault_request(VaultRequest::UpdateLoginPacket {
+        login_packet,
+        expected_version: 0,
+    });
+    env.poll();
+    assert_eq!(
+        client.expect_vault_response(message_id),
+        VaultResponse::LoginPacketVersion(Err(NdError::InvalidSuccessor(1)))
+    );
+
+    let message_id =
+        client.send_vault_request(VaultRequest::ListLoginPacketVersions(login_packet_locator));
+    env.poll();
+    assert_eq!(
+        client.expect_vault_response(message_id),
+        VaultResponse::LoginPacketVersions(Ok((1, vec![0])))
+    );
+
+    // Rolling back restores the original packet as a new version.
+    let message_id = client.send_vault_request(VaultRequest::RollbackLoginPacket {
+        name: login_packet_locator,
+        version: 0,
+    });
+    env.poll();
+    assert_eq!(
+        client.expect_vault_response(message_id),
+        VaultResponse::LoginPacketVersion(Ok(2))
+    );
+    let (data, _) = common::get_from_response(
+        &mut env,
+        &mut client,
+        Request::GetLoginPacket(login_packet_locator),
+    );
+    assert_eq!(data, vec![0; 32]);
+
+    // Other clients can't see or roll back the versions.
+    let mut other_client = env.new_connected_client();
+    let message_id = other_client
+        .send_vault_request(VaultRequest::ListLoginPacketVersions(login_packet_locator));
+    env.poll();
+    assert_eq!(
+        other_client.expect_vault_response(message_id),
+        VaultResponse::LoginPacketVersions(Err(NdError::AccessDenied))
+    );
+    let message_id = other_client.send_vault_request(VaultRequest::RollbackLoginPacket {
+        name: login_packet_locator,
+        version: 1,
+    });
+    env.poll();
+    assert_eq!(
+        other_client.expect_vault_response(message_id),
+        VaultResponse::LoginPacketVersion(Err(NdError::AccessDenied))
+    );
+}
+
+#[test]
+fn login_packet_recovery() {
+    let mut env = Environment::new();
+    let mut owner = env.new_connected_client();
+    let mut recoverer_a = env.new_connected_client();
+    let mut recoverer_b = env.new_connected_client();
+    let mut outsider = env.new_connected_client();
+
+    common::create_balance(&mut env, &mut owner, None, *COST_OF_PUT);
+
+    let login_packet_locator: XorName = env.rng().gen();
+    let data = vec![0; 32];
+    let login_packet = unwrap!(LoginPacket::new(
+        login_packet_locator,
+        *owner.public_id().public_key(),
+        data.clone(),
+        owner.sign(&data),
+    ));
+    common::perform_mutation(
+        &mut env,
+        &mut owner,
+        Request::CreateLoginPacket(login_packet),
+    );
+
+    let keys = vec![
+        *recoverer_a.public_id().public_key(),
+        *recoverer_b.public_id().public_key(),
+    ]
+    .into_iter()
+    .collect();
+    let delay_secs = 24 * 60 * 60;
+    common::perform_vault_mutation(
+        &mut env,
+        &mut owner,
+        VaultRequest::SetRecoveryConfig {
+            name: login_packet_locator,
+            config: unwrap!(RecoveryConfig::new(keys, 2, delay_secs)),
+        },
+    );
+
+    // The replacement is handed to a new key.
+    let new_owner = ClientFullId::new_ed25519(env.rng());
+    let data = vec![1; 32];
+    let new_login_packet = unwrap!(LoginPacket::new(
+        login_packet_locator,
+        *new_owner.public_id().public_key(),
+        data.clone(),
+        new_owner.sign(&data),
+    ));
+
+    let message_id =
+        outsider.send_vault_request(VaultRequest::ApproveRecovery(new_login_packet.clone()));
+    env.poll();
+    assert_eq!(
+        outsider.expect_vault_response(message_id),
+        VaultResponse::RecoveryApproval(Err(NdError::AccessDenied))
+    );
+
+    let message_id =
+        recoverer_a.send_vault_request(VaultRequest::ApproveRecovery(new_login_packet.clone()));
+    env.poll();
+    assert_eq!(
+        recoverer_a.expect_vault_response(message_id),
+        VaultResponse::RecoveryApproval(Ok(None))
+    );
+    owner.expect_no_new_message();
+
+    // Once the threshold is reached, the owner is notified.
+    let message_id =
+        recoverer_b.send_vault_request(VaultRequest::ApproveRecovery(new_login_packet));
+    env.poll();
+    let due_at = match recoverer_b.expect_vault_response(message_id) {
+        VaultResponse::RecoveryApproval(Ok(Some(due_at))) => due_at,
+        response => unexpected!(response),
+    };
+    match owner.expect_vault_message() {
+        VaultMessage::Notification(VaultNotification::RecoveryApproved {
+            name,
+            due_at: notified_due_at,
+        }) => {
+            assert_eq!(name, login_packet_locator);
+            assert_eq!(notified_due_at, due_at);
+        }
+        message => unexpected!(message),
+    }
+
+    // Only the owner can veto the recovery, and only while it's in progress.
+    let message_id = outsider.send_vault_request(VaultRequest::VetoRecovery(login_packet_locator));
+    env.poll();
+    assert_eq!(
+        outsider.expect_vault_response(message_id),
+        VaultResponse::Mutation(Err(NdError::AccessDenied))
+    );
+    common::perform_vault_mutation(
+        &mut env,
+        &mut owner,
+        VaultRequest::VetoRecovery(login_packet_locator),
+    );
+    let message_id = owner.send_vault_request(VaultRequest::VetoRecovery(login_packet_locator));
+    env.poll();
+    assert_eq!(
+        owner.expect_vault_response(message_id),
+        VaultResponse::Mutation(Err(NdError::InvalidOperation))
+    );
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 //
 // Coins
@@ -501,6 +988,73 @@ fn coin_operations_by_app() {
     );
 }
 
+#[test]
+fn app_spending_allowance() {
+    let mut env = Environment::new();
+    let mut owner = env.new_connected_client();
+    common::create_balance(&mut env, &mut owner, None, 10);
+
+    let mut app = env.new_disconnected_app(owner.public_id().clone());
+    common::perform_mutation(
+        &mut env,
+        &mut owner,
+        Request::InsAuthKey {
+            key: *app.public_id().public_key(),
+            version: 1,
+            permissions: AppPermissions {
+                transfer_coins: true,
+            },
+        },
+    );
+    env.establish_connection(&mut app);
+
+    let restrictions = AppRestrictions {
+        spending_allowance: Some(unwrap!(Coins::from_nano(5))),
+        ..Default::default()
+    };
+    let request = VaultRequest::SetAppRestrictions {
+        key: *app.public_id().public_key(),
+        restrictions,
+    };
+
+    // Apps can't set restrictions themselves.
+    let message_id = app.send_vault_request(request.clone());
+    env.poll();
+    assert_eq!(
+        app.expect_vault_response(message_id),
+        VaultResponse::Mutation(Err(NdError::AccessDenied))
+    );
+    common::perform_vault_mutation(&mut env, &mut owner, request);
+
+    // A failed transfer doesn't use up the allowance.
+    let client_b = env.new_connected_client();
+    common::send_request_expect_err(
+        &mut env,
+        &mut app,
+        Request::TransferCoins {
+            destination: *client_b.public_id().name(),
+            amount: unwrap!(Coins::from_nano(4)),
+            transaction_id: 1,
+        },
+        NdError::NoSuchBalance,
+    );
+
+    let mut client_c = env.new_connected_client();
+    common::create_balance(&mut env, &mut client_c, None, 0);
+    common::transfer_coins(&mut env, &mut app, &mut client_c, 4, 2);
+    common::send_request_expect_err(
+        &mut env,
+        &mut app,
+        Request::TransferCoins {
+            destination: *client_c.public_id().name(),
+            amount: unwrap!(Coins::from_nano(2)),
+            transaction_id: 3,
+        },
+        NdError::AccessDenied,
+    );
+    common::transfer_coins(&mut env, &mut app, &mut client_c, 1, 4);
+}
+
 #[test]
 fn coin_operations_by_app_with_insufficient_permissions() {
     let mut env = Environment::new();
@@ -2275,6 +2829,162 @@ fn auth_keys() {
     list_keys(&mut env, &mut owner, Ok((expected_map, 3)));
 }
 
+#[test]
+fn audit_log() {
+    let mut env = Environment::new();
+    let mut owner = env.new_connected_client();
+    common::create_balance(&mut env, &mut owner, None, 10);
+
+    let mut app = env.new_disconnected_app(owner.public_id().clone());
+    let app_key = *app.public_id().public_key();
+    let permissions = AppPermissions {
+        transfer_coins: true,
+    };
+    common::perform_mutation(
+        &mut env,
+        &mut owner,
+        Request::InsAuthKey {
+            key: app_key,
+            version: 1,
+            permissions,
+        },
+    );
+    env.establish_connection(&mut app);
+
+    // Only the successful mutation by the app is logged.
+    let unpub_idata = IData::Unpub(UnpubImmutableData::new(vec![1], app_key));
+    common::send_request_expect_err(
+        &mut env,
+        &mut app,
+        Request::PutIData(unpub_idata),
+        NdError::InvalidOwners,
+    );
+    let pub_idata = IData::Pub(PubImmutableData::new(vec![2]));
+    common::perform_mutation(&mut env, &mut app, Request::PutIData(pub_idata.clone()));
+
+    // The app can't read its owner's log.
+    let message_id = app.send_vault_request(VaultRequest::GetAuditLog);
+    env.poll();
+    assert_eq!(
+        app.expect_vault_response(message_id),
+        VaultResponse::GetAuditLog(Err(NdError::AccessDenied))
+    );
+
+    let message_id = owner.send_vault_request(VaultRequest::GetAuditLog);
+    env.poll();
+    let entries = match owner.expect_vault_response(message_id) {
+        VaultResponse::GetAuditLog(Ok(entries)) => entries,
+        response => unexpected!(response),
+    };
+    let events: Vec<_> = entries.into_iter().map(|entry| entry.event).collect();
+    match events.as_slice() {
+        [AuditEvent::AuthKeyInserted {
+            key,
+            permissions: inserted_permissions,
+        }, AuditEvent::AppMutation {
+            app,
+            request_kind,
+            destination,
+            ..
+        }] => {
+            assert_eq!(*key, app_key);
+            assert_eq!(*inserted_permissions, permissions);
+            assert_eq!(*app, app_key);
+            assert_eq!(request_kind, "PutIData");
+            assert_eq!(*destination, Some(*pub_idata.name()));
+        }
+        events => unexpected!(events),
+    }
+}
+
+#[test]
+fn multisig_transfer() {
+    let mut env = Environment::new();
+    let mut client_a = env.new_connected_client();
+    let mut client_b = env.new_connected_client();
+    let mut client_c = env.new_connected_client();
+
+    common::create_balance(&mut env, &mut client_a, None, 10);
+    common::create_balance(&mut env, &mut client_a, Some(&mut client_b), 1);
+    common::create_balance(&mut env, &mut client_a, Some(&mut client_c), 1);
+
+    // Transfers out of A's balance need the approval of both A and B.
+    let owners: BTreeSet<_> = vec![
+        *client_a.public_id().public_key(),
+        *client_b.public_id().public_key(),
+    ]
+    .into_iter()
+    .collect();
+    let policy = unwrap!(MultisigPolicy::new(owners, 2));
+    common::perform_vault_mutation(
+        &mut env,
+        &mut client_a,
+        VaultRequest::SetMultisigPolicy(policy.clone()),
+    );
+    // The policy can't be replaced.
+    let message_id = client_a.send_vault_request(VaultRequest::SetMultisigPolicy(policy));
+    env.poll();
+    assert_eq!(
+        client_a.expect_vault_response(message_id),
+        VaultResponse::Mutation(Err(NdError::InvalidOperation))
+    );
+
+    // Plain transfers out of the balance are rejected.
+    common::send_request_expect_err(
+        &mut env,
+        &mut client_a,
+        Request::TransferCoins {
+            destination: *client_c.public_id().name(),
+            amount: unwrap!(Coins::from_nano(2)),
+            transaction_id: 1,
+        },
+        NdError::AccessDenied,
+    );
+
+    let proposal = TransferProposal {
+        balance: *client_a.public_id().name(),
+        destination: *client_c.public_id().name(),
+        amount: unwrap!(Coins::from_nano(2)),
+        transaction_id: 1,
+    };
+    let message_id = client_a.send_vault_request(VaultRequest::ApproveMultisigTransfer(proposal));
+    env.poll();
+    assert_eq!(
+        client_a.expect_vault_response(message_id),
+        VaultResponse::TransferApproval(Ok(None))
+    );
+    client_c.expect_no_new_message();
+
+    // B's approval completes the transfer.
+    let expected = Transaction {
+        id: 1,
+        amount: unwrap!(Coins::from_nano(2)),
+    };
+    let message_id = client_b.send_vault_request(VaultRequest::ApproveMultisigTransfer(proposal));
+    env.poll();
+    assert_eq!(client_c.expect_notification(), Notification(expected));
+    assert_eq!(
+        client_b.expect_vault_response(message_id),
+        VaultResponse::TransferApproval(Ok(Some(expected)))
+    );
+
+    // The completed transfer can't be approved again.
+    let message_id = client_b.send_vault_request(VaultRequest::ApproveMultisigTransfer(proposal));
+    env.poll();
+    assert_eq!(
+        client_b.expect_vault_response(message_id),
+        VaultResponse::TransferApproval(Err(NdError::InvalidOperation))
+    );
+    client_c.expect_no_new_message();
+
+    common::send_request_expect_ok(
+        &mut env,
+        &mut client_c,
+        Request::GetBalance,
+        unwrap!(Coins::from_nano(3)),
+    );
+}
+
 #[test]
 fn app_permissions() {
     let mut env = Environment::new();